@@ -4,6 +4,318 @@
   changelog = {
     # Find the current version number with `git log --pretty=%h | wc -l`
     entries = [
+      {
+        version = 715;
+        changes = ''
+          A local `fetchGit` of a working tree watches the directories of the tracked files instead of every tracked file, so large repositories no longer exhaust the inotify watches.
+        '';
+      }
+      {
+        version = 714;
+        changes = ''
+          `lorri internal explain-rebuild` no longer suggests ignoring `.git`, whose HEAD, refs and index lorri watches for local `fetchGit`.
+        '';
+      }
+      {
+        version = 713;
+        changes = ''
+          `lorri direnv` watches the build status of the project, so direnv reloads and shows the stale environment warning right after a rebuild fails.
+        '';
+      }
+      {
+        version = 712;
+        changes = ''
+          `lorri shell` starts nushell with a `--config` file that sources the user’s `config.nu` and prefixes the prompt with `(lorri)`.
+        '';
+      }
+      {
+        version = 711;
+        changes = ''
+          `lorri internal stream-events` resolves `--nix-file` and `--project-root` like the other commands find `shell.nix`, so relative paths and symlinked directories match the daemon's projects; paths that do not exist are an error.
+        '';
+      }
+      {
+        version = 710;
+        changes = ''
+          The daemon refuses a TCP token file other users can access, and replaces an empty or short token with a new random one.
+        '';
+      }
+      {
+        version = 709;
+        changes = ''
+          The system daemon starts user daemons with the `XDG_CACHE_HOME`, `XDG_CONFIG_HOME` and `XDG_RUNTIME_DIR` of the connecting client, so they use the same socket and directories as the user's lorri commands.
+        '';
+      }
+      {
+        version = 708;
+        changes = ''
+          `lorri daemon --exit-when-idle` exits again after projects were pinged:
+          a pinged project only keeps the daemon running until it was neither
+          pinged nor built for the idle time.
+        '';
+      }
+      {
+        version = 707;
+        changes = ''
+          Clients monitoring the daemon now reliably receive the final shutdown
+          event: the daemon waits for them before it exits.
+        '';
+      }
+      {
+        version = 706;
+        changes = ''
+          `lorri shell` and `lorri exec` ask a running daemon to build the project
+          again and wait for that build, instead of using a build the daemon
+          reported from before. Started events of the daemon now always name their
+          project, so clients can tell whose build started.
+        '';
+      }
+      {
+        version = 704;
+        changes = ''
+          A build producing the environment of the active generation no longer
+          adds a new generation for it.
+        '';
+      }
+      {
+        version = 703;
+        changes = ''
+          A file watcher that keeps failing is restarted with exponential backoff,
+          instead of immediately after every error.
+        '';
+      }
+      {
+        version = 702;
+        changes = ''
+          `lorri internal explain-rebuild` now finds noisy directories such as
+          `target/` in the files that triggered rebuilds, and names the watched
+          directory they are in.
+        '';
+      }
+      {
+        version = 701;
+        changes = ''
+          `builtins.fetchGit` of a local repository without `rev` or `ref` copies its working tree,
+          so lorri now also watches the repository's index and tracked files in that case:
+          uncommitted edits trigger a rebuild, too.
+        '';
+      }
+      {
+        version = 700;
+        changes = ''
+          `Monitor` takes an optional filter, so clients only receive the events they care about:
+          events of specific nix files or of the projects below some directories, events of some
+          kinds (`started`, `completed`, `failure`), and failures without their log lines.
+          `lorri internal stream-events` exposes it with `--nix-file`, `--project-root`,
+          `--build-event` and `--no-logs`, and `lorri shell` only subscribes to its own project.
+        '';
+      }
+      {
+        version = 699;
+        changes = ''
+          The daemon can listen on a TCP address in addition to its socket (config option
+          `daemon.listen_tcp`, for example `127.0.0.1:9876`), so clients can reach it through an SSH
+          forwarded port. TCP clients authenticate with the token in `daemon_token` in the cache
+          directory, via the new `Authenticate` method of both varlink interfaces. Set
+          `LORRI_DAEMON_ADDRESS=tcp:HOST:PORT` and `LORRI_DAEMON_TOKEN` to point lorri commands to
+          such a daemon.
+        '';
+      }
+      {
+        version = 698;
+        changes = ''
+          New `lorri daemon --system`: one daemon, running as root on `/run/lorri/daemon.socket`,
+          serves all users of a shared machine. It identifies each client with `SO_PEERCRED` and
+          passes the connection on to a `lorri daemon` it starts as that user, so builds run with the
+          user's permissions and GC roots and events stay separate per user. Commands of users
+          without a daemon of their own use the system daemon.
+        '';
+      }
+      {
+        version = 697;
+        changes = ''
+          The daemon remembers the projects it watches in `active_projects.json` in the cache
+          directory. After a restart it watches and builds them again, one after the other, so
+          background rebuilds resume without waiting for direnv to ping every project. The new
+          `daemon` config section turns this off (`restore_projects`) and forgets projects that
+          were not used for `forget_projects_after_days` (default 30).
+        '';
+      }
+      {
+        version = 696;
+        changes = ''
+          `lorri daemon` now serves on the socket passed by systemd socket activation
+          (`contrib/lorri.socket`) instead of binding its own, and leaves that socket in place
+          when it stops. With `--exit-when-idle MINUTES` the daemon exits once it had no clients
+          and no projects for that long, so it can be started on demand. The daemon now serves
+          each client on its own thread, so many `Monitor` clients no longer block other calls.
+        '';
+      }
+      {
+        version = 695;
+        changes = ''
+          New `lorri daemon stop` command: the daemon finishes its running builds (or cancels
+          them with `--cancel-builds`), sends a final `shutdown` event to `Monitor` clients,
+          removes its socket and exits. It is backed by the new `Shutdown` method of
+          `com.target.lorri.internal`, which `lorri daemon --replace` now uses as well.
+        '';
+      }
+      {
+        version = 694;
+        changes = ''
+          lorri commands now ask the daemon for its version (new `GetInfo` method of
+          `com.target.lorri.internal`). They warn if it is another lorri version and
+          ignore daemons that speak another protocol. `lorri daemon --replace` stops a
+          running daemon and takes over its socket.
+        '';
+      }
+      {
+        version = 693;
+        changes = ''
+          `lorri doctor` now checks the whole environment: nix, the direnv version, the
+          config file, GC roots, whether the daemon is running, reachable and of the same
+          version, the inotify watch limit and `$SHELL`. `--json` prints machine-readable
+          results.
+        '';
+      }
+      {
+        version = 692;
+        changes = ''
+          GC roots no longer require `$USER` to be set. If the per-user GC roots directory of
+          nix is not writable, lorri registers its roots with `nix-store --add-root --indirect`;
+          `gc_roots.directory` in the config selects another directory. The new `lorri doctor`
+          command checks that roots can be created.
+        '';
+      }
+      {
+        version = 691;
+        changes = ''
+          New `gc_roots.keep_outputs` config option: for projects below the listed paths,
+          lorri also roots the derivation of the environment and the outputs it was built
+          from, so rebuilding after a garbage collection is fast. `lorri info` shows how
+          much store space a project keeps alive.
+        '';
+      }
+      {
+        version = 690;
+        changes = ''
+          Updating a project’s GC roots is now race-free across processes: the daemon,
+          `lorri shell` and `lorri watch` take a per-project lock, and the root symlinks
+          are replaced atomically, so direnv never sees a missing environment.
+        '';
+      }
+      {
+        version = 689;
+        changes = ''
+          `lorri shell` and `lorri exec` let a running daemon build the project and wait
+          for its build, instead of building the project a second time. The daemon now
+          also serves the `com.target.lorri` interface (`Monitor`), which it didn’t before.
+        '';
+      }
+      {
+        version = 688;
+        changes = ''
+          `lorri shell` and `lorri exec` got a `--pure` flag, which starts from an empty
+          environment (keeping only session variables like `HOME` and `TERM`) instead of
+          the current one, like `nix-shell --pure`. `--keep VAR` keeps more variables.
+        '';
+      }
+      {
+        version = 687;
+        changes = ''
+          `lorri shell` now supports fish, nushell, xonsh and tcsh: the user’s
+          configuration is loaded and the prompt is prefixed with `(lorri)`. With fish,
+          the project’s `PATH` entries stay in front even if the fish configuration
+          resets `PATH`.
+        '';
+      }
+      {
+        version = 686;
+        changes = ''
+          Add `lorri exec [--cached] -- COMMAND [ARGS…]`, which runs a command in the
+          project environment (a replacement for `nix-shell --run`). lorri execs into the
+          command, so its exit status and signals are passed through.
+        '';
+      }
+      {
+        version = 685;
+        changes = ''
+          Add `lorri env --format {json,dotenv,fish,nushell,systemd,docker-env}`, which
+          prints the variables the project environment sets (following the same rules as
+          `lorri direnv`) for editors, containers and shells other than bash.
+        '';
+      }
+      {
+        version = 684;
+        changes = ''
+          Add `lorri env diff [FROM [TO]]`, showing which variables were added, removed
+          or changed between two environment generations (search paths like `PATH` are
+          compared component-wise). `--from-shell` compares with the current shell
+          instead. Completed build events now carry the names of the changed variables.
+        '';
+      }
+      {
+        version = 683;
+        changes = ''
+          Every successful build now creates a new environment generation, and the
+          last few generations (`generations.keep` in the config file, default 5)
+          are kept as GC roots. `lorri generations` lists them, `lorri rollback [N]`
+          activates an older one until the next successful build.
+        '';
+      }
+      {
+        version = 682;
+        changes = ''
+          When a rebuild fails, `lorri direnv` keeps loading the environment of the
+          previous successful build, but now sets `LORRI_ENV_STALE=1` and prints a
+          warning with a summary of the build error.
+        '';
+      }
+      {
+        version = 681;
+        changes = ''
+          A failing project no longer takes down the daemon. Errors outside of the
+          user’s control (like I/O errors) are reported as build failures and the
+          build is retried with exponential backoff; a failing file watcher is
+          restarted; a crashed build loop is restarted on the next ping.
+        '';
+      }
+      {
+        version = 680;
+        changes = ''
+          Add a polling file watcher for network filesystems like NFS and sshfs.
+          Projects below the `watcher.poll_paths` of the config file are polled
+          every `watcher.poll_interval_ms` milliseconds. lorri also falls back to
+          polling when the system runs out of inotify watches, instead of failing.
+        '';
+      }
+      {
+        version = 679;
+        changes = ''
+          Add `lorri internal explain-rebuild`, which shows the watched paths of a
+          project, the directories with the most watched paths, the recent rebuild
+          triggers with the changed files and event kinds, and suggestions on how
+          to avoid unnecessary rebuilds.
+        '';
+      }
+      {
+        version = 678;
+        changes = ''
+          Add a configuration file, `~/.config/lorri/config.json`.
+          Its `path_reducers` list registers rules for the watched files,
+          for example to watch only the git refs of a local nixpkgs checkout.
+          Add `lorri internal explain-watches` to show which rule applies to which file.
+        '';
+      }
+      {
+        version = 677;
+        changes = ''
+          Local git repositories used via `builtins.fetchGit` are now watched:
+          a new commit or branch update triggers a rebuild.
+          Local files fetched via `builtins.fetchTarball` or `builtins.fetchurl`
+          are watched as well.
+        '';
+      }
       {
         version = 676;
         changes = ''
@@ -52,6 +52,12 @@ let
     # To ensure we always have a compatible nix in our shells.
     # CI doesn’t know `nix-env` otherwise.
     pkgs.nix
+
+    # User shells with special support in `lorri shell`, see tests/shell.
+    pkgs.fish
+    pkgs.nushell
+    pkgs.tcsh
+    pkgs.xonsh
   ] ++ pkgs.stdenv.lib.optionals pkgs.stdenv.isDarwin [
     pkgs.darwin.Security
     pkgs.darwin.apple_sdk.frameworks.CoreServices
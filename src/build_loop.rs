@@ -5,15 +5,17 @@ use crate::builder;
 use crate::daemon::LoopHandlerEvent;
 use crate::error::BuildError;
 use crate::nix::options::NixOptions;
-use crate::pathreduction::reduce_paths;
+use crate::pathreduction::Reducers;
+use crate::project::diagnostics::{Diagnostics, Trigger};
 use crate::project::roots;
 use crate::project::roots::Roots;
 use crate::project::Project;
-use crate::watch::{DebugMessage, EventError, Reason, Watch};
+use crate::watch::{Backend, DebugMessage, EventError, Reason, Watch};
 use crate::NixFile;
 use crossbeam_channel as chan;
 use slog_scope::{debug, warn};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 /// Builder events sent back over `BuildLoop.tx`.
 #[derive(Clone, Debug, Serialize)]
@@ -42,6 +44,17 @@ pub enum Event {
         /// The error that exited the build
         failure: BuildError,
     },
+    /// The daemon is shutting down, no events follow
+    Shutdown,
+}
+
+/// Asks a `BuildLoop` to build its project.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ping {
+    /// Build if the environment is missing, e.g. because it was garbage collected
+    IfMissing,
+    /// Build even if the environment is up to date
+    Rebuild,
 }
 
 /// Results of a single, successful build.
@@ -49,6 +62,9 @@ pub enum Event {
 pub struct BuildResults {
     /// See `build::Info.outputPaths
     pub output_paths: builder::OutputPaths<roots::RootPath>,
+    /// How the environment changed compared to the previous build,
+    /// if there was one.
+    pub env_changes: Option<crate::env::diff::Summary>,
 }
 
 /// The BuildLoop repeatedly builds the Nix expression in
@@ -63,6 +79,34 @@ pub struct BuildLoop<'a> {
     watch: Watch,
     /// Extra options to pass to each nix invocation
     extra_nix_options: NixOptions,
+    /// When to restart the watcher after it failed, if it did
+    restart_watch_at: Option<Instant>,
+    /// Backoff between restarts of a watcher that keeps failing
+    restart_watch_backoff: Backoff,
+}
+
+/// The first retry after a build failed for reasons outside of the
+/// user’s control happens after this long.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Retries are never further apart than this.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Exponential backoff between retries of failed builds.
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff { next: MIN_BACKOFF }
+    }
+
+    /// Time to wait before the next retry; doubles on every call.
+    fn next(&mut self) -> Duration {
+        let current = self.next;
+        self.next = std::cmp::min(current * 2, MAX_BACKOFF);
+        current
+    }
 }
 
 impl<'a> BuildLoop<'a> {
@@ -71,8 +115,55 @@ impl<'a> BuildLoop<'a> {
     pub fn new(project: &'a Project, extra_nix_options: NixOptions) -> BuildLoop<'a> {
         BuildLoop {
             project,
-            watch: Watch::try_new().expect("Failed to initialize watch"),
+            watch: Self::new_watch(project),
             extra_nix_options,
+            restart_watch_at: None,
+            restart_watch_backoff: Backoff::new(),
+        }
+    }
+
+    /// Create a watcher for `project`.
+    /// Falls back to polling if filesystem notifications are not available.
+    fn new_watch(project: &Project) -> Watch {
+        let config = &project.config.watcher;
+        Watch::try_new(config.backend(project.nix_file.as_path())).unwrap_or_else(|e| {
+            warn!(
+                "cannot use filesystem notifications, polling instead";
+                "nix_file" => ?project.nix_file, "error" => ?e
+            );
+            Watch::try_new(Backend::Poll {
+                interval: Duration::from_millis(config.poll_interval_ms),
+            })
+            .expect("the polling watcher cannot fail to initialize")
+        })
+    }
+
+    /// Restart the failed watcher after the backoff time,
+    /// unless a restart is already scheduled.
+    fn schedule_restart_watch(&mut self) {
+        if self.restart_watch_at.is_none() {
+            let wait = self.restart_watch_backoff.next();
+            warn!(
+                "restarting the file watcher";
+                "nix_file" => ?self.project.nix_file,
+                "restart_in" => ?wait
+            );
+            self.restart_watch_at = Some(Instant::now() + wait);
+        }
+    }
+
+    /// Replace the watcher by a fresh one, watching the same paths.
+    fn restart_watch(&mut self) {
+        self.restart_watch_at = None;
+        let watched = self.watch.watched().iter().cloned().collect::<Vec<_>>();
+        self.watch = Self::new_watch(self.project);
+        for path in watched {
+            // Every watched path is listed, so directories need not be walked.
+            // The path might be gone by now; the next build
+            // registers all paths that are still relevant anyway.
+            if let Err(e) = self.watch.extend_shallow(vec![path.clone()]) {
+                debug!("could not watch path again"; "path" => ?path, "error" => ?e)
+            }
         }
     }
 
@@ -80,78 +171,157 @@ impl<'a> BuildLoop<'a> {
     /// Sends `Event`s over `Self.tx` once they happen.
     /// When new filesystem changes are detected while a build is
     /// still running, it is finished first before starting a new build.
+    ///
+    /// Builds failing for reasons outside of the user’s control
+    /// (e.g. I/O errors) are retried with exponential backoff.
+    /// If the watcher fails, it is restarted and the project rebuilt,
+    /// since changes might have been missed. A watcher that keeps failing
+    /// is restarted with exponential backoff as well.
     #[allow(clippy::drop_copy, clippy::zero_ptr)] // triggered by `select!`
-    pub fn forever(&mut self, tx: chan::Sender<LoopHandlerEvent>, rx_ping: chan::Receiver<()>) {
-        let translate_reason = |rsn| match rsn {
-            Ok(rsn) => rsn,
-            // we should continue and just cite an unknown reason
-            Err(EventError::EventHasNoFilePath(msg)) => {
-                warn!(
-                    "event has no file path; possible issue with the watcher?";
-                    "message" => ?msg
-                );
-                // can’t Clone `Event`s, so we return the Debug output here
-                Reason::UnknownEvent(DebugMessage(format!("{:#?}", msg)))
-            }
-            Err(EventError::RxNoEventReceived) => {
-                panic!("The file watcher died!");
-            }
-        };
-
+    pub fn forever(&mut self, tx: chan::Sender<LoopHandlerEvent>, rx_ping: chan::Receiver<Ping>) {
         // The project has just been added, so run the builder in the first iteration
-        let mut output_paths = self.once_with_send(
-            &tx,
-            Event::Started {
-                nix_file: self.project.nix_file.clone(),
-                reason: Reason::ProjectAdded(self.project.nix_file.clone()),
-            },
-        );
+        let first = Event::Started {
+            nix_file: self.project.nix_file.clone(),
+            reason: Reason::ProjectAdded(self.project.nix_file.clone()),
+        };
+        self.record_trigger(&first, None);
+        let mut backoff = Backoff::new();
+        let mut output_paths = None;
+        let mut retry_at =
+            self.build_and_schedule_retry(&tx, first, &mut backoff, &mut output_paths);
 
         // Drain pings initially: we're going to trigger a first build anyway
         rx_ping.try_iter().for_each(drop);
 
-        let rx_notify = self.watch.rx.clone();
-
         loop {
+            // the watcher might have been restarted, so get the current receiver
+            let rx_notify = self.watch.rx.clone();
+            let rx_retry = match retry_at {
+                Some(at) => chan::after(at.saturating_duration_since(Instant::now())),
+                None => chan::never(),
+            };
+            let rx_restart_watch = match self.restart_watch_at {
+                Some(at) => chan::after(at.saturating_duration_since(Instant::now())),
+                None => chan::never(),
+            };
+            // The kind of filesystem event that caused the build, if any
+            let mut event_kind = None;
             let reason = chan::select! {
-                recv(rx_notify) -> msg => match msg {
-                    Ok(msg) => {
-                        match self.watch.process(msg) {
-                            Some(rsn) => {
-                                Some(Event::Started{
-                                    nix_file: self.project.nix_file.clone(),
-                                    reason: translate_reason(rsn)
-                                })
-                            },
-                            None => {
-                                // No relevant file events
-                                None
-                            }
-                        }
-                    }
-                    // TODO: can we just ignore Err?
-                    Err(_) => None
+                recv(rx_notify) -> msg => {
+                    // `self.watch` holds a sender, so the channel is never disconnected
+                    let msg = msg.expect("the file watcher channel is disconnected");
+                    event_kind = msg.as_ref().ok().map(|ev| format!("{:?}", ev.kind));
+                    // No reason if there were no relevant file events
+                    self.watch.process(msg).and_then(|rsn| self.translate_reason(rsn))
+                },
+                recv(rx_restart_watch) -> _ => {
+                    self.restart_watch();
+                    Some(Reason::UnknownEvent(DebugMessage(
+                        "file watcher restarted".to_string()
+                    )))
                 },
                 recv(rx_ping) -> msg => match (msg, &output_paths) {
-                    (Ok(()), Some(output_paths)) => {
+                    (Ok(Ping::Rebuild), _) => Some(Reason::PingReceived),
+                    (Ok(Ping::IfMissing), Some(output_paths)) => {
                         // TODO: why is this check done here?
                         if !output_paths.shell_gc_root_is_dir() {
-                            Some(Event::Started{
-                                nix_file: self.project.nix_file.clone(),
-                                reason: Reason::PingReceived
-                            })
+                            Some(Reason::PingReceived)
                         }
                         else { None }
                     },
                     // TODO: can we just ignore these two cases?
-                    (Ok(()), None) => None,
+                    (Ok(Ping::IfMissing), None) => None,
                     (Err(_), _) => None
-                }
+                },
+                recv(rx_retry) -> _ => Some(Reason::UnknownEvent(DebugMessage(
+                    "retrying the failed build".to_string()
+                )))
             };
 
             // If there is some reason to build, run the build!
-            if let Some(rsn) = reason {
-                output_paths = self.once_with_send(&tx, rsn)
+            if let Some(reason) = reason {
+                let rsn = Event::Started {
+                    nix_file: self.project.nix_file.clone(),
+                    reason,
+                };
+                self.record_trigger(&rsn, event_kind);
+                retry_at = self.build_and_schedule_retry(&tx, rsn, &mut backoff, &mut output_paths);
+            }
+        }
+    }
+
+    /// Turn the result of `Watch::process` into a build reason.
+    /// If the watcher failed, there is none yet: the watcher is restarted
+    /// after a backoff time, and then the project is rebuilt.
+    fn translate_reason(&mut self, rsn: Result<Reason, EventError>) -> Option<Reason> {
+        match rsn {
+            Ok(rsn) => {
+                self.restart_watch_backoff = Backoff::new();
+                Some(rsn)
+            }
+            // we should continue and just cite an unknown reason
+            Err(EventError::EventHasNoFilePath(msg)) => {
+                warn!(
+                    "event has no file path; possible issue with the watcher?";
+                    "message" => ?msg
+                );
+                // can’t Clone `Event`s, so we return the Debug output here
+                Some(Reason::UnknownEvent(DebugMessage(format!("{:#?}", msg))))
+            }
+            Err(EventError::RxNoEventReceived) => {
+                warn!("the file watcher died");
+                self.schedule_restart_watch();
+                None
+            }
+            Err(EventError::WatcherFailed(msg)) => {
+                warn!("the file watcher failed"; "error" => ?msg);
+                self.schedule_restart_watch();
+                None
+            }
+        }
+    }
+
+    /// Run a build, and if it failed for a reason outside of the user’s
+    /// control, return when to retry it.
+    fn build_and_schedule_retry(
+        &mut self,
+        tx: &chan::Sender<LoopHandlerEvent>,
+        reason: Event,
+        backoff: &mut Backoff,
+        output_paths: &mut Option<builder::OutputPaths<roots::RootPath>>,
+    ) -> Option<Instant> {
+        match self.once_with_send(tx, reason) {
+            Ok(paths) => {
+                *output_paths = Some(paths);
+                *backoff = Backoff::new();
+                None
+            }
+            Err(e) => {
+                *output_paths = None;
+                if e.is_actionable() {
+                    // the user has to change something, retrying won’t help
+                    None
+                } else {
+                    let wait = backoff.next();
+                    warn!(
+                        "build failed unexpectedly, retrying";
+                        "nix_file" => ?self.project.nix_file,
+                        "error" => %e,
+                        "retry_in" => ?wait
+                    );
+                    Some(Instant::now() + wait)
+                }
+            }
+        }
+    }
+
+    /// Record why a build was started, for `lorri internal explain-rebuild`.
+    fn record_trigger(&self, event: &Event, event_kind: Option<String>) {
+        if let Event::Started { reason, .. } = event {
+            if let Err(e) = Diagnostics::from_project(self.project)
+                .record_trigger(Trigger::now(reason.clone(), event_kind))
+            {
+                warn!("could not record build trigger"; "error" => ?e)
             }
         }
     }
@@ -160,7 +330,7 @@ impl<'a> BuildLoop<'a> {
         &mut self,
         tx: &chan::Sender<LoopHandlerEvent>,
         reason: Event,
-    ) -> Option<builder::OutputPaths<roots::RootPath>> {
+    ) -> Result<builder::OutputPaths<roots::RootPath>, BuildError> {
         let send = |msg| {
             tx.send(LoopHandlerEvent::from(msg))
                 .expect("Failed to send an event")
@@ -172,18 +342,14 @@ impl<'a> BuildLoop<'a> {
                     nix_file: self.project.nix_file.clone(),
                     result: result.clone(),
                 });
-                Some(result.output_paths)
+                Ok(result.output_paths)
             }
             Err(e) => {
-                if e.is_actionable() {
-                    send(Event::Failure {
-                        nix_file: self.project.nix_file.clone(),
-                        failure: e,
-                    })
-                } else {
-                    panic!("Unrecoverable error:\n{:#?}", e);
-                }
-                None
+                send(Event::Failure {
+                    nix_file: self.project.nix_file.clone(),
+                    failure: e.clone(),
+                });
+                Err(e)
             }
         }
     }
@@ -192,32 +358,127 @@ impl<'a> BuildLoop<'a> {
     ///
     /// This will create GC roots and expand the file watch list for
     /// the evaluation.
+    ///
+    /// If the build fails, the roots of the previous build stay
+    /// in place, but are marked as stale.
     pub fn once(&mut self) -> Result<BuildResults, BuildError> {
+        let res = self.build();
+        if let Err(e) = &res {
+            if let Err(err) = Roots::from_project(self.project).record_failure(e) {
+                warn!("could not record build failure"; "error" => ?err)
+            }
+        }
+        res
+    }
+
+    fn build(&mut self) -> Result<BuildResults, BuildError> {
         let run_result = builder::run(
             &self.project.nix_file,
             &self.project.cas,
             &self.extra_nix_options,
         )?;
-        self.register_paths(&run_result.referenced_paths)?;
-        self.root_result(run_result.result)
+        self.register_paths(
+            &run_result.referenced_paths,
+            &run_result.referenced_directories,
+        )?;
+        self.root_result(run_result.result, &run_result.drv)
     }
 
-    fn register_paths(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+    fn register_paths(
+        &mut self,
+        paths: &[PathBuf],
+        directories: &[PathBuf],
+    ) -> Result<(), notify::Error> {
         let original_paths_len = paths.len();
-        let paths = reduce_paths(&paths);
+        let reducers = Reducers::from_config(&self.project.config);
+        let (directories, rewritten) = reducers.reduce_shallow(directories);
+        let paths = reducers.reduce(&[paths, &rewritten].concat());
         debug!("paths reduced"; "from" => original_paths_len, "to" => paths.len());
 
         // add all new (reduced) nix sources to the input source watchlist
         self.watch.extend(paths.into_iter().collect::<Vec<_>>())?;
+        self.watch.extend_shallow(directories)?;
+
+        if let Err(e) = Diagnostics::from_project(self.project).record_watched(self.watch.watched())
+        {
+            warn!("could not record watched paths"; "error" => ?e)
+        }
 
         Ok(())
     }
 
-    fn root_result(&mut self, build: builder::RootedPath) -> Result<BuildResults, BuildError> {
+    fn root_result(
+        &mut self,
+        build: builder::RootedPath,
+        drv: &builder::RootedDrv,
+    ) -> Result<BuildResults, BuildError> {
         let roots = Roots::from_project(&self.project);
+        // the environment of the previous build, if any
+        let previous_env = crate::env::read_env(&roots.paths().shell_gc_root.0).ok();
 
+        let output_paths = roots.create_roots(build, drv).map_err(BuildError::io)?;
+        let env_changes = match (
+            previous_env,
+            crate::env::read_env(&output_paths.shell_gc_root.0),
+        ) {
+            (Some(old), Ok(new)) => Some(crate::env::diff::diff(&old, &new).summary()),
+            _ => None,
+        };
         Ok(BuildResults {
-            output_paths: roots.create_roots(build).map_err(BuildError::io)?,
+            output_paths,
+            env_changes,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+        assert_eq!(backoff.next(), Duration::from_secs(2));
+        assert_eq!(backoff.next(), Duration::from_secs(4));
+        for _ in 0..20 {
+            backoff.next();
+        }
+        assert_eq!(backoff.next(), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failed_watcher_is_restarted_with_backoff() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let project = Project::new(
+            NixFile::from(tmp.path().join("shell.nix")),
+            &tmp.path().join("gc_roots"),
+            crate::cas::ContentAddressable::new(tmp.path().join("cas"))?,
+            crate::config::Config::default(),
+        )?;
+        let mut build_loop = BuildLoop::new(&project, NixOptions::empty());
+        let failed = || Err(EventError::WatcherFailed(DebugMessage("boom".to_string())));
+
+        // failures only schedule a restart, further ones don’t move it
+        assert!(build_loop.translate_reason(failed()).is_none());
+        let first = build_loop.restart_watch_at.expect("no restart scheduled");
+        assert!(build_loop.translate_reason(failed()).is_none());
+        assert_eq!(build_loop.restart_watch_at, Some(first));
+
+        // a watcher that keeps failing is restarted later every time
+        build_loop.restart_watch();
+        assert_eq!(build_loop.restart_watch_at, None);
+        let before = Instant::now();
+        build_loop.translate_reason(failed());
+        let second = build_loop.restart_watch_at.expect("no restart scheduled");
+        assert!(second >= before + Duration::from_secs(2));
+
+        // until it works again
+        build_loop.restart_watch();
+        assert!(build_loop
+            .translate_reason(Ok(Reason::PingReceived))
+            .is_some());
+        assert_eq!(build_loop.restart_watch_backoff.next(), MIN_BACKOFF);
+        Ok(())
+    }
+}
@@ -20,9 +20,12 @@ use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::thread;
 
-struct RootedDrv {
+/// A derivation which is temporarily rooted, like `RootedPath`.
+#[derive(Debug)]
+pub struct RootedDrv {
     _gc_handle: GcRootTempDir,
-    path: DrvFile,
+    /// The instantiated derivation
+    pub path: DrvFile,
 }
 
 /// Represents a path which is temporarily rooted in a temporary directory.
@@ -39,6 +42,7 @@ pub struct RootedPath {
 
 struct InstantiateOutput {
     referenced_paths: Vec<PathBuf>,
+    referenced_directories: Vec<PathBuf>,
     output: RootedDrv,
 }
 
@@ -119,6 +123,7 @@ fn instrumented_instantiation(
             .collect::<Result<Vec<DrvFile>, _>>()
     });
 
+    let running = crate::nix::track(&child);
     let (exec_result, mut build_products, results) = (
         child.wait()?,
         build_products
@@ -128,19 +133,42 @@ fn instrumented_instantiation(
             .join()
             .expect("Failed to join stderr processing thread")?,
     );
+    drop(running);
 
     // TODO: this can move entirely into the stderr thread,
     // meaning we don’t have to keep the outputs in memory (fold directly)
 
     // iterate over all lines, parsing out the ones we are interested in
+    let mut directories = vec![];
     let (paths, log_lines): (Vec<PathBuf>, Vec<OsString>) =
         results
             .into_iter()
             .fold((vec![], vec![]), |(mut paths, mut log_lines), result| {
                 match result {
-                    LogDatum::CopiedSource(src) | LogDatum::ReadFileOrDir(src) => {
+                    LogDatum::CopiedSource(src)
+                    | LogDatum::ReadFileOrDir(src)
+                    | LogDatum::FetchedLocalFile(src) => {
                         paths.push(src);
                     }
+                    LogDatum::FetchedGitRepository(repo) => {
+                        // With a `rev` or `ref`, `fetchGit` only looks at committed
+                        // state, so we watch the refs instead of the whole repository.
+                        match crate::git::ref_paths(&repo) {
+                            Some(refs) => paths.extend(refs),
+                            None => paths.push(repo),
+                        }
+                    }
+                    LogDatum::FetchedGitWorkingTree(repo) => {
+                        // Otherwise it copies the tracked files of the working tree,
+                        // uncommitted changes included.
+                        match crate::git::working_tree_paths(&repo) {
+                            Some(working_tree) => {
+                                paths.extend(working_tree.refs);
+                                directories.extend(working_tree.directories);
+                            }
+                            None => paths.push(repo),
+                        }
+                    }
                     LogDatum::NixSourceFile(mut src) => {
                         // We need to emulate nix’s `default.nix` mechanism here.
                         // That is, if the user uses something like
@@ -175,6 +203,7 @@ fn instrumented_instantiation(
 
     Ok(InstantiateOutput {
         referenced_paths: paths,
+        referenced_directories: directories,
         output: RootedDrv {
             _gc_handle: GcRootTempDir(gc_root_dir),
             path: shell_gc_root,
@@ -207,8 +236,13 @@ struct GcRootTempDir(tempfile::TempDir);
 pub struct RunResult {
     /// All the paths identified during the instantiation
     pub referenced_paths: Vec<PathBuf>,
+    /// Directories whose entries, but not their subdirectories, the
+    /// instantiation depends on (see `git::WorkingTreePaths`)
+    pub referenced_directories: Vec<PathBuf>,
     /// The status of the build attempt
     pub result: RootedPath,
+    /// The derivation that was built
+    pub drv: RootedDrv,
 }
 
 /// Builds the Nix expression in `root_nix_file`.
@@ -221,13 +255,26 @@ pub fn run(
     extra_nix_options: &NixOptions,
 ) -> Result<RunResult, BuildError> {
     let inst_info = instrumented_instantiation(root_nix_file, cas, &extra_nix_options)?;
-    let buildoutput = build(inst_info.output.path)?;
+    let buildoutput = build(inst_info.output.path.clone())?;
     Ok(RunResult {
         referenced_paths: inst_info.referenced_paths,
+        referenced_directories: inst_info.referenced_directories,
         result: buildoutput.output,
+        drv: inst_info.output,
     })
 }
 
+/// Evaluates the Nix expression in `root_nix_file` without building it.
+///
+/// Returns all the paths identified during the instantiation.
+pub fn referenced_paths(
+    root_nix_file: &NixFile,
+    cas: &ContentAddressable,
+    extra_nix_options: &NixOptions,
+) -> Result<Vec<PathBuf>, BuildError> {
+    Ok(instrumented_instantiation(root_nix_file, cas, extra_nix_options)?.referenced_paths)
+}
+
 /// Classifies the output of nix-instantiate -vv.
 #[derive(Debug, PartialEq)]
 enum LogDatum {
@@ -237,6 +284,13 @@ enum LogDatum {
     CopiedSource(PathBuf),
     /// A `builtins.readFile` or `builtins.readDir` invocation (at eval time)
     ReadFileOrDir(PathBuf),
+    /// A `builtins.fetchGit` invocation on a local git repository,
+    /// for a given `rev` or `ref`
+    FetchedGitRepository(PathBuf),
+    /// A `builtins.fetchGit` invocation on the working tree of a local git repository
+    FetchedGitWorkingTree(PathBuf),
+    /// A `builtins.fetchTarball` or `builtins.fetchurl` invocation on a local file
+    FetchedLocalFile(PathBuf),
     /// Arbitrary text (which we couldn’t otherwise classify)
     Text(String),
     /// Text which we coudn’t decode from UTF-8
@@ -261,6 +315,16 @@ where
         // by our instrumentation in `./logged-evaluation.nix`.
         static ref LORRI_READ: Regex =
             Regex::new("^trace: lorri read: '(?P<source>.*)'$").expect("invalid regex!");
+        // These are printed for `builtins.fetchGit`,
+        // by our instrumentation in `./logged-evaluation.nix`.
+        static ref LORRI_FETCH_GIT: Regex =
+            Regex::new("^trace: lorri fetchGit: '(?P<url>.*)'$").expect("invalid regex!");
+        static ref LORRI_FETCH_GIT_WORKING_TREE: Regex =
+            Regex::new("^trace: lorri fetchGit working tree: '(?P<url>.*)'$").expect("invalid regex!");
+        // These are printed for `builtins.fetchTarball` and `builtins.fetchurl`,
+        // by our instrumentation in `./logged-evaluation.nix`.
+        static ref LORRI_FETCH: Regex =
+            Regex::new("^trace: lorri fetch: '(?P<url>.*)'$").expect("invalid regex!");
     }
 
     // see the regexes above for explanations of the nix outputs
@@ -279,6 +343,21 @@ where
             // to make sure we only watch directories if they were builtins.readDir’ed
             } else if let Some(matches) = LORRI_READ.captures(&linestr) {
                 LogDatum::ReadFileOrDir(PathBuf::from(&matches["source"]))
+            } else if let Some(local) = LORRI_FETCH_GIT
+                .captures(linestr)
+                .and_then(|matches| local_url_path(&matches["url"]))
+            {
+                LogDatum::FetchedGitRepository(local)
+            } else if let Some(local) = LORRI_FETCH_GIT_WORKING_TREE
+                .captures(linestr)
+                .and_then(|matches| local_url_path(&matches["url"]))
+            {
+                LogDatum::FetchedGitWorkingTree(local)
+            } else if let Some(local) = LORRI_FETCH
+                .captures(linestr)
+                .and_then(|matches| local_url_path(&matches["url"]))
+            {
+                LogDatum::FetchedLocalFile(local)
             } else {
                 LogDatum::Text(linestr.to_owned())
             }
@@ -286,6 +365,19 @@ where
     }
 }
 
+/// The local filesystem path a fetcher url refers to, if any.
+///
+/// Fetchers accept plain absolute paths (like `toString ./.`)
+/// as well as `file://` urls. Remote urls are not watched.
+fn local_url_path(url: &str) -> Option<PathBuf> {
+    let path = url.trim_start_matches("file://");
+    if path.starts_with('/') {
+        Some(PathBuf::from(path))
+    } else {
+        None
+    }
+}
+
 /// Output paths generated by `logged-evaluation.nix`
 #[derive(Debug, Clone, Serialize)]
 pub struct OutputPaths<T> {
@@ -322,6 +414,35 @@ mod tests {
             ))
         );
 
+        assert_eq!(
+            parse_evaluation_line("trace: lorri fetchGit: '/home/grahamc/projects/nixpkgs'"),
+            LogDatum::FetchedGitRepository(PathBuf::from("/home/grahamc/projects/nixpkgs"))
+        );
+
+        assert_eq!(
+            parse_evaluation_line("trace: lorri fetchGit: 'file:///home/grahamc/projects/nixpkgs'"),
+            LogDatum::FetchedGitRepository(PathBuf::from("/home/grahamc/projects/nixpkgs"))
+        );
+
+        assert_eq!(
+            parse_evaluation_line(
+                "trace: lorri fetchGit working tree: '/home/grahamc/projects/nixpkgs'"
+            ),
+            LogDatum::FetchedGitWorkingTree(PathBuf::from("/home/grahamc/projects/nixpkgs"))
+        );
+
+        assert_eq!(
+            parse_evaluation_line("trace: lorri fetchGit: 'https://github.com/target/lorri.git'"),
+            LogDatum::Text(String::from(
+                "trace: lorri fetchGit: 'https://github.com/target/lorri.git'"
+            ))
+        );
+
+        assert_eq!(
+            parse_evaluation_line("trace: lorri fetch: '/home/grahamc/vendor/src.tar.gz'"),
+            LogDatum::FetchedLocalFile(PathBuf::from("/home/grahamc/vendor/src.tar.gz"))
+        );
+
         assert_eq!(
             parse_evaluation_line(
                 "downloading 'https://static.rust-lang.org/dist/channel-rust-stable.toml'..."
@@ -413,8 +534,10 @@ in {}
         Ok(())
     }
 
-    // TODO: builtins.fetchTarball and the like? What happens with those?
-    // Are they directories and if yes, should we watch them?
+    // `builtins.fetchTarball` and friends only produce directories in
+    // the nix store, which are never watched. For local repositories
+    // and files they are handled via the `lorri fetch*` traces in
+    // `./logged-evaluation.nix`, see `parse_evaluation_line`.
     /// The paths that are returned by the nix-instantiate call
     /// must not contain directories, otherwise the watcher will
     /// watch those recursively, which leads to a lot of wasted resources
@@ -477,4 +600,87 @@ dir-as-source = ./dir;
         );
         Ok(())
     }
+
+    /// A `builtins.fetchGit` of a local repository watches the
+    /// repository’s refs, so that new commits trigger a rebuild.
+    #[test]
+    fn local_fetch_git_watches_refs() -> std::io::Result<()> {
+        let root_tmp = tempfile::tempdir()?;
+        let cas_tmp = tempfile::tempdir()?;
+        let root = root_tmp.path();
+        let repo = root.join("repo");
+        std::fs::create_dir(&repo)?;
+        std::fs::write(repo.join("file"), "committed")?;
+        crate::bash::expect_bash(
+            r#"cd "$1" && git init -q && git add file \
+                && git -c user.name=lorri -c user.email=lorri@example.com commit -q -m init"#,
+            [repo.as_os_str()],
+        );
+
+        let shell = root.join("shell.nix");
+        std::fs::write(
+            &shell,
+            drv(
+                "shell",
+                r#"src = builtins.fetchGit { url = ./repo; ref = "master"; };"#,
+            ),
+        )?;
+
+        let cas = ContentAddressable::new(cas_tmp.path().join("cas"))?;
+        let inst_info =
+            instrumented_instantiation(&NixFile(shell), &cas, &NixOptions::empty()).unwrap();
+        let ends_with = |end| inst_info.referenced_paths.iter().any(|p| p.ends_with(end));
+        assert!(ends_with("repo/.git/HEAD"), "HEAD should be watched!");
+        assert!(
+            ends_with("repo/.git/refs/heads"),
+            "refs/heads should be watched!"
+        );
+        assert!(
+            !ends_with("repo/file"),
+            "the working tree should not be watched for a ref"
+        );
+        Ok(())
+    }
+
+    /// Without a `rev` or `ref`, `builtins.fetchGit` copies the working tree,
+    /// so uncommitted edits of tracked files have to trigger a rebuild.
+    /// Their directories are watched, instead of every file.
+    #[test]
+    fn local_fetch_git_watches_working_tree() -> std::io::Result<()> {
+        let root_tmp = tempfile::tempdir()?;
+        let cas_tmp = tempfile::tempdir()?;
+        let root = root_tmp.path();
+        let repo = root.join("repo");
+        std::fs::create_dir(&repo)?;
+        std::fs::write(repo.join("file"), "committed")?;
+        crate::bash::expect_bash(
+            r#"cd "$1" && git init -q && git add file \
+                && git -c user.name=lorri -c user.email=lorri@example.com commit -q -m init"#,
+            [repo.as_os_str()],
+        );
+        // edit the tracked file without committing
+        std::fs::write(repo.join("file"), "dirty")?;
+
+        let shell = root.join("shell.nix");
+        std::fs::write(&shell, drv("shell", "src = builtins.fetchGit ./repo;"))?;
+
+        let cas = ContentAddressable::new(cas_tmp.path().join("cas"))?;
+        let inst_info =
+            instrumented_instantiation(&NixFile(shell), &cas, &NixOptions::empty()).unwrap();
+        let ends_with = |end| inst_info.referenced_paths.iter().any(|p| p.ends_with(end));
+        assert!(
+            inst_info
+                .referenced_directories
+                .iter()
+                .any(|d| d.ends_with("repo")),
+            "the directories of tracked files should be watched!"
+        );
+        assert!(
+            !ends_with("repo/file"),
+            "tracked files should not be watched one by one!"
+        );
+        assert!(ends_with("repo/.git/index"), "the index should be watched!");
+        assert!(ends_with("repo/.git/HEAD"), "HEAD should be watched!");
+        Ok(())
+    }
 }
@@ -8,6 +8,7 @@
 //
 // See MAINTAINERS.md for details on internal and non-internal commands.
 
+use std::ffi::OsString;
 use std::path::PathBuf;
 
 #[derive(StructOpt, Debug)]
@@ -41,10 +42,29 @@ pub enum Command {
     #[structopt(name = "shell")]
     Shell(ShellOptions),
 
+    /// Run a command in the project environment
+    #[structopt(
+        name = "exec",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Exec(ExecOptions),
+
     /// Build project whenever an input file changes
     #[structopt(name = "watch")]
     Watch(WatchOptions),
 
+    /// List the environments of past builds of a project
+    #[structopt(name = "generations")]
+    Generations(GenerationsOptions),
+
+    /// Load the environment of an older build until the next successful build
+    #[structopt(name = "rollback")]
+    Rollback(RollbackOptions),
+
+    /// Print the environment of a project, or inspect its generations
+    #[structopt(name = "env")]
+    Env(EnvOptions),
+
     /// Start the multi-project daemon. Replaces `lorri watch`
     #[structopt(name = "daemon")]
     Daemon(DaemonOptions),
@@ -57,6 +77,10 @@ pub enum Command {
     #[structopt(name = "init")]
     Init,
 
+    /// Check the environment for problems that keep lorri from working
+    #[structopt(name = "doctor")]
+    Doctor(DoctorOptions),
+
     /// Internal commands, only use to experiment with unstable features
     #[structopt(name = "internal")]
     Internal {
@@ -94,6 +118,37 @@ pub struct ShellOptions {
     /// If true, load environment from cache
     #[structopt(long = "cached")]
     pub cached: bool,
+    /// Start from an empty environment instead of the current one, like `nix-shell --pure`.
+    /// Session variables like `HOME` and `TERM` are kept.
+    #[structopt(long = "pure")]
+    pub pure: bool,
+    /// With --pure, also keep this variable of the current environment (can be repeated)
+    #[structopt(long = "keep", requires = "pure", raw(number_of_values = "1"))]
+    pub keep: Vec<String>,
+}
+
+/// Options for the `exec` subcommand.
+///
+/// Builds the project (unless `--cached` is given) and runs the command
+/// with the same environment `lorri shell` and `lorri direnv` load.
+#[derive(StructOpt, Debug)]
+pub struct ExecOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// If true, load environment from cache
+    #[structopt(long = "cached")]
+    pub cached: bool,
+    /// Start from an empty environment instead of the current one, like `nix-shell --pure`.
+    /// Session variables like `HOME` and `TERM` are kept.
+    #[structopt(long = "pure")]
+    pub pure: bool,
+    /// With --pure, also keep this variable of the current environment (can be repeated)
+    #[structopt(long = "keep", requires = "pure", raw(number_of_values = "1"))]
+    pub keep: Vec<String>,
+    /// The command to run, followed by its arguments
+    #[structopt(parse(from_os_str), raw(required = "true"))]
+    pub command: Vec<OsString>,
 }
 
 /// Options for the `internal start-user-shell` subcommand.
@@ -118,6 +173,79 @@ pub struct WatchOptions {
     pub once: bool,
 }
 
+/// Options for the `generations` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct GenerationsOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Options for the `rollback` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RollbackOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The generation to activate (see `lorri generations`).
+    /// Defaults to the generation before the current one.
+    pub generation: Option<u32>,
+}
+
+/// Print the variables loading the project environment sets, like `lorri direnv` would.
+///
+/// Search paths like `PATH` are combined with their value in the calling environment.
+/// The environment of the latest successful build is used; nothing is built.
+#[derive(StructOpt, Debug)]
+pub struct EnvOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The output format, one of json, dotenv, fish, nushell, systemd, docker-env
+    #[structopt(long = "format", default_value = "json")]
+    pub format: crate::env::format::Format,
+    /// Instead of printing the environment, inspect its generations
+    #[structopt(subcommand)]
+    pub command: Option<EnvCommand>,
+}
+
+/// Sub-commands of `lorri env`.
+#[derive(StructOpt, Debug)]
+pub enum EnvCommand {
+    /// Show how the environment changed between two generations
+    #[structopt(name = "diff")]
+    Diff(EnvDiffOptions),
+}
+
+/// Compare the environments of two generations (see `lorri generations`).
+///
+/// Shows added (+), removed (-) and changed (~) variables.
+/// For search paths like `PATH`, the added and removed components are listed.
+/// Without arguments, the current generation is compared to the one before it.
+#[derive(StructOpt, Debug)]
+pub struct EnvDiffOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Compare the environment of the current shell instead of a generation,
+    /// showing what loading the (current or given) generation would change
+    #[structopt(long = "from-shell", conflicts_with = "from")]
+    pub from_shell: bool,
+    /// The generation to compare from
+    pub from: Option<u32>,
+    /// The generation to compare to; defaults to the current one
+    pub to: Option<u32>,
+}
+
+/// Options for the `doctor` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DoctorOptions {
+    /// Print the results as a JSON list of objects
+    /// with a `name`, a `status` (`ok`, `warning` or `error`) and a `message`
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
 /// Options for the `daemon` subcommand
 #[derive(StructOpt, Debug)]
 pub struct DaemonOptions {
@@ -132,10 +260,51 @@ pub struct DaemonOptions {
     ///   "substituters": <optional list of string>
     /// }
     pub extra_nix_options: Option<NixOptions>,
+
+    /// Stop a daemon that is already running (for example one of an older
+    /// lorri version after an upgrade) and take over its socket
+    #[structopt(long = "replace")]
+    pub replace: bool,
+
+    /// Exit after no client was connected and no project was pinged (or built
+    /// after a ping) for this many minutes. Meant for a daemon the service manager starts on demand
+    /// (socket activation)
+    #[structopt(long = "exit-when-idle", name = "minutes")]
+    pub exit_when_idle: Option<u64>,
+
+    /// Run the system daemon, which serves all users of this machine.
+    /// It has to run as root; it starts a daemon as each connecting user
+    /// and passes the connection on to it
+    #[structopt(
+        long = "system",
+        conflicts_with = "replace",
+        conflicts_with = "minutes"
+    )]
+    pub system: bool,
+
+    /// Instead of starting the daemon, control the running one
+    #[structopt(subcommand)]
+    pub command: Option<DaemonCommand>,
+}
+
+/// Sub-commands of `lorri daemon`.
+#[derive(StructOpt, Debug)]
+pub enum DaemonCommand {
+    /// Stop the running daemon after its running builds have finished
+    #[structopt(name = "stop")]
+    Stop(DaemonStopOptions),
+}
+
+/// Options for the `daemon stop` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DaemonStopOptions {
+    /// Cancel running builds instead of waiting for them to finish
+    #[structopt(long = "cancel-builds")]
+    pub cancel_builds: bool,
 }
 
 /// The nix options we can parse as json string
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 // ATTN: If you modify this,
 // adjust the help text in DaemonOptions.extra_nix_options
 pub struct NixOptions {
@@ -159,6 +328,14 @@ pub enum Internal_ {
     /// (plumbing) Ask the lorri daemon to report build events as they occur
     #[structopt(name = "stream-events")]
     StreamEvents_(StreamEvents_),
+
+    /// (debug) Show which files lorri watches for a project, and why
+    #[structopt(name = "explain-watches")]
+    ExplainWatches_(ExplainWatches_),
+
+    /// (debug) Show why lorri rebuilt a project recently
+    #[structopt(name = "explain-rebuild")]
+    ExplainRebuild_(ExplainRebuild_),
 }
 
 /// Send a message with a lorri project.
@@ -182,6 +359,55 @@ pub struct StreamEvents_ {
     #[structopt(long, default_value = "all")]
     /// The kind of events to report
     pub kind: crate::ops::stream_events::EventKind,
+
+    /// Only report the events of this nix file (repeat for more)
+    #[structopt(long = "nix-file", parse(from_os_str))]
+    pub nix_files: Vec<PathBuf>,
+
+    /// Only report the events of projects below this directory (repeat for more)
+    #[structopt(long = "project-root", parse(from_os_str))]
+    pub project_roots: Vec<PathBuf>,
+
+    /// Only report build events of this kind: started, completed or failure
+    /// (repeat for more)
+    #[structopt(long = "build-event")]
+    pub build_events: Vec<crate::daemon::BuildEventKind>,
+
+    /// Leave out the log lines of failed builds
+    #[structopt(long = "no-logs")]
+    pub no_logs: bool,
+}
+
+/// Evaluate a project and explain how each referenced path is watched.
+///
+/// Every path referenced during evaluation is passed through the path reducers
+/// (the builtin ones and the ones registered in the config file).
+/// For each path, this shows whether it is kept, rewritten or removed,
+/// and by which reducer.
+#[derive(StructOpt, Debug)]
+pub struct ExplainWatches_ {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Analyse the watched paths and recent rebuild triggers of a project.
+///
+/// Lists the paths watched after the last evaluation, the directories with
+/// the most watched paths, and the most recent rebuild triggers with the exact
+/// files and filesystem event kinds, followed by suggestions how to avoid
+/// unnecessary rebuilds. The data is recorded by `lorri daemon` and `lorri watch`.
+#[derive(StructOpt, Debug)]
+pub struct ExplainRebuild_ {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// List all watched paths instead of only the first few
+    #[structopt(long = "all")]
+    pub all: bool,
+    /// How many of the most recent rebuild triggers to show
+    #[structopt(long = "triggers", default_value = "10")]
+    pub triggers: usize,
 }
 
 /// A stub struct to represent how what we want to upgrade to.
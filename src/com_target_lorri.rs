@@ -11,12 +11,18 @@ use varlink::{self, CallTrait};
 pub enum ErrorKind {
     Varlink_Error,
     VarlinkReply_Error,
+    AuthenticationRequired(Option<AuthenticationRequired_Args>),
+    InvalidToken(Option<InvalidToken_Args>),
 }
 impl ::std::fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match self {
             ErrorKind::Varlink_Error => write!(f, "Varlink Error"),
             ErrorKind::VarlinkReply_Error => write!(f, "Varlink error reply"),
+            ErrorKind::AuthenticationRequired(v) => {
+                write!(f, "com.target.lorri.AuthenticationRequired: {:#?}", v)
+            }
+            ErrorKind::InvalidToken(v) => write!(f, "com.target.lorri.InvalidToken: {:#?}", v),
         }
     }
 }
@@ -102,18 +108,59 @@ impl From<&varlink::Reply> for ErrorKind {
     #[allow(unused_variables)]
     fn from(e: &varlink::Reply) -> Self {
         match e {
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.AuthenticationRequired" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::AuthenticationRequired(v),
+                    Err(_) => ErrorKind::AuthenticationRequired(None),
+                },
+                _ => ErrorKind::AuthenticationRequired(None),
+            },
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.InvalidToken" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::InvalidToken(v),
+                    Err(_) => ErrorKind::InvalidToken(None),
+                },
+                _ => ErrorKind::InvalidToken(None),
+            },
             _ => ErrorKind::VarlinkReply_Error,
         }
     }
 }
-pub trait VarlinkCallError: varlink::CallTrait {}
+pub trait VarlinkCallError: varlink::CallTrait {
+    fn reply_authentication_required(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.AuthenticationRequired",
+            None,
+        ))
+    }
+    fn reply_invalid_token(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error("com.target.lorri.InvalidToken", None))
+    }
+}
 impl<'a> VarlinkCallError for varlink::Call<'a> {}
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#EnvChanges {
+    pub r#added: Vec<String>,
+    pub r#removed: Vec<String>,
+    pub r#changed: Vec<String>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum r#Event_kind {
     r#section_end,
     r#started,
     r#completed,
     r#failure,
+    r#shutdown,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct r#Event {
@@ -150,9 +197,17 @@ pub struct r#IOFail {
     pub r#message: String,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#MonitorFilter {
+    pub r#nix_files: Option<Vec<String>>,
+    pub r#project_roots: Option<Vec<String>>,
+    pub r#kinds: Option<Vec<String>>,
+    pub r#logs: Option<bool>,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct r#Outcome {
     pub r#nix_file: String,
     pub r#project_root: String,
+    pub r#env_changes: Option<EnvChanges>,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct r#OutputFail {
@@ -180,12 +235,32 @@ pub struct r#SpawnFail {
     pub r#command: String,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuthenticationRequired_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct InvalidToken_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Authenticate_Reply {}
+impl varlink::VarlinkReply for Authenticate_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Authenticate_Args {
+    pub r#token: String,
+}
+pub trait Call_Authenticate: VarlinkCallError {
+    fn reply(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::parameters(None))
+    }
+}
+impl<'a> Call_Authenticate for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Monitor_Reply {
     pub r#event: Event,
 }
 impl varlink::VarlinkReply for Monitor_Reply {}
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
-pub struct Monitor_Args {}
+pub struct Monitor_Args {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#filter: Option<MonitorFilter>,
+}
 pub trait Call_Monitor: VarlinkCallError {
     fn reply(&mut self, r#event: Event) -> varlink::Result<()> {
         self.reply_struct(Monitor_Reply { r#event }.into())
@@ -193,7 +268,16 @@ pub trait Call_Monitor: VarlinkCallError {
 }
 impl<'a> Call_Monitor for varlink::Call<'a> {}
 pub trait VarlinkInterface {
-    fn monitor(&self, call: &mut dyn Call_Monitor) -> varlink::Result<()>;
+    fn authenticate(
+        &self,
+        call: &mut dyn Call_Authenticate,
+        r#token: String,
+    ) -> varlink::Result<()>;
+    fn monitor(
+        &self,
+        call: &mut dyn Call_Monitor,
+        r#filter: Option<MonitorFilter>,
+    ) -> varlink::Result<()>;
     fn call_upgraded(
         &self,
         _call: &mut varlink::Call,
@@ -203,7 +287,14 @@ pub trait VarlinkInterface {
     }
 }
 pub trait VarlinkClientInterface {
-    fn monitor(&mut self) -> varlink::MethodCall<Monitor_Args, Monitor_Reply, Error>;
+    fn authenticate(
+        &mut self,
+        r#token: String,
+    ) -> varlink::MethodCall<Authenticate_Args, Authenticate_Reply, Error>;
+    fn monitor(
+        &mut self,
+        r#filter: Option<MonitorFilter>,
+    ) -> varlink::MethodCall<Monitor_Args, Monitor_Reply, Error>;
 }
 #[allow(dead_code)]
 pub struct VarlinkClient {
@@ -216,11 +307,24 @@ impl VarlinkClient {
     }
 }
 impl VarlinkClientInterface for VarlinkClient {
-    fn monitor(&mut self) -> varlink::MethodCall<Monitor_Args, Monitor_Reply, Error> {
+    fn authenticate(
+        &mut self,
+        r#token: String,
+    ) -> varlink::MethodCall<Authenticate_Args, Authenticate_Reply, Error> {
+        varlink::MethodCall::<Authenticate_Args, Authenticate_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.Authenticate",
+            Authenticate_Args { r#token },
+        )
+    }
+    fn monitor(
+        &mut self,
+        r#filter: Option<MonitorFilter>,
+    ) -> varlink::MethodCall<Monitor_Args, Monitor_Reply, Error> {
         varlink::MethodCall::<Monitor_Args, Monitor_Reply, Error>::new(
             self.connection.clone(),
             "com.target.lorri.Monitor",
-            Monitor_Args {},
+            Monitor_Args { r#filter },
         )
     }
 }
@@ -234,7 +338,7 @@ pub fn new(inner: Box<dyn VarlinkInterface + Send + Sync>) -> VarlinkInterfacePr
 }
 impl varlink::Interface for VarlinkInterfaceProxy {
     fn get_description(&self) -> &'static str {
-        "# The interface `lorri daemon` exposes.\ninterface com.target.lorri\n\n# Monitor the daemon. The method will reply with an Event update whenever a\n# build begins or ends.  Monitor will immediately reply with a snapshot of\n# known projects, then a marker event, indicating that the stream of events is\n# now \"live.\"\nmethod Monitor() -> (event: Event)\n\n# An event describing the behavior of Lorri across all known projects. There\n# are several kinds of Event, and each kind has a different type to represent\n# futher information\ntype Event (\n    # The kind of the event:\n    # - section_end: marks the break between the current state snapshot, and\n    #   live events.\n    # - started: a build has started but not completed\n    # - completed: a build completed successfully\n    # - failure: a build failed\n    kind: (section_end, started, completed, failure),\n    section: ?SectionMarker, # present iff kind == section_end\n    reason: ?Reason,         # present iff kind == started\n    result: ?Outcome,        # present iff kind == completed\n    failure: ?Failure        # present iff kind == failure\n)\n\n# An empty value - there is nothing further to distinguish the section end\n# event. This type (and its field on Event) exist as a ward against future\n# changes to the event, and to aid recipients in the meantime.\ntype SectionMarker ()\n\n# The impetus for a new build. Like Event, Reason has a kind, and each kind has\n# a unique field.\ntype Reason (\n    # The kind of build reason:\n    # - project_added: Lorri has been newly informed of a project\n    # - ping_received: A client requested a new build\n    # - files_changed: Lorri received a filesystem notification of changed files\n    # - unknown: A build started for an unknown reason\n    kind: (project_added, ping_received, files_changed, unknown),\n    # The absolute path to the shell.nix file for the added project\n    project: ?string, # present iff kind == project_added\n    # A list of files that changed, triggering a new build\n    # This can be useful e.g. to debug Nix expressions bringing in too many\n    # files and thereby building too frequently\n    files: ?[]string, # present iff kind == files_changed\n    # A message describing the unknown cause for a new build.\n    debug: ?string    # present iff kind == unknown\n)\n\n# Details about the built project.\ntype Outcome (\n    # The absolute path to the shell.nix file for the added project\n    nix_file: string,\n    # The root directory of the project\n    project_root: string\n)\n\ntype Failure (\n    # The kind of failure:\n    # - io: An I/O failure\n    # - spawn: The build process couldn't be spawned\n    # - exit: The build started but exited with a failure\n    # - output: the build completed, but Lorri wasn't able to interpret the\n    #   output\n    kind: (io, spawn, exit, output),\n    # The absolute path to the shell.nix file for the added project\n    nix_file: string,\n    io: ?IOFail,        # present iff kind == io\n    spawn: ?SpawnFail,  # present iff kind == spawn\n    exit: ?ExitFail,    # present iff kind == exit\n    output: ?OutputFail # present iff kind == output\n)\n\n# Describes a build failure related to opening files, usually the shell.nix file\ntype IOFail (\n    # A message describing the failure\n    message: string\n)\n\n# Describes a failure to launch the build process\ntype SpawnFail (\n    # A message describing the failure\n    message: string,\n    # The command Lorri attempted to execute\n    command: string\n)\n\n# Describes a failed build process\ntype ExitFail (\n    # The command executed by Lorri\n    command: string,\n    # The Unix exit status of the command, if available\n    status: ?int,\n    # stderr of the failed command.\n    logs: []string\n)\n\n# Describes a failure caused by output produced by the build that Lorri cannot\n# parse\ntype OutputFail (\n    # A message describing the failure\n    message: string\n)\n"
+        "# The interface `lorri daemon` exposes.\ninterface com.target.lorri\n\n# Monitor the daemon. The method will reply with an Event update whenever a\n# build begins or ends.  Monitor will immediately reply with a snapshot of\n# known projects, then a marker event, indicating that the stream of events is\n# now \"live.\" With a filter, only the matching events are sent.\nmethod Monitor(filter: ?MonitorFilter) -> (event: Event)\n\n# Restricts the events Monitor sends. Absent fields don't restrict anything.\n# The section_end and shutdown events are always sent.\ntype MonitorFilter (\n    # Only events of projects with one of these absolute shell.nix paths,\n    # or (if project_roots is given as well) below one of the project_roots.\n    nix_files: ?[]string,\n    # Only events of projects whose shell.nix is below one of these absolute\n    # directories, or (if nix_files is given as well) one of the nix_files.\n    project_roots: ?[]string,\n    # Only events of these kinds: started, completed or failure.\n    kinds: ?[]string,\n    # Whether failure events include the log lines of the failed build\n    # (default: true).\n    logs: ?bool\n)\n\n# Authenticate authenticates a connection to the TCP listener of the daemon\n# with the daemon's token. Until then, Monitor fails with\n# AuthenticationRequired on such a connection. Unix socket connections need\n# no token.\nmethod Authenticate(token: string) -> ()\n\n# The connection has to be authenticated first, see Authenticate.\nerror AuthenticationRequired()\n\n# The token passed to Authenticate is not the token of the daemon.\nerror InvalidToken()\n\n# An event describing the behavior of Lorri across all known projects. There\n# are several kinds of Event, and each kind has a different type to represent\n# futher information\ntype Event (\n    # The kind of the event:\n    # - section_end: marks the break between the current state snapshot, and\n    #   live events.\n    # - started: a build has started but not completed\n    # - completed: a build completed successfully\n    # - failure: a build failed\n    # - shutdown: the daemon is stopping, no further events follow\n    kind: (section_end, started, completed, failure, shutdown),\n    section: ?SectionMarker, # present iff kind == section_end\n    reason: ?Reason,         # present iff kind == started\n    result: ?Outcome,        # present iff kind == completed\n    failure: ?Failure        # present iff kind == failure\n)\n\n# An empty value - there is nothing further to distinguish the section end\n# event. This type (and its field on Event) exist as a ward against future\n# changes to the event, and to aid recipients in the meantime.\ntype SectionMarker ()\n\n# The impetus for a new build. Like Event, Reason has a kind, and each kind has\n# a unique field.\ntype Reason (\n    # The kind of build reason:\n    # - project_added: Lorri has been newly informed of a project\n    # - ping_received: A client requested a new build\n    # - files_changed: Lorri received a filesystem notification of changed files\n    # - unknown: A build started for an unknown reason\n    kind: (project_added, ping_received, files_changed, unknown),\n    # The absolute path to the shell.nix file of the project that is built\n    # (older daemons only send it if kind == project_added)\n    project: ?string,\n    # A list of files that changed, triggering a new build\n    # This can be useful e.g. to debug Nix expressions bringing in too many\n    # files and thereby building too frequently\n    files: ?[]string, # present iff kind == files_changed\n    # A message describing the unknown cause for a new build.\n    debug: ?string    # present iff kind == unknown\n)\n\n# Details about the built project.\ntype Outcome (\n    # The absolute path to the shell.nix file for the added project\n    nix_file: string,\n    # The root directory of the project\n    project_root: string,\n    # How the environment changed compared to the previous build.\n    # Absent for the first build of a project.\n    env_changes: ?EnvChanges\n)\n\n# The names of the environment variables that differ between two builds.\ntype EnvChanges (\n    added: []string,\n    removed: []string,\n    changed: []string\n)\n\ntype Failure (\n    # The kind of failure:\n    # - io: An I/O failure\n    # - spawn: The build process couldn't be spawned\n    # - exit: The build started but exited with a failure\n    # - output: the build completed, but Lorri wasn't able to interpret the\n    #   output\n    kind: (io, spawn, exit, output),\n    # The absolute path to the shell.nix file for the added project\n    nix_file: string,\n    io: ?IOFail,        # present iff kind == io\n    spawn: ?SpawnFail,  # present iff kind == spawn\n    exit: ?ExitFail,    # present iff kind == exit\n    output: ?OutputFail # present iff kind == output\n)\n\n# Describes a build failure related to opening files, usually the shell.nix file\ntype IOFail (\n    # A message describing the failure\n    message: string\n)\n\n# Describes a failure to launch the build process\ntype SpawnFail (\n    # A message describing the failure\n    message: string,\n    # The command Lorri attempted to execute\n    command: string\n)\n\n# Describes a failed build process\ntype ExitFail (\n    # The command executed by Lorri\n    command: string,\n    # The Unix exit status of the command, if available\n    status: ?int,\n    # stderr of the failed command.\n    logs: []string\n)\n\n# Describes a failure caused by output produced by the build that Lorri cannot\n# parse\ntype OutputFail (\n    # A message describing the failure\n    message: string\n)\n"
     }
     fn get_name(&self) -> &'static str {
         "com.target.lorri"
@@ -249,7 +353,42 @@ impl varlink::Interface for VarlinkInterfaceProxy {
     fn call(&self, call: &mut varlink::Call) -> varlink::Result<()> {
         let req = call.request.unwrap();
         match req.method.as_ref() {
-            "com.target.lorri.Monitor" => self.inner.monitor(call as &mut dyn Call_Monitor),
+            "com.target.lorri.Authenticate" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: Authenticate_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner
+                        .authenticate(call as &mut dyn Call_Authenticate, args.r#token)
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
+            "com.target.lorri.Monitor" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: Monitor_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner
+                        .monitor(call as &mut dyn Call_Monitor, args.r#filter)
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
             m => call.reply_method_not_found(String::from(m)),
         }
     }
@@ -11,12 +11,22 @@ use varlink::{self, CallTrait};
 pub enum ErrorKind {
     Varlink_Error,
     VarlinkReply_Error,
+    AuthenticationRequired(Option<AuthenticationRequired_Args>),
+    InvalidToken(Option<InvalidToken_Args>),
 }
 impl ::std::fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match self {
             ErrorKind::Varlink_Error => write!(f, "Varlink Error"),
             ErrorKind::VarlinkReply_Error => write!(f, "Varlink error reply"),
+            ErrorKind::AuthenticationRequired(v) => write!(
+                f,
+                "com.target.lorri.internal.AuthenticationRequired: {:#?}",
+                v
+            ),
+            ErrorKind::InvalidToken(v) => {
+                write!(f, "com.target.lorri.internal.InvalidToken: {:#?}", v)
+            }
         }
     }
 }
@@ -102,13 +112,56 @@ impl From<&varlink::Reply> for ErrorKind {
     #[allow(unused_variables)]
     fn from(e: &varlink::Reply) -> Self {
         match e {
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.internal.AuthenticationRequired" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::AuthenticationRequired(v),
+                    Err(_) => ErrorKind::AuthenticationRequired(None),
+                },
+                _ => ErrorKind::AuthenticationRequired(None),
+            },
+            varlink::Reply {
+                error: Some(ref t), ..
+            } if t == "com.target.lorri.internal.InvalidToken" => match e {
+                varlink::Reply {
+                    parameters: Some(p),
+                    ..
+                } => match serde_json::from_value(p.clone()) {
+                    Ok(v) => ErrorKind::InvalidToken(v),
+                    Err(_) => ErrorKind::InvalidToken(None),
+                },
+                _ => ErrorKind::InvalidToken(None),
+            },
             _ => ErrorKind::VarlinkReply_Error,
         }
     }
 }
-pub trait VarlinkCallError: varlink::CallTrait {}
+pub trait VarlinkCallError: varlink::CallTrait {
+    fn reply_authentication_required(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.internal.AuthenticationRequired",
+            None,
+        ))
+    }
+    fn reply_invalid_token(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::error(
+            "com.target.lorri.internal.InvalidToken",
+            None,
+        ))
+    }
+}
 impl<'a> VarlinkCallError for varlink::Call<'a> {}
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct r#DaemonInfo {
+    pub r#version: String,
+    pub r#build_rev: i64,
+    pub r#protocol_version: i64,
+}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum r#Failure_kind {
     r#io,
     r#spawn,
@@ -146,11 +199,56 @@ pub struct r#ShellNix {
     pub r#path: String,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuthenticationRequired_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct InvalidToken_Args {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Authenticate_Reply {}
+impl varlink::VarlinkReply for Authenticate_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Authenticate_Args {
+    pub r#token: String,
+}
+pub trait Call_Authenticate: VarlinkCallError {
+    fn reply(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::parameters(None))
+    }
+}
+impl<'a> Call_Authenticate for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GetInfo_Reply {
+    pub r#info: DaemonInfo,
+}
+impl varlink::VarlinkReply for GetInfo_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GetInfo_Args {}
+pub trait Call_GetInfo: VarlinkCallError {
+    fn reply(&mut self, r#info: DaemonInfo) -> varlink::Result<()> {
+        self.reply_struct(GetInfo_Reply { r#info }.into())
+    }
+}
+impl<'a> Call_GetInfo for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Shutdown_Reply {}
+impl varlink::VarlinkReply for Shutdown_Reply {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Shutdown_Args {
+    pub r#cancel_builds: bool,
+}
+pub trait Call_Shutdown: VarlinkCallError {
+    fn reply(&mut self) -> varlink::Result<()> {
+        self.reply_struct(varlink::Reply::parameters(None))
+    }
+}
+impl<'a> Call_Shutdown for varlink::Call<'a> {}
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct WatchShell_Reply {}
 impl varlink::VarlinkReply for WatchShell_Reply {}
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct WatchShell_Args {
     pub r#shell_nix: ShellNix,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#rebuild: Option<bool>,
 }
 pub trait Call_WatchShell: VarlinkCallError {
     fn reply(&mut self) -> varlink::Result<()> {
@@ -159,10 +257,18 @@ pub trait Call_WatchShell: VarlinkCallError {
 }
 impl<'a> Call_WatchShell for varlink::Call<'a> {}
 pub trait VarlinkInterface {
+    fn authenticate(
+        &self,
+        call: &mut dyn Call_Authenticate,
+        r#token: String,
+    ) -> varlink::Result<()>;
+    fn get_info(&self, call: &mut dyn Call_GetInfo) -> varlink::Result<()>;
+    fn shutdown(&self, call: &mut dyn Call_Shutdown, r#cancel_builds: bool) -> varlink::Result<()>;
     fn watch_shell(
         &self,
         call: &mut dyn Call_WatchShell,
         r#shell_nix: ShellNix,
+        r#rebuild: Option<bool>,
     ) -> varlink::Result<()>;
     fn call_upgraded(
         &self,
@@ -173,9 +279,19 @@ pub trait VarlinkInterface {
     }
 }
 pub trait VarlinkClientInterface {
+    fn authenticate(
+        &mut self,
+        r#token: String,
+    ) -> varlink::MethodCall<Authenticate_Args, Authenticate_Reply, Error>;
+    fn get_info(&mut self) -> varlink::MethodCall<GetInfo_Args, GetInfo_Reply, Error>;
+    fn shutdown(
+        &mut self,
+        r#cancel_builds: bool,
+    ) -> varlink::MethodCall<Shutdown_Args, Shutdown_Reply, Error>;
     fn watch_shell(
         &mut self,
         r#shell_nix: ShellNix,
+        r#rebuild: Option<bool>,
     ) -> varlink::MethodCall<WatchShell_Args, WatchShell_Reply, Error>;
 }
 #[allow(dead_code)]
@@ -189,14 +305,45 @@ impl VarlinkClient {
     }
 }
 impl VarlinkClientInterface for VarlinkClient {
+    fn authenticate(
+        &mut self,
+        r#token: String,
+    ) -> varlink::MethodCall<Authenticate_Args, Authenticate_Reply, Error> {
+        varlink::MethodCall::<Authenticate_Args, Authenticate_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.internal.Authenticate",
+            Authenticate_Args { r#token },
+        )
+    }
+    fn get_info(&mut self) -> varlink::MethodCall<GetInfo_Args, GetInfo_Reply, Error> {
+        varlink::MethodCall::<GetInfo_Args, GetInfo_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.internal.GetInfo",
+            GetInfo_Args {},
+        )
+    }
+    fn shutdown(
+        &mut self,
+        r#cancel_builds: bool,
+    ) -> varlink::MethodCall<Shutdown_Args, Shutdown_Reply, Error> {
+        varlink::MethodCall::<Shutdown_Args, Shutdown_Reply, Error>::new(
+            self.connection.clone(),
+            "com.target.lorri.internal.Shutdown",
+            Shutdown_Args { r#cancel_builds },
+        )
+    }
     fn watch_shell(
         &mut self,
         r#shell_nix: ShellNix,
+        r#rebuild: Option<bool>,
     ) -> varlink::MethodCall<WatchShell_Args, WatchShell_Reply, Error> {
         varlink::MethodCall::<WatchShell_Args, WatchShell_Reply, Error>::new(
             self.connection.clone(),
             "com.target.lorri.internal.WatchShell",
-            WatchShell_Args { r#shell_nix },
+            WatchShell_Args {
+                r#shell_nix,
+                r#rebuild,
+            },
         )
     }
 }
@@ -210,7 +357,7 @@ pub fn new(inner: Box<dyn VarlinkInterface + Send + Sync>) -> VarlinkInterfacePr
 }
 impl varlink::Interface for VarlinkInterfaceProxy {
     fn get_description(&self) -> &'static str {
-        "# The interface `lorri daemon` exposes.\ninterface com.target.lorri.internal\n\n# WatchShell instructs the daemon to evaluate a Nix expression and re-evaluate\n# it when it or its dependencies change.\nmethod WatchShell(shell_nix: ShellNix) -> ()\n\n# ShellNix describes the Nix expression which evaluates to a development\n# environment.\ntype ShellNix (\n  # The absolute path of a Nix file specifying the project environment.\n  path: string\n)\n\ntype Reason (\n    kind: (project_added, ping_received, files_changed, unknown),\n    project: ?ShellNix, # only present if kind == project_added\n    files: ?[]string,   # only present if kind == files_changed\n    debug: ?string      # only present if kind == unknown\n)\n\ntype Outcome (\n    project_root: string\n)\n\ntype Failure (\n    kind: (io, spawn, exit, output),\n    msg: ?string,   # only present if kind in (io, spawn)\n    cmd: ?string,   # only present if kind in (spawn, exit)\n    status: ?int,   # only present if kind == exit\n    logs: ?[]string # only present if kind == exit\n)\n"
+        "# The interface `lorri daemon` exposes.\ninterface com.target.lorri.internal\n\n# WatchShell instructs the daemon to evaluate a Nix expression and re-evaluate\n# it when it or its dependencies change. With rebuild set, a project the daemon\n# watches already is built again, even if its environment is up to date.\nmethod WatchShell(shell_nix: ShellNix, rebuild: ?bool) -> ()\n\n# GetInfo describes the running daemon, so clients can check that they\n# understand each other before talking to it.\nmethod GetInfo() -> (info: DaemonInfo)\n\n# Shutdown stops the daemon. Running builds are finished first, unless\n# cancel_builds is set, in which case they are stopped. Monitor clients\n# receive a final shutdown event. The daemon replies once it has stopped\n# building, then removes its socket and exits.\nmethod Shutdown(cancel_builds: bool) -> ()\n\n# Authenticate authenticates a connection to the TCP listener of the daemon\n# with the daemon's token. Until then, all other calls on such a connection\n# fail with AuthenticationRequired. Unix socket connections need no token.\nmethod Authenticate(token: string) -> ()\n\n# The connection has to be authenticated first, see Authenticate.\nerror AuthenticationRequired()\n\n# The token passed to Authenticate is not the token of the daemon.\nerror InvalidToken()\n\n# ShellNix describes the Nix expression which evaluates to a development\n# environment.\ntype ShellNix (\n  # The absolute path of a Nix file specifying the project environment.\n  path: string\n)\n\ntype DaemonInfo (\n  # The lorri version of the daemon, in MAJOR.MINOR format.\n  version: string,\n  # The number of revisions in the git tree the daemon was built from.\n  build_rev: int,\n  # The version of this interface. Clients refuse to talk to a daemon\n  # with a different protocol version.\n  protocol_version: int\n)\n\ntype Reason (\n    kind: (project_added, ping_received, files_changed, unknown),\n    project: ?ShellNix, # only present if kind == project_added\n    files: ?[]string,   # only present if kind == files_changed\n    debug: ?string      # only present if kind == unknown\n)\n\ntype Outcome (\n    project_root: string\n)\n\ntype Failure (\n    kind: (io, spawn, exit, output),\n    msg: ?string,   # only present if kind in (io, spawn)\n    cmd: ?string,   # only present if kind in (spawn, exit)\n    status: ?int,   # only present if kind == exit\n    logs: ?[]string # only present if kind == exit\n)\n"
     }
     fn get_name(&self) -> &'static str {
         "com.target.lorri.internal"
@@ -225,6 +372,45 @@ impl varlink::Interface for VarlinkInterfaceProxy {
     fn call(&self, call: &mut varlink::Call) -> varlink::Result<()> {
         let req = call.request.unwrap();
         match req.method.as_ref() {
+            "com.target.lorri.internal.Authenticate" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: Authenticate_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner
+                        .authenticate(call as &mut dyn Call_Authenticate, args.r#token)
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
+            "com.target.lorri.internal.GetInfo" => {
+                self.inner.get_info(call as &mut dyn Call_GetInfo)
+            }
+            "com.target.lorri.internal.Shutdown" => {
+                if let Some(args) = req.parameters.clone() {
+                    let args: Shutdown_Args = match serde_json::from_value(args) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            let es = format!("{}", e);
+                            let _ = call.reply_invalid_parameter(es.clone());
+                            return Err(
+                                varlink::context!(varlink::ErrorKind::SerdeJsonDe(es)).into()
+                            );
+                        }
+                    };
+                    self.inner
+                        .shutdown(call as &mut dyn Call_Shutdown, args.r#cancel_builds)
+                } else {
+                    call.reply_invalid_parameter("parameters".into())
+                }
+            }
             "com.target.lorri.internal.WatchShell" => {
                 if let Some(args) = req.parameters.clone() {
                     let args: WatchShell_Args = match serde_json::from_value(args) {
@@ -237,8 +423,11 @@ impl varlink::Interface for VarlinkInterfaceProxy {
                             );
                         }
                     };
-                    self.inner
-                        .watch_shell(call as &mut dyn Call_WatchShell, args.r#shell_nix)
+                    self.inner.watch_shell(
+                        call as &mut dyn Call_WatchShell,
+                        args.r#shell_nix,
+                        args.r#rebuild,
+                    )
                 } else {
                     call.reply_invalid_parameter("parameters".into())
                 }
@@ -0,0 +1,352 @@
+//! User configuration of lorri.
+//!
+//! The configuration is read from `config.json` in the lorri
+//! config directory (usually `~/.config/lorri/config.json`).
+//! All fields are optional, a missing file is the same as an empty one.
+//!
+//! Example:
+//!
+//! ```json
+//! {
+//!   "path_reducers": [
+//!     { "kind": "git_checkout", "path": "~/src/nixpkgs" },
+//!     { "kind": "ignore", "path": "/home/me/huge-data-dir" }
+//!   ],
+//!   "watcher": {
+//!     "poll_interval_ms": 2000,
+//!     "poll_paths": [ "/nfs/home/me" ]
+//!   },
+//!   "generations": { "keep": 5 },
+//!   "gc_roots": {
+//!     "directory": "/nix/var/nix/gcroots/lorri/me",
+//!     "keep_outputs": [ "~/src/big-project" ]
+//!   },
+//!   "daemon": {
+//!     "restore_projects": true,
+//!     "forget_projects_after_days": 30,
+//!     "listen_tcp": "127.0.0.1:9876"
+//!   }
+//! }
+//! ```
+
+use crate::watch::Backend;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The lorri configuration file.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Additional path reducers, which are consulted before the builtin ones.
+    /// See `pathreduction::Reducer`.
+    #[serde(default)]
+    pub path_reducers: Vec<ReducerConfig>,
+    /// How changes to watched files are detected.
+    #[serde(default)]
+    pub watcher: WatcherConfig,
+    /// How many past environments are kept.
+    #[serde(default)]
+    pub generations: GenerationsConfig,
+    /// What is protected from garbage collection.
+    #[serde(default)]
+    pub gc_roots: GcRootsConfig,
+    /// How the daemon treats the projects it watches.
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+}
+
+/// Configuration of `lorri daemon`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Watch and build the projects of the previous daemon when the daemon starts.
+    pub restore_projects: bool,
+    /// Projects that were not pinged for this many days are not restored.
+    pub forget_projects_after_days: u64,
+    /// Also listen for clients on this TCP address, like `127.0.0.1:9876`.
+    /// They have to authenticate with the token of the daemon (see `daemon::tcp`).
+    pub listen_tcp: Option<String>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            restore_projects: true,
+            forget_projects_after_days: 30,
+            listen_tcp: None,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// How long a project is restored after it was last pinged.
+    pub fn forget_projects_after(&self) -> Duration {
+        Duration::from_secs(self.forget_projects_after_days * 24 * 60 * 60)
+    }
+}
+
+/// Configuration of the GC roots lorri creates for each project.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcRootsConfig {
+    /// Directory for the GC roots that point nix to the roots in the lorri
+    /// cache directory. Nix only searches its own `gcroots` directory, so
+    /// it has to be below that (nix does not follow symlinked directories).
+    /// Defaults to the per-user GC roots directory of nix.
+    pub directory: Option<PathBuf>,
+    /// For projects below these paths, the derivation of the environment
+    /// and the outputs it was built from are rooted as well, like nix’
+    /// `keep-derivations` and `keep-outputs` options do for all roots.
+    pub keep_outputs: Vec<PathBuf>,
+}
+
+impl GcRootsConfig {
+    /// Whether the inputs of the project at `nix_file` are rooted.
+    pub fn keeps_outputs(&self, nix_file: &Path) -> bool {
+        below_any(&self.keep_outputs, nix_file)
+    }
+}
+
+/// Configuration of the environment generations kept for `lorri rollback`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationsConfig {
+    /// Number of generations to keep per project, including the newest one.
+    pub keep: usize,
+}
+
+impl Default for GenerationsConfig {
+    fn default() -> GenerationsConfig {
+        GenerationsConfig { keep: 5 }
+    }
+}
+
+/// Configuration of the file watcher.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    /// Interval of the polling watcher, in milliseconds.
+    pub poll_interval_ms: u64,
+    /// Projects below these paths are always polled instead of
+    /// relying on filesystem notifications, which are not delivered
+    /// on e.g. NFS or sshfs.
+    pub poll_paths: Vec<PathBuf>,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> WatcherConfig {
+        WatcherConfig {
+            poll_interval_ms: 2000,
+            poll_paths: vec![],
+        }
+    }
+}
+
+impl WatcherConfig {
+    /// The watcher backend to use for the project at `nix_file`.
+    pub fn backend(&self, nix_file: &Path) -> Backend {
+        let interval = Duration::from_millis(self.poll_interval_ms);
+        if below_any(&self.poll_paths, nix_file) {
+            Backend::Poll { interval }
+        } else {
+            Backend::Notify {
+                poll_interval: interval,
+            }
+        }
+    }
+}
+
+/// Whether `nix_file` (or the file it resolves to) is below one of `prefixes`.
+fn below_any(prefixes: &[PathBuf], nix_file: &Path) -> bool {
+    let mut candidates = vec![nix_file.to_owned()];
+    candidates.extend(nix_file.canonicalize().ok());
+    prefixes
+        .iter()
+        .any(|prefix| candidates.iter().any(|p| p.starts_with(prefix)))
+}
+
+/// A path reducer registered in the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ReducerConfig {
+    /// Treat every path in the git checkout at `path` as the checkout
+    /// itself: instead of watching the files, watch its git `HEAD` and refs.
+    GitCheckout {
+        /// Root of the git checkout
+        path: PathBuf,
+    },
+    /// Never watch anything below `path`.
+    Ignore {
+        /// Path prefix to ignore
+        path: PathBuf,
+    },
+}
+
+/// Loading the config file failed.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file exists, but could not be read.
+    Io(PathBuf, std::io::Error),
+    /// The file is not valid.
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config from `file`. Returns the default config if `file` does not exist.
+    pub fn load(file: &Path) -> Result<Config, ConfigError> {
+        match std::fs::read(file) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(ConfigError::Io(file.to_owned(), e)),
+            Ok(contents) => Config::parse(&contents)
+                .map_err(|e| ConfigError::Parse(file.to_owned(), e))
+                .map(Config::expand_home),
+        }
+    }
+
+    fn parse(contents: &[u8]) -> Result<Config, serde_json::Error> {
+        serde_json::from_slice(contents)
+    }
+
+    /// Expand a leading `~` in all configured paths.
+    fn expand_home(mut self) -> Config {
+        for reducer in self.path_reducers.iter_mut() {
+            match reducer {
+                ReducerConfig::GitCheckout { path } | ReducerConfig::Ignore { path } => {
+                    *path = expand_home(path)
+                }
+            }
+        }
+        for path in self
+            .watcher
+            .poll_paths
+            .iter_mut()
+            .chain(self.gc_roots.keep_outputs.iter_mut())
+        {
+            *path = expand_home(path)
+        }
+        if let Some(dir) = self.gc_roots.directory.as_mut() {
+            *dir = expand_home(dir)
+        }
+        self
+    }
+}
+
+/// Replace a leading `~` component with `$HOME`.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config() {
+        let config = Config::parse(b"{}").unwrap();
+        assert!(config.path_reducers.is_empty());
+    }
+
+    #[test]
+    fn path_reducers() {
+        let config = Config::parse(
+            br#"{
+                "path_reducers": [
+                    { "kind": "git_checkout", "path": "/src/nixpkgs" },
+                    { "kind": "ignore", "path": "/data" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.path_reducers,
+            vec![
+                ReducerConfig::GitCheckout {
+                    path: PathBuf::from("/src/nixpkgs")
+                },
+                ReducerConfig::Ignore {
+                    path: PathBuf::from("/data")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn watcher_backend() {
+        let config = Config::parse(
+            br#"{
+                "watcher": { "poll_interval_ms": 500, "poll_paths": [ "/nfs" ] }
+            }"#,
+        )
+        .unwrap();
+        let interval = Duration::from_millis(500);
+        assert_eq!(
+            config.watcher.backend(Path::new("/nfs/project/shell.nix")),
+            Backend::Poll { interval }
+        );
+        assert_eq!(
+            config.watcher.backend(Path::new("/nfsx/shell.nix")),
+            Backend::Notify {
+                poll_interval: interval
+            }
+        );
+        assert_eq!(
+            Config::default()
+                .watcher
+                .backend(Path::new("/nfs/project/shell.nix")),
+            Backend::Notify {
+                poll_interval: Duration::from_millis(2000)
+            }
+        );
+    }
+
+    #[test]
+    fn gc_roots() {
+        let config = Config::parse(
+            br#"{
+                "gc_roots": { "directory": "~/roots", "keep_outputs": [ "/src/big" ] }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.gc_roots.directory, Some(PathBuf::from("~/roots")));
+        assert!(config
+            .gc_roots
+            .keeps_outputs(Path::new("/src/big/shell.nix")));
+        assert!(!config
+            .gc_roots
+            .keeps_outputs(Path::new("/src/small/shell.nix")));
+        assert!(!Config::default()
+            .gc_roots
+            .keeps_outputs(Path::new("/src/big/shell.nix")));
+    }
+
+    #[test]
+    fn daemon() {
+        let config = Config::parse(br#"{ "daemon": { "restore_projects": false } }"#).unwrap();
+        assert!(!config.daemon.restore_projects);
+        assert_eq!(
+            config.daemon.forget_projects_after(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert!(Config::default().daemon.restore_projects);
+        assert_eq!(Config::default().daemon.listen_tcp, None);
+        let config = Config::parse(br#"{ "daemon": { "listen_tcp": "127.0.0.1:9876" } }"#).unwrap();
+        assert_eq!(config.daemon.listen_tcp, Some("127.0.0.1:9876".to_string()));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(Config::parse(br#"{ "path_reducer": [] }"#).is_err());
+    }
+}
@@ -2,6 +2,7 @@
 
 use crate::cas::ContentAddressable;
 use directories::ProjectDirs;
+use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 
 /// Path constants like the GC root directory.
@@ -9,6 +10,9 @@ pub struct Paths {
     gc_root_dir: PathBuf,
     daemon_socket_file: PathBuf,
     cas_store: ContentAddressable,
+    config_file: PathBuf,
+    active_projects_file: PathBuf,
+    daemon_token_file: PathBuf,
 }
 
 /// Everything that can happen when creating `Paths`.
@@ -62,6 +66,9 @@ impl Paths {
                 .join("daemon.socket"),
             cas_store: ContentAddressable::new(cas_dir.clone())
                 .map_err(|err| PathsInitError::CasCantBeCreated { cas_dir, err })?,
+            config_file: pd.config_dir().join("config.json"),
+            active_projects_file: pd.cache_dir().join("active_projects.json"),
+            daemon_token_file: pd.cache_dir().join("daemon_token"),
         })
     }
 
@@ -78,9 +85,32 @@ impl Paths {
         &self.daemon_socket_file
     }
 
-    /// Unix socket address of the daemon.
+    /// Path to the socket of the system daemon (see `daemon::system`),
+    /// which serves the users who don’t run a daemon of their own.
+    pub fn system_daemon_socket_file(&self) -> &Path {
+        Path::new("/run/lorri/daemon.socket")
+    }
+
+    /// Path to the socket clients connect to: the socket of the user’s
+    /// daemon, or the system daemon socket if only the system daemon is running.
+    pub fn client_socket_file(&self) -> &Path {
+        let own = self.daemon_socket_file();
+        let system = self.system_daemon_socket_file();
+        if UnixStream::connect(own).is_err() && UnixStream::connect(system).is_ok() {
+            system
+        } else {
+            own
+        }
+    }
+
+    /// Address of the daemon clients connect to: `$LORRI_DAEMON_ADDRESS`
+    /// (like `tcp:127.0.0.1:9876` for a remote daemon) if it is set,
+    /// otherwise the unix socket from `client_socket_file`.
     pub fn daemon_socket_address(&self) -> String {
-        format!("unix:{}", self.daemon_socket_file().display())
+        match std::env::var("LORRI_DAEMON_ADDRESS") {
+            Ok(address) if !address.is_empty() => address,
+            _ => format!("unix:{}", self.client_socket_file().display()),
+        }
     }
 
     /// content-addressable store.
@@ -90,4 +120,22 @@ impl Paths {
     pub fn cas_store(&self) -> &ContentAddressable {
         &self.cas_store
     }
+
+    /// Path to the user configuration file (see `config::Config`).
+    /// The file does not necessarily exist.
+    pub fn config_file(&self) -> &Path {
+        &self.config_file
+    }
+
+    /// Path to the file in which the daemon remembers the projects it watches
+    /// (see `daemon::projects::ActiveProjects`).
+    pub fn active_projects_file(&self) -> &Path {
+        &self.active_projects_file
+    }
+
+    /// Path to the token TCP clients of the daemon authenticate with
+    /// (see `daemon::tcp`). Created by the daemon if it listens on TCP.
+    pub fn daemon_token_file(&self) -> &Path {
+        &self.daemon_token_file
+    }
 }
@@ -1,24 +1,115 @@
 //! The lorri daemon, watches multiple projects in the background.
 
-use crate::build_loop::{BuildLoop, Event};
+use crate::build_loop::{BuildLoop, Event, Ping};
+use crate::config::Config;
+use crate::error::BuildError;
 use crate::nix::options::NixOptions;
 use crate::ops::error::ExitError;
 use crate::socket::SocketPath;
 use crate::NixFile;
 use crossbeam_channel as chan;
-use slog_scope::debug;
-use std::collections::HashMap;
+use slog_scope::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+pub mod client;
 mod internal_proto;
+pub mod projects;
+pub mod system;
+pub mod tcp;
+
+/// Version of the protocol between the lorri commands and the daemon.
+/// Increase it whenever a client cannot talk to a daemon of the
+/// previous version any more (or the other way around).
+pub const PROTOCOL_VERSION: i64 = 1;
 
 #[derive(Debug, Clone)]
 /// Union of build_loop::Event and NewListener for internal use.
 pub enum LoopHandlerEvent {
-    /// A new listener has joined for event streaming
-    NewListener(chan::Sender<Event>),
+    /// A new listener has joined for event streaming,
+    /// interested in the events that pass the filter
+    NewListener(chan::Sender<Event>, EventFilter),
     /// Events from a BuildLoop
     BuildEvent(Event),
+    /// The daemon was asked to shut down. Once no build is running any more,
+    /// listeners get a final `Event::Shutdown` and `done` is notified.
+    Shutdown {
+        /// Stop running builds instead of waiting for them to finish
+        cancel_builds: bool,
+        /// Notified when the daemon is ready to exit
+        done: chan::Sender<()>,
+    },
+}
+
+/// The kinds of build events a listener can choose from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildEventKind {
+    /// `Event::Started`
+    Started,
+    /// `Event::Completed`
+    Completed,
+    /// `Event::Failure`
+    Failure,
+}
+
+impl FromStr for BuildEventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "started" => Ok(BuildEventKind::Started),
+            "completed" => Ok(BuildEventKind::Completed),
+            "failure" => Ok(BuildEventKind::Failure),
+            _ => Err(format!("{} not in started,completed,failure", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for BuildEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            BuildEventKind::Started => "started",
+            BuildEventKind::Completed => "completed",
+            BuildEventKind::Failure => "failure",
+        })
+    }
+}
+
+/// Which events a listener receives. Section ends and the shutdown always pass.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    /// Only events of these nix files (or of the `project_roots`),
+    /// all projects if both are empty
+    pub nix_files: Vec<NixFile>,
+    /// Only events of nix files below these directories (or of the `nix_files`),
+    /// all projects if both are empty
+    pub project_roots: Vec<PathBuf>,
+    /// Only these kinds of build events, all if empty
+    pub kinds: Vec<BuildEventKind>,
+}
+
+impl EventFilter {
+    /// Whether `event` passes the filter.
+    pub fn matches(&self, event: &Event) -> bool {
+        let (nix_file, kind) = match event {
+            Event::SectionEnd | Event::Shutdown => return true,
+            Event::Started { nix_file, .. } => (nix_file, BuildEventKind::Started),
+            Event::Completed { nix_file, .. } => (nix_file, BuildEventKind::Completed),
+            Event::Failure { nix_file, .. } => (nix_file, BuildEventKind::Failure),
+        };
+        let all_projects = self.nix_files.is_empty() && self.project_roots.is_empty();
+        let project = all_projects
+            || self.nix_files.contains(nix_file)
+            || self
+                .project_roots
+                .iter()
+                .any(|root| nix_file.as_path().starts_with(root));
+        project && (self.kinds.is_empty() || self.kinds.contains(&kind))
+    }
 }
 
 impl From<Event> for LoopHandlerEvent {
@@ -42,10 +133,99 @@ impl From<Event> for LoopHandlerEvent {
 pub struct IndicateActivity {
     /// This nix file should be build/watched by the daemon.
     pub nix_file: NixFile,
+    /// The project was watched by a previous daemon and is restored,
+    /// nobody asked for it. It is not built again if it is watched already,
+    /// and it does not keep the daemon from exiting when idle.
+    pub restored: bool,
+    /// Build the project again even if it is watched and up to date already.
+    pub rebuild: bool,
 }
 
 struct Handler {
-    tx: chan::Sender<()>,
+    tx: chan::Sender<Ping>,
+}
+
+/// How often the daemon checks whether it has been idle for too long.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the build of a restored project may take before the next one starts.
+const RESTORE_BUILD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// What keeps the daemon busy: connected clients and the projects they asked for.
+#[derive(Clone)]
+pub struct Activity {
+    clients: Arc<AtomicUsize>,
+    /// When pinged projects were last pinged or built; restored ones don’t count
+    projects: Arc<Mutex<HashMap<NixFile, Instant>>>,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+/// A connected client, counted by `Activity` until dropped.
+pub struct Client(Activity);
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.0.clients.fetch_sub(1, Ordering::SeqCst);
+        self.0.touch();
+    }
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            clients: Arc::new(AtomicUsize::new(0)),
+            projects: Arc::new(Mutex::new(HashMap::new())),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Count a newly connected client.
+    pub fn client(&self) -> Client {
+        self.clients.fetch_add(1, Ordering::SeqCst);
+        self.touch();
+        Client(self.clone())
+    }
+
+    /// Count a project a client asked for.
+    pub fn project(&self, nix_file: &NixFile) {
+        self.projects
+            .lock()
+            .expect("daemon activity poisoned")
+            .insert(nix_file.clone(), Instant::now());
+        self.touch();
+    }
+
+    /// Count a build of `nix_file`, if a client asked for the project.
+    fn built(&self, nix_file: &NixFile) {
+        if let Some(last) = self
+            .projects
+            .lock()
+            .expect("daemon activity poisoned")
+            .get_mut(nix_file)
+        {
+            *last = Instant::now();
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().expect("daemon activity poisoned") = Instant::now();
+    }
+
+    /// How long the daemon has had no clients, and no pinged project
+    /// has been pinged or built. `None` while clients are connected.
+    fn idle_for(&self) -> Option<Duration> {
+        if self.clients.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        let last_active = *self.last_active.lock().expect("daemon activity poisoned");
+        let last_active = self
+            .projects
+            .lock()
+            .expect("daemon activity poisoned")
+            .values()
+            .fold(last_active, |last, project| std::cmp::max(last, *project));
+        Some(last_active.elapsed())
+    }
 }
 
 /// Keeps all state of the running `lorri daemon` service, watches nix files and runs builds.
@@ -57,6 +237,8 @@ pub struct Daemon {
     mon_tx: chan::Sender<LoopHandlerEvent>,
     /// Extra options to pass to each nix invocation
     extra_nix_options: NixOptions,
+    /// Where to listen for TCP clients, if at all
+    tcp: Option<tcp::Endpoint>,
 }
 
 impl Daemon {
@@ -72,17 +254,30 @@ impl Daemon {
                 build_events_rx,
                 mon_tx,
                 extra_nix_options,
+                tcp: None,
             },
             mon_rx,
         )
     }
 
+    /// Also serve clients on a TCP listener at `endpoint.address`,
+    /// once they authenticated with `endpoint.token`.
+    pub fn listen_tcp(&mut self, endpoint: tcp::Endpoint) {
+        self.tcp = Some(endpoint);
+    }
+
     /// Serve the daemon's RPC endpoint.
+    /// With an `idle_timeout`, exit once there were no clients, and no pinged project
+    /// was pinged or built, for that long.
+    /// The watched projects are remembered in `active_projects_file`, and restored from it.
     pub fn serve(
         &mut self,
         socket_path: SocketPath,
         gc_root_dir: PathBuf,
         cas: crate::cas::ContentAddressable,
+        config: Config,
+        idle_timeout: Option<Duration>,
+        active_projects_file: PathBuf,
     ) -> Result<(), ExitError> {
         let (activity_tx, activity_rx): (
             chan::Sender<IndicateActivity>,
@@ -91,15 +286,53 @@ impl Daemon {
 
         let mut pool = crate::thread::Pool::new();
         let build_events_tx = self.build_events_tx.clone();
+        let activity = Activity::new();
+        let mut active_projects = projects::ActiveProjects::load(active_projects_file);
+        let restored = if config.daemon.restore_projects {
+            active_projects.restorable(config.daemon.forget_projects_after())
+        } else {
+            vec![]
+        };
 
-        let server = internal_proto::Server::new(socket_path.clone(), activity_tx, build_events_tx)
-            .map_err(|e| {
+        let server = internal_proto::Server::new(
+            socket_path.clone(),
+            activity_tx.clone(),
+            build_events_tx,
+            activity.clone(),
+            active_projects,
+        )
+        .map_err(|e| {
+            ExitError::temporary(format!(
+                "unable to bind to the server socket at {}: {:?} \
+                     (`lorri daemon --replace` replaces a running daemon)",
+                socket_path.0.display(),
+                e
+            ))
+        })?;
+
+        if let Some(idle_timeout) = idle_timeout {
+            let server = server.clone();
+            let activity = activity.clone();
+            pool.spawn("idle-watch", move || {
+                Self::exit_when_idle(server, activity, idle_timeout)
+            })?;
+        }
+
+        if let Some(endpoint) = self.tcp.take() {
+            let listener = std::net::TcpListener::bind(&endpoint.address).map_err(|e| {
                 ExitError::temporary(format!(
-                    "unable to bind to the server socket at {}: {:?}",
-                    socket_path.0.display(),
-                    e
+                    "unable to listen on TCP address {}: {}",
+                    endpoint.address, e
                 ))
             })?;
+            info!("listening for TCP clients"; "address" => &endpoint.address);
+            let server = server.clone();
+            pool.spawn("tcp-accept-loop", move || {
+                server
+                    .serve_tcp(listener, endpoint.token)
+                    .expect("varlink error");
+            })?;
+        }
 
         pool.spawn("accept-loop", || {
             server.serve().expect("varlink error");
@@ -107,7 +340,15 @@ impl Daemon {
 
         let build_events_rx = self.build_events_rx.clone();
         let mon_tx = self.mon_tx.clone();
-        pool.spawn("build-loop", || Self::build_loop(build_events_rx, mon_tx))?;
+        let build_activity = activity.clone();
+        pool.spawn("build-loop", || {
+            Self::build_loop(build_events_rx, mon_tx, build_activity)
+        })?;
+
+        let build_events_tx = self.build_events_tx.clone();
+        pool.spawn("restore", move || {
+            Self::restore_projects(restored, activity_tx, build_events_tx)
+        })?;
 
         let build_events_tx = self.build_events_tx.clone();
         let extra_nix_options = self.extra_nix_options.clone();
@@ -118,6 +359,7 @@ impl Daemon {
                 activity_rx,
                 gc_root_dir,
                 cas,
+                config,
             )
         })?;
 
@@ -126,12 +368,79 @@ impl Daemon {
         Ok(())
     }
 
+    fn exit_when_idle(server: internal_proto::Server, activity: Activity, idle_timeout: Duration) {
+        loop {
+            std::thread::sleep(IDLE_CHECK_INTERVAL);
+            match activity.idle_for() {
+                Some(idle) if idle >= idle_timeout => break,
+                _ => {}
+            }
+        }
+        info!("exiting, no clients and no projects"; "idle_seconds" => idle_timeout.as_secs());
+        server.wind_down(false);
+        std::process::exit(0)
+    }
+
+    /// Watch the projects of a previous daemon again. Their builds run one after
+    /// another, so they don’t hold up the builds users are waiting for.
+    fn restore_projects(
+        nix_files: Vec<NixFile>,
+        activity_tx: chan::Sender<IndicateActivity>,
+        build_events_tx: chan::Sender<LoopHandlerEvent>,
+    ) {
+        if nix_files.is_empty() {
+            return;
+        }
+        info!("restoring projects"; "count" => nix_files.len());
+        let (tx, rx) = chan::unbounded();
+        if build_events_tx
+            .send(LoopHandlerEvent::NewListener(tx, EventFilter::default()))
+            .is_err()
+        {
+            return;
+        }
+        // projects that were built since the daemon started
+        let mut built = HashSet::new();
+        for nix_file in nix_files {
+            if built.contains(&nix_file) {
+                continue;
+            }
+            debug!("restoring project"; "nix_file" => ?nix_file);
+            let sent = activity_tx.send(IndicateActivity {
+                nix_file: nix_file.clone(),
+                restored: true,
+                rebuild: false,
+            });
+            if sent.is_err() {
+                return;
+            }
+            let start = Instant::now();
+            while !built.contains(&nix_file) {
+                let left = match RESTORE_BUILD_TIMEOUT.checked_sub(start.elapsed()) {
+                    Some(left) => left,
+                    None => break,
+                };
+                match rx.recv_timeout(left) {
+                    Ok(Event::Completed { nix_file, .. }) | Ok(Event::Failure { nix_file, .. }) => {
+                        built.insert(nix_file);
+                    }
+                    Ok(Event::Shutdown) | Err(chan::RecvTimeoutError::Disconnected) => return,
+                    Ok(_) | Err(chan::RecvTimeoutError::Timeout) => {}
+                }
+            }
+        }
+        info!("restored projects");
+    }
+
     fn build_loop(
         build_events_rx: chan::Receiver<LoopHandlerEvent>,
         mon_tx: chan::Sender<LoopHandlerEvent>,
+        activity: Activity,
     ) {
         let mut project_states: HashMap<NixFile, Event> = HashMap::new();
-        let mut event_listeners: Vec<chan::Sender<Event>> = Vec::new();
+        let mut event_listeners: Vec<(chan::Sender<Event>, EventFilter)> = Vec::new();
+        // Waiting for the running builds to end before shutting down
+        let mut shutdown: Option<chan::Sender<()>> = None;
 
         for msg in build_events_rx {
             mon_tx
@@ -139,35 +448,73 @@ impl Daemon {
                 .expect("listener still to be there");
             match &msg {
                 LoopHandlerEvent::BuildEvent(ev) => match ev {
-                    Event::SectionEnd => (),
+                    Event::SectionEnd | Event::Shutdown => (),
                     Event::Started { nix_file, .. }
                     | Event::Completed { nix_file, .. }
                     | Event::Failure { nix_file, .. } => {
+                        activity.built(nix_file);
                         project_states.insert(nix_file.clone(), ev.clone());
-                        event_listeners.retain(|tx| {
+                        event_listeners.retain(|(tx, filter)| {
+                            if !filter.matches(ev) {
+                                return true;
+                            }
                             let keep = tx.send(ev.clone()).is_ok();
                             debug!("Sent"; "event" => ?ev, "keep" => keep);
                             keep
                         })
                     }
                 },
-                LoopHandlerEvent::NewListener(tx) => {
-                    debug!("adding listener");
-                    let keep = project_states.values().all(|event| {
-                        let keeping = tx.send(event.clone()).is_ok();
-                        debug!("Sent snapshot"; "event" => ?&event, "keep" => keeping);
-                        keeping
-                    });
+                LoopHandlerEvent::NewListener(tx, filter) => {
+                    debug!("adding listener"; "filter" => ?filter);
+                    let keep = project_states
+                        .values()
+                        .filter(|event| filter.matches(event))
+                        .all(|event| {
+                            let keeping = tx.send(event.clone()).is_ok();
+                            debug!("Sent snapshot"; "event" => ?&event, "keep" => keeping);
+                            keeping
+                        });
                     debug!("Finished snapshot"; "keep" => keep);
                     if keep {
-                        event_listeners.push(tx.clone());
+                        event_listeners.push((tx.clone(), filter.clone()));
                     }
-                    event_listeners.retain(|tx| {
+                    event_listeners.retain(|(tx, _)| {
                         let keep = tx.send(Event::SectionEnd).is_ok();
                         debug!("Sent new listener sectionend"; "keep" => keep);
                         keep
                     })
                 }
+                LoopHandlerEvent::Shutdown {
+                    cancel_builds,
+                    done,
+                } => {
+                    if *cancel_builds {
+                        info!("cancelling running builds");
+                        crate::nix::stop_running();
+                    }
+                    shutdown = Some(done.clone());
+                }
+            }
+
+            if shutdown.is_none() {
+                continue;
+            }
+            let building = project_states
+                .values()
+                .filter_map(|event| match event {
+                    Event::Started { nix_file, .. } => Some(nix_file),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if !building.is_empty() {
+                debug!("waiting for builds before shutting down"; "building" => ?building);
+                continue;
+            }
+            if let Some(done) = shutdown.take() {
+                for (tx, _) in event_listeners.drain(..) {
+                    let _ = tx.send(Event::Shutdown);
+                }
+                let _ = done.send(());
             }
         }
     }
@@ -180,6 +527,7 @@ impl Daemon {
         activity_rx: chan::Receiver<IndicateActivity>,
         gc_root_dir: PathBuf,
         cas: crate::cas::ContentAddressable,
+        config: Config,
     ) {
         // A thread for each `BuildLoop`, keyed by the nix files listened on.
         let mut handler_threads: HashMap<NixFile, Handler> = HashMap::new();
@@ -187,10 +535,51 @@ impl Daemon {
         // For each build instruction, add the corresponding file
         // to the watch list.
         for start_build in activity_rx {
-            let project =
-                crate::project::Project::new(start_build.nix_file, &gc_root_dir, cas.clone())
-                    // TODO: the project needs to create its gc root dir
-                    .unwrap();
+            let nix_file = start_build.nix_file;
+
+            // Notify the handler, if it is still running.
+            // If its thread died, the handler is started again.
+            if let Some(handler) = handler_threads.get(&nix_file) {
+                if start_build.restored {
+                    continue;
+                }
+                let ping = if start_build.rebuild {
+                    Ping::Rebuild
+                } else {
+                    Ping::IfMissing
+                };
+                if handler.tx.send(ping).is_ok() {
+                    continue;
+                }
+                warn!("build loop is gone, restarting it"; "nix_file" => ?nix_file);
+            }
+
+            let project = match crate::project::Project::new(
+                nix_file.clone(),
+                &gc_root_dir,
+                cas.clone(),
+                config.clone(),
+            ) {
+                Ok(project) => project,
+                Err(e) => {
+                    error!("cannot set up project"; "nix_file" => ?nix_file, "error" => ?e);
+                    let send = |event| {
+                        build_events_tx
+                            .send(LoopHandlerEvent::BuildEvent(event))
+                            .expect("daemon event receiver must be alive")
+                    };
+                    // every failure follows the start of its build
+                    send(Event::Started {
+                        nix_file: nix_file.clone(),
+                        reason: crate::watch::Reason::ProjectAdded(nix_file.clone()),
+                    });
+                    send(Event::Failure {
+                        nix_file,
+                        failure: BuildError::io(e),
+                    });
+                    continue;
+                }
+            };
 
             // Add nix file to the set of files this daemon watches
             // & build if they change.
@@ -200,28 +589,107 @@ impl Daemon {
             let build_events_tx = build_events_tx.clone();
             let extra_nix_options = extra_nix_options.clone();
 
-            handler_threads
-                .entry(project.nix_file.clone())
-                .or_insert_with(|| {
-                    // TODO: how to use the pool here?
-                    // We cannot just spawn new threads once messages come in,
-                    // because then then pool objects is stuck in this loop
-                    // and will never start to wait for joins, which means
-                    // we don’t catch panics as they happen!
-                    // If we can get the pool to “wait for join but also spawn new
-                    // thread when you get a message” that could work!
-                    // pool.spawn(format!("build_loop for {}", nix_file.display()),
-                    let _ = std::thread::spawn(move || {
-                        let mut build_loop = BuildLoop::new(&project, extra_nix_options);
-
-                        build_loop.forever(build_events_tx, rx);
-                    });
-                    Handler { tx }
-                })
-                // Notify the handler, whether or not it was newly added
-                .tx
-                .send(())
-                .unwrap();
+            // TODO: how to use the pool here?
+            // We cannot just spawn new threads once messages come in,
+            // because then then pool objects is stuck in this loop
+            // and will never start to wait for joins, which means
+            // we don’t catch panics as they happen!
+            // If we can get the pool to “wait for join but also spawn new
+            // thread when you get a message” that could work!
+            // pool.spawn(format!("build_loop for {}", nix_file.display()),
+            let _ = std::thread::spawn(move || {
+                // A panic only stops this project’s build loop, not the
+                // whole daemon. The loop is started again on the next ping.
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let mut build_loop = BuildLoop::new(&project, extra_nix_options);
+
+                    build_loop.forever(build_events_tx.clone(), rx);
+                }));
+                if let Err(panic) = res {
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown panic".to_string());
+                    error!("build loop crashed"; "nix_file" => ?project.nix_file, "panic" => &msg);
+                    let _ = build_events_tx.send(LoopHandlerEvent::BuildEvent(Event::Failure {
+                        nix_file: project.nix_file.clone(),
+                        failure: BuildError::Io {
+                            msg: format!("build loop crashed: {}", msg),
+                        },
+                    }));
+                }
+            });
+            // Start the first build
+            let _ = tx.send(Ping::IfMissing);
+            handler_threads.insert(nix_file, Handler { tx });
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_for() {
+        let ago = |secs| Instant::now() - Duration::from_secs(secs);
+        let idle = |activity: &Activity| activity.idle_for().map(|d| d.as_secs());
+        let activity = Activity::new();
+        *activity.last_active.lock().unwrap() = ago(600);
+        assert_eq!(idle(&activity), Some(600));
+
+        // a client keeps the daemon busy, and leaving is activity
+        let client = activity.client();
+        assert_eq!(idle(&activity), None);
+        drop(client);
+        assert_eq!(idle(&activity), Some(0));
+
+        // a pinged project counts since its last ping, not forever
+        let nix_file = NixFile::from(PathBuf::from("/a/shell.nix"));
+        activity.project(&nix_file);
+        *activity.last_active.lock().unwrap() = ago(600);
+        activity
+            .projects
+            .lock()
+            .unwrap()
+            .insert(nix_file.clone(), ago(300));
+        assert_eq!(idle(&activity), Some(300));
+
+        // or since its last build
+        activity.built(&nix_file);
+        assert_eq!(idle(&activity), Some(0));
+
+        // builds of projects nobody pinged don’t count
+        activity.projects.lock().unwrap().insert(nix_file, ago(300));
+        activity.built(&NixFile::from(PathBuf::from("/b/shell.nix")));
+        assert_eq!(idle(&activity), Some(300));
+    }
+
+    #[test]
+    fn event_filter() {
+        let started = |path: &str| Event::Started {
+            nix_file: NixFile::from(PathBuf::from(path)),
+            reason: crate::watch::Reason::PingReceived,
+        };
+        assert!(EventFilter::default().matches(&started("/a/shell.nix")));
+
+        let filter = EventFilter {
+            nix_files: vec![NixFile::from(PathBuf::from("/a/shell.nix"))],
+            project_roots: vec![PathBuf::from("/src/b")],
+            kinds: vec![],
+        };
+        assert!(filter.matches(&started("/a/shell.nix")));
+        assert!(filter.matches(&started("/src/b/sub/shell.nix")));
+        assert!(!filter.matches(&started("/a/other.nix")));
+        assert!(!filter.matches(&started("/src/bb/shell.nix")));
+        assert!(filter.matches(&Event::SectionEnd));
+
+        let filter = EventFilter {
+            kinds: vec![BuildEventKind::Completed, BuildEventKind::Failure],
+            ..EventFilter::default()
+        };
+        assert!(!filter.matches(&started("/a/shell.nix")));
+        assert!(filter.matches(&Event::Shutdown));
+    }
+}
@@ -0,0 +1,154 @@
+//! Connecting to a running daemon, and checking that it speaks our protocol.
+
+use super::PROTOCOL_VERSION;
+use crate::internal_proto;
+use slog_scope::warn;
+use std::sync::{Arc, RwLock};
+
+/// How a running daemon relates to this lorri.
+#[derive(Debug, PartialEq)]
+pub enum Compatibility {
+    /// The daemon is the same lorri version
+    Same,
+    /// The daemon is another lorri version, but speaks the same protocol
+    OtherVersion {
+        /// lorri version of the daemon
+        version: String,
+    },
+    /// The daemon cannot tell its version, because it is older than `GetInfo`
+    Unknown,
+    /// The daemon speaks another protocol, clients must not talk to it
+    Incompatible {
+        /// lorri version of the daemon
+        version: String,
+        /// protocol version of the daemon
+        protocol_version: i64,
+    },
+}
+
+impl Compatibility {
+    /// Compare the daemon’s lorri and protocol version with ours.
+    pub fn of(version: &str, protocol_version: i64) -> Compatibility {
+        if protocol_version != PROTOCOL_VERSION {
+            Compatibility::Incompatible {
+                version: version.to_string(),
+                protocol_version,
+            }
+        } else if version != crate::LORRI_VERSION {
+            Compatibility::OtherVersion {
+                version: version.to_string(),
+            }
+        } else {
+            Compatibility::Same
+        }
+    }
+}
+
+/// Ask the daemon behind `connection` for its version.
+pub fn handshake(connection: Arc<RwLock<varlink::Connection>>) -> Compatibility {
+    use internal_proto::VarlinkClientInterface;
+    match internal_proto::VarlinkClient::new(connection)
+        .get_info()
+        .call()
+    {
+        Ok(reply) => Compatibility::of(&reply.info.version, reply.info.protocol_version),
+        Err(_) => Compatibility::Unknown,
+    }
+}
+
+/// Open a connection to the daemon at `address`, without checking its version.
+/// Connections over TCP (`tcp:` addresses) are authenticated with the token
+/// from `$LORRI_DAEMON_TOKEN`, or else from the token file of the local daemon.
+/// `None` if no daemon is listening or it rejects the token.
+pub fn open(address: &str) -> Option<Arc<RwLock<varlink::Connection>>> {
+    use internal_proto::VarlinkClientInterface;
+    let connection = varlink::Connection::with_address(address).ok()?;
+    if !address.starts_with("tcp:") {
+        return Some(connection);
+    }
+    let token = match token() {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("cannot authenticate to the daemon, set LORRI_DAEMON_TOKEN"; "address" => address, "error" => e);
+            return None;
+        }
+    };
+    match internal_proto::VarlinkClient::new(connection.clone())
+        .authenticate(token)
+        .call()
+    {
+        Ok(_) => Some(connection),
+        Err(e) => {
+            warn!("the daemon rejected the authentication"; "address" => address, "error" => ?e);
+            None
+        }
+    }
+}
+
+/// The token to authenticate TCP connections with.
+fn token() -> Result<String, String> {
+    if let Ok(token) = std::env::var("LORRI_DAEMON_TOKEN") {
+        return Ok(token);
+    }
+    let paths = crate::constants::Paths::initialize().map_err(|e| format!("{:?}", e))?;
+    std::fs::read_to_string(paths.daemon_token_file())
+        .map(|token| token.trim().to_string())
+        .map_err(|e| format!("{}: {}", paths.daemon_token_file().display(), e))
+}
+
+/// Connect to the daemon at `address` (see `open`). `None` if no daemon is listening,
+/// or if it speaks another protocol. Warns if it is another lorri version.
+pub fn connect(address: &str) -> Option<Arc<RwLock<varlink::Connection>>> {
+    let connection = open(address)?;
+    match handshake(connection.clone()) {
+        Compatibility::Same => {}
+        Compatibility::OtherVersion { version } => warn!(
+            "the daemon runs another lorri version, restart it with `lorri daemon --replace`";
+            "daemon_version" => version, "lorri_version" => crate::LORRI_VERSION
+        ),
+        Compatibility::Unknown => warn!(
+            "the daemon is from an older lorri version, restart it with `lorri daemon --replace`";
+            "lorri_version" => crate::LORRI_VERSION
+        ),
+        Compatibility::Incompatible {
+            version,
+            protocol_version,
+        } => {
+            warn!(
+                "the daemon speaks another protocol and is ignored, restart it with `lorri daemon --replace`";
+                "daemon_version" => version,
+                "daemon_protocol_version" => protocol_version,
+                "lorri_version" => crate::LORRI_VERSION,
+                "lorri_protocol_version" => PROTOCOL_VERSION
+            );
+            return None;
+        }
+    }
+    Some(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatibility() {
+        assert_eq!(
+            Compatibility::of(crate::LORRI_VERSION, PROTOCOL_VERSION),
+            Compatibility::Same
+        );
+        assert_eq!(
+            Compatibility::of("0.9", PROTOCOL_VERSION),
+            Compatibility::OtherVersion {
+                version: "0.9".to_string()
+            }
+        );
+        assert_eq!(
+            Compatibility::of(crate::LORRI_VERSION, PROTOCOL_VERSION + 1),
+            Compatibility::Incompatible {
+                version: crate::LORRI_VERSION.to_string(),
+                protocol_version: PROTOCOL_VERSION + 1
+            }
+        );
+    }
+}
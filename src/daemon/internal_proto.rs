@@ -1,67 +1,237 @@
 //! The daemon's RPC server.
 
-use super::IndicateActivity;
-use super::LoopHandlerEvent;
+use super::projects::ActiveProjects;
+use super::tcp::Session;
+use super::{Activity, EventFilter, IndicateActivity, LoopHandlerEvent};
 use crate::build_loop;
 use crate::error;
 use crate::internal_proto;
 use crate::ops::error::ExitError;
 use crate::proto;
-use crate::socket::{BindLock, SocketPath};
+use crate::socket::{BindLock, Binding, SocketPath};
 use crate::watch;
 use crate::NixFile;
 
 use crossbeam_channel as chan;
-use slog_scope::debug;
+use slog_scope::{debug, info, warn};
 use std::convert::{TryFrom, TryInto};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use varlink::ConnectionHandler;
+
+/// How long a shutdown waits for the monitoring clients to receive the final event.
+const MONITOR_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// The daemon server.
+#[derive(Clone)]
 pub struct Server {
     activity_tx: chan::Sender<IndicateActivity>,
     build_tx: chan::Sender<LoopHandlerEvent>,
     socket_path: SocketPath,
-    _lock: BindLock,
+    listener: Arc<UnixListener>,
+    binding: Binding,
+    activity: Activity,
+    active_projects: Arc<Mutex<ActiveProjects>>,
+    /// Held as long as the server runs, released on shutdown.
+    lock: Arc<Mutex<Option<BindLock>>>,
+    /// The TCP connection served, `None` on the unix socket.
+    session: Option<Arc<Session>>,
+    /// Disconnected once the `Monitor` call they belong to has ended.
+    monitors: Arc<Mutex<Vec<chan::Receiver<()>>>>,
 }
 
 impl Server {
     /// Create a new Server. Locks the Unix socket path, so there can be only one Server instance
-    /// per socket path at any time.
+    /// per socket path at any time, and listens on it.
     pub fn new(
         socket_path: SocketPath,
         activity_tx: chan::Sender<IndicateActivity>,
         build_tx: chan::Sender<LoopHandlerEvent>,
+        activity: Activity,
+        active_projects: ActiveProjects,
     ) -> Result<Server, ExitError> {
         let lock = socket_path.lock()?;
+        let (listener, binding) = socket_path.listen()?;
+        if let Binding::Activated = binding {
+            info!("listening on the socket passed by the service manager");
+        }
         Ok(Server {
             socket_path,
             activity_tx,
             build_tx,
-            _lock: lock,
+            listener: Arc::new(listener),
+            binding,
+            activity,
+            active_projects: Arc::new(Mutex::new(active_projects)),
+            lock: Arc::new(Mutex::new(Some(lock))),
+            session: None,
+            monitors: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
-    /// Serve the daemon endpoint.
-    pub fn serve(self) -> Result<(), ExitError> {
-        let address = &self.socket_path.address();
-        let service = varlink::VarlinkService::new(
+    fn service(&self) -> varlink::VarlinkService {
+        varlink::VarlinkService::new(
             /* vendor */ "com.target",
             /* product */ "lorri",
-            /* version */ "0.1",
+            /* version */ crate::LORRI_VERSION,
             /* url */ "https://github.com/target/lorri",
-            vec![Box::new(internal_proto::new(Box::new(self)))],
-        );
-        let initial_worker_threads = 1;
-        let max_worker_threads = 10;
-        let idle_timeout = 0;
-        varlink::listen(
-            service,
-            address,
-            initial_worker_threads,
-            max_worker_threads,
-            idle_timeout,
+            vec![
+                Box::new(internal_proto::new(Box::new(self.clone()))),
+                Box::new(proto::new(Box::new(self.clone()))),
+            ],
         )
-        .map_err(|e| ExitError::temporary(format!("{}", e)))
+    }
+
+    /// Serve the daemon endpoint, with a thread for each client.
+    pub fn serve(self) -> Result<(), ExitError> {
+        let service = Arc::new(self.service());
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("cannot accept a client"; "error" => ?e);
+                    continue;
+                }
+            };
+            let reader = match stream.try_clone() {
+                Ok(reader) => reader,
+                Err(e) => {
+                    warn!("cannot read from client"; "error" => ?e);
+                    continue;
+                }
+            };
+            let service = service.clone();
+            let client = self.activity.client();
+            std::thread::Builder::new()
+                .name("client".to_string())
+                .spawn(move || {
+                    serve_client(&service, reader, stream);
+                    drop(client);
+                })
+                .map_err(|e| {
+                    ExitError::temporary(format!("cannot start a client thread: {}", e))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Serve clients connecting to `listener`, like `serve`. Each connection
+    /// has to be authenticated with `token` before its calls are served.
+    pub fn serve_tcp(self, listener: TcpListener, token: String) -> Result<(), ExitError> {
+        let token = Arc::new(token);
+        for stream in listener.incoming() {
+            let (reader, stream) = match stream.and_then(|s| Ok((s.try_clone()?, s))) {
+                Ok(streams) => streams,
+                Err(e) => {
+                    warn!("cannot accept a TCP client"; "error" => ?e);
+                    continue;
+                }
+            };
+            debug!("TCP client connected"; "peer" => ?stream.peer_addr().ok());
+            let service = Server {
+                session: Some(Arc::new(Session::new(token.clone()))),
+                ..self.clone()
+            }
+            .service();
+            let client = self.activity.client();
+            std::thread::Builder::new()
+                .name("tcp-client".to_string())
+                .spawn(move || {
+                    serve_client(&service, reader, stream);
+                    drop(client);
+                })
+                .map_err(|e| {
+                    ExitError::temporary(format!("cannot start a client thread: {}", e))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Whether the calls of this connection may be served:
+    /// always on the unix socket, on TCP once it is authenticated.
+    fn authenticated(&self) -> bool {
+        match &self.session {
+            None => true,
+            Some(session) => session.is_authenticated(),
+        }
+    }
+
+    /// Whether `token` authenticates this connection, see `Session::authenticate`.
+    fn authenticate(&self, token: &str) -> bool {
+        match &self.session {
+            // the unix socket needs no token
+            None => true,
+            Some(session) => {
+                let valid = session.authenticate(token);
+                if !valid {
+                    warn!("a TCP client sent an invalid token");
+                }
+                valid
+            }
+        }
+    }
+
+    /// Stop building (see `LoopHandlerEvent::Shutdown`), remove the socket file
+    /// if lorri bound it, and release the lock. The process should exit afterwards.
+    pub fn wind_down(&self, cancel_builds: bool) {
+        let (done_tx, done_rx) = chan::bounded(1);
+        let sent = self.build_tx.send(LoopHandlerEvent::Shutdown {
+            cancel_builds,
+            done: done_tx,
+        });
+        // Wait until no build is running and the listeners were told.
+        if sent.is_ok() {
+            let _ = done_rx.recv();
+        }
+        // Give the monitoring clients the chance to receive the final event,
+        // before the process exits.
+        let monitors = self.monitors.lock().expect("monitors poisoned").clone();
+        for monitor in monitors {
+            if monitor.recv_timeout(MONITOR_SHUTDOWN_TIMEOUT)
+                != Err(chan::RecvTimeoutError::Disconnected)
+            {
+                warn!("a monitoring client did not receive the shutdown");
+            }
+        }
+
+        // The socket file is removed while we still hold the lock,
+        // so it cannot belong to a daemon started in the meantime.
+        // An activated socket stays, so the service manager can start us again.
+        if let Binding::Bound = self.binding {
+            if let Err(e) = std::fs::remove_file(self.socket_path.path()) {
+                warn!("could not remove the daemon socket"; "socket" => ?self.socket_path.path(), "error" => ?e);
+            }
+        }
+        drop(self.lock.lock().expect("daemon lock poisoned").take());
+    }
+}
+
+/// Handle the calls of one client until it disconnects.
+fn serve_client(service: &varlink::VarlinkService, reader: impl Read, mut writer: impl Write) {
+    let mut reader = BufReader::new(reader);
+    let mut upgraded_iface = None;
+    loop {
+        match service.handle(&mut reader, &mut writer, upgraded_iface.clone()) {
+            Ok((_, iface)) => {
+                upgraded_iface = iface;
+                match reader.fill_buf() {
+                    Ok(buf) if !buf.is_empty() => {}
+                    // the client hung up
+                    _ => break,
+                }
+            }
+            Err(e) => {
+                match e.kind() {
+                    varlink::ErrorKind::ConnectionClosed | varlink::ErrorKind::SerdeJsonDe(_) => {}
+                    _ => debug!("client error"; "error" => ?e),
+                }
+                break;
+            }
+        }
     }
 }
 
@@ -72,12 +242,24 @@ impl internal_proto::VarlinkInterface for Server {
         &self,
         call: &mut dyn internal_proto::Call_WatchShell,
         shell_nix: internal_proto::ShellNix,
+        rebuild: Option<bool>,
     ) -> varlink::Result<()> {
+        if !self.authenticated() {
+            return call.reply_authentication_required();
+        }
         let p = PathBuf::from(&shell_nix.path);
         if p.is_file() {
+            let nix_file = NixFile::from(p);
+            self.activity.project(&nix_file);
+            self.active_projects
+                .lock()
+                .expect("active projects poisoned")
+                .touch(&nix_file);
             self.activity_tx
                 .send(IndicateActivity {
-                    nix_file: NixFile::from(p),
+                    nix_file,
+                    restored: false,
+                    rebuild: rebuild.unwrap_or(false),
                 })
                 .expect("failed to indicate activity via channel");
             call.reply()
@@ -85,6 +267,45 @@ impl internal_proto::VarlinkInterface for Server {
             call.reply_invalid_parameter(format!("{:?}", shell_nix))
         }
     }
+
+    fn get_info(&self, call: &mut dyn internal_proto::Call_GetInfo) -> varlink::Result<()> {
+        if !self.authenticated() {
+            return call.reply_authentication_required();
+        }
+        call.reply(internal_proto::DaemonInfo {
+            version: crate::LORRI_VERSION.to_string(),
+            build_rev: crate::VERSION_BUILD_REV as i64,
+            protocol_version: super::PROTOCOL_VERSION,
+        })
+    }
+
+    fn shutdown(
+        &self,
+        call: &mut dyn internal_proto::Call_Shutdown,
+        cancel_builds: bool,
+    ) -> varlink::Result<()> {
+        if !self.authenticated() {
+            return call.reply_authentication_required();
+        }
+        info!("shutting down"; "cancel_builds" => cancel_builds);
+        self.wind_down(cancel_builds);
+        call.reply()?;
+        info!("stopped");
+        // The accept loop never returns on its own.
+        std::process::exit(0)
+    }
+
+    fn authenticate(
+        &self,
+        call: &mut dyn internal_proto::Call_Authenticate,
+        token: String,
+    ) -> varlink::Result<()> {
+        if Server::authenticate(self, &token) {
+            call.reply()
+        } else {
+            call.reply_invalid_token()
+        }
+    }
 }
 
 // TODO: remove when switching to a protocol that can do [u8]
@@ -106,14 +327,34 @@ fn log_line_to_string(ll: &crate::error::LogLine) -> String {
 }
 
 impl proto::VarlinkInterface for Server {
-    fn monitor(&self, call: &mut dyn proto::Call_Monitor) -> varlink::Result<()> {
+    fn monitor(
+        &self,
+        call: &mut dyn proto::Call_Monitor,
+        filter: Option<proto::MonitorFilter>,
+    ) -> varlink::Result<()> {
+        if !self.authenticated() {
+            return call.reply_authentication_required();
+        }
         if !call.wants_more() {
             return call.reply_invalid_parameter("wants_more".to_string());
         }
+        let logs = filter.as_ref().and_then(|f| f.logs).unwrap_or(true);
+        let filter = match filter.map(EventFilter::try_from).transpose() {
+            Ok(filter) => filter.unwrap_or_default(),
+            Err(e) => return call.reply_invalid_parameter(format!("filter: {}", e)),
+        };
+
+        // dropped when this call ends, see `wind_down`
+        let (_running, running_rx) = chan::bounded::<()>(0);
+        {
+            let mut monitors = self.monitors.lock().expect("monitors poisoned");
+            monitors.retain(|m| m.try_recv() != Err(chan::TryRecvError::Disconnected));
+            monitors.push(running_rx);
+        }
 
         let (tx, rx) = chan::unbounded();
         self.build_tx
-            .send(LoopHandlerEvent::NewListener(tx))
+            .send(LoopHandlerEvent::NewListener(tx, filter))
             .map_err(|_| varlink::error::ErrorKind::Server)?;
 
         call.set_continues(true);
@@ -121,12 +362,97 @@ impl proto::VarlinkInterface for Server {
             debug!("event for varlink"; "event" => ?&event);
             // TODO: destructure the owned event instead of a pointer to the event here
             match event.try_into() {
-                Ok(ev) => call.reply(ev),
+                Ok(mut ev) => {
+                    if !logs {
+                        drop_logs(&mut ev);
+                    }
+                    call.reply(ev)
+                }
                 Err(e) => call.reply_invalid_parameter(e.to_string()),
             }?;
         }
         Ok(())
     }
+
+    fn authenticate(
+        &self,
+        call: &mut dyn proto::Call_Authenticate,
+        token: String,
+    ) -> varlink::Result<()> {
+        if Server::authenticate(self, &token) {
+            call.reply()
+        } else {
+            call.reply_invalid_token()
+        }
+    }
+}
+
+/// Remove the log lines of a failed build from `event`.
+fn drop_logs(event: &mut proto::Event) {
+    if let Some(exit) = event.failure.as_mut().and_then(|f| f.exit.as_mut()) {
+        exit.logs.clear();
+    }
+}
+
+impl TryFrom<proto::MonitorFilter> for EventFilter {
+    type Error = String;
+
+    fn try_from(filter: proto::MonitorFilter) -> Result<Self, Self::Error> {
+        let absolute = |path: String| -> Result<PathBuf, String> {
+            let path = PathBuf::from(path);
+            if path.is_absolute() {
+                Ok(path)
+            } else {
+                Err(format!("{} is not an absolute path", path.display()))
+            }
+        };
+        Ok(EventFilter {
+            nix_files: filter
+                .nix_files
+                .unwrap_or_default()
+                .into_iter()
+                .map(|f| absolute(f).map(NixFile::from))
+                .collect::<Result<_, _>>()?,
+            project_roots: filter
+                .project_roots
+                .unwrap_or_default()
+                .into_iter()
+                .map(absolute)
+                .collect::<Result<_, _>>()?,
+            kinds: filter
+                .kinds
+                .unwrap_or_default()
+                .iter()
+                .map(|kind| kind.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl TryFrom<&EventFilter> for proto::MonitorFilter {
+    type Error = String;
+
+    fn try_from(filter: &EventFilter) -> Result<Self, Self::Error> {
+        let some = |v: Vec<String>| if v.is_empty() { None } else { Some(v) };
+        Ok(proto::MonitorFilter {
+            nix_files: some(
+                filter
+                    .nix_files
+                    .iter()
+                    .map(try_nix_file_to_string)
+                    .collect::<Result<_, _>>()?,
+            ),
+            project_roots: some(
+                filter
+                    .project_roots
+                    .iter()
+                    .map(|root| try_file_to_string(root))
+                    .collect::<Result<_, _>>()?,
+            ),
+            kinds: some(filter.kinds.iter().map(|kind| kind.to_string()).collect()),
+            logs: None,
+        })
+    }
 }
 
 // TODO: replace all these TryFrom instances with one explicit transformation function.
@@ -146,10 +472,14 @@ impl TryFrom<&build_loop::Event> for proto::Event {
                 result: None,
                 failure: None,
             },
-            Event::Started { reason, .. } => proto::Event {
+            Event::Started { nix_file, reason } => proto::Event {
                 kind: kind::started,
                 section: None,
-                reason: Some(reason.try_into()?),
+                // the project is needed to tell whose build started
+                reason: Some(proto::Reason {
+                    project: Some(try_nix_file_to_string(nix_file)?),
+                    ..reason.try_into()?
+                }),
                 result: None,
                 failure: None,
             },
@@ -167,6 +497,13 @@ impl TryFrom<&build_loop::Event> for proto::Event {
                 result: None,
                 failure: Some(ev.try_into()?),
             },
+            Event::Shutdown => proto::Event {
+                kind: kind::shutdown,
+                section: None,
+                reason: None,
+                result: None,
+                failure: None,
+            },
         })
     }
 }
@@ -198,6 +535,7 @@ impl TryFrom<proto::Event> for build_loop::Event {
             started => re.reason.ok_or("missing reason")?.try_into()?,
             completed => re.result.ok_or("missing result")?.try_into()?,
             failure => re.failure.ok_or("missing failure log")?.try_into()?,
+            shutdown => build_loop::Event::Shutdown,
         })
     }
 }
@@ -289,6 +627,14 @@ impl TryFrom<&build_loop::Event> for proto::Outcome {
             Ok(proto::Outcome {
                 nix_file: try_nix_file_to_string(nix_file)?,
                 project_root: result.output_paths.shell_gc_root.to_string(),
+                env_changes: result
+                    .env_changes
+                    .as_ref()
+                    .map(|changes| proto::EnvChanges {
+                        added: changes.added.clone(),
+                        removed: changes.removed.clone(),
+                        changed: changes.changed.clone(),
+                    }),
             })
         } else {
             Err(format!("can't make an Outcome out of {:?}", ev))
@@ -311,12 +657,18 @@ impl From<proto::Outcome> for build_loop::BuildResults {
     fn from(ro: proto::Outcome) -> Self {
         use crate::build_loop::BuildResults;
         use crate::builder::OutputPaths;
+        use crate::env::diff::Summary;
         use crate::project::roots::RootPath;
 
         BuildResults {
             output_paths: OutputPaths {
                 shell_gc_root: RootPath(PathBuf::from(ro.project_root)),
             },
+            env_changes: ro.env_changes.map(|changes| Summary {
+                added: changes.added,
+                removed: changes.removed,
+                changed: changes.changed,
+            }),
         }
     }
 }
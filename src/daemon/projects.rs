@@ -0,0 +1,162 @@
+//! Remembers the projects the daemon watches, so a restarted daemon
+//! can pick up where the previous one left off.
+//!
+//! The projects live in `active_projects.json` in the lorri cache directory,
+//! together with the time each was last pinged.
+
+use crate::project::{read_json, write_json};
+use crate::NixFile;
+use slog_scope::warn;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The activity time of a project is written at most this often.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    nix_file: NixFile,
+    /// When the project was last pinged, in seconds since the UNIX epoch
+    last_active: u64,
+}
+
+/// The projects watched by the daemon, and when they were last active.
+pub struct ActiveProjects {
+    file: PathBuf,
+    last_active: HashMap<NixFile, u64>,
+}
+
+impl ActiveProjects {
+    /// Read the projects from `file`.
+    /// A missing or unreadable file means there are none.
+    pub fn load(file: PathBuf) -> ActiveProjects {
+        let entries: Vec<Entry> = match read_json(&file) {
+            Ok(entries) => entries.unwrap_or_else(Vec::new),
+            Err(e) => {
+                warn!("cannot read the active projects"; "file" => ?file, "error" => ?e);
+                vec![]
+            }
+        };
+        ActiveProjects {
+            file,
+            last_active: entries
+                .into_iter()
+                .map(|e| (e.nix_file, e.last_active))
+                .collect(),
+        }
+    }
+
+    /// Record that `nix_file` was pinged just now.
+    pub fn touch(&mut self, nix_file: &NixFile) {
+        self.touch_at(nix_file, now())
+    }
+
+    fn touch_at(&mut self, nix_file: &NixFile, now: u64) {
+        // pings are frequent, so the time is only updated once it is outdated
+        let stale = match self.last_active.get(nix_file) {
+            Some(last) => now.saturating_sub(*last) >= SAVE_INTERVAL.as_secs(),
+            None => true,
+        };
+        if stale {
+            self.last_active.insert(nix_file.clone(), now);
+            self.save();
+        }
+    }
+
+    /// The projects to watch again, most recently active first.
+    /// Projects that were not active within `forget_after`,
+    /// or whose nix file is gone, are forgotten.
+    pub fn restorable(&mut self, forget_after: Duration) -> Vec<NixFile> {
+        self.restorable_at(forget_after, now())
+    }
+
+    fn restorable_at(&mut self, forget_after: Duration, now: u64) -> Vec<NixFile> {
+        let before = self.last_active.len();
+        self.last_active.retain(|nix_file, last| {
+            now.saturating_sub(*last) < forget_after.as_secs() && nix_file.as_path().is_file()
+        });
+        if self.last_active.len() != before {
+            self.save();
+        }
+        let mut projects = self.last_active.iter().collect::<Vec<_>>();
+        projects.sort_by(|(_, a), (_, b)| b.cmp(a));
+        projects
+            .into_iter()
+            .map(|(nix_file, _)| nix_file.clone())
+            .collect()
+    }
+
+    fn save(&self) {
+        let mut entries = self
+            .last_active
+            .iter()
+            .map(|(nix_file, last_active)| Entry {
+                nix_file: nix_file.clone(),
+                last_active: *last_active,
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.nix_file.as_path().cmp(b.nix_file.as_path()));
+        if let Err(e) = write_json(&self.file, &entries) {
+            warn!("cannot save the active projects"; "file" => ?self.file, "error" => ?e);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_recent_projects() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("active_projects.json");
+        let project = |name: &str| -> std::io::Result<NixFile> {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, "")?;
+            Ok(NixFile::from(path))
+        };
+        let (old, recent, newest) = (
+            project("old.nix")?,
+            project("recent.nix")?,
+            project("new.nix")?,
+        );
+        let gone = NixFile::from(tmp.path().join("gone.nix"));
+        let day = 24 * 60 * 60;
+
+        let mut projects = ActiveProjects::load(file.clone());
+        projects.touch_at(&old, 0);
+        projects.touch_at(&recent, 20 * day);
+        projects.touch_at(&gone, 20 * day);
+        projects.touch_at(&newest, 25 * day);
+        // too soon to be recorded
+        projects.touch_at(&recent, 20 * day + 60);
+
+        let forget_after = Duration::from_secs(10 * day);
+        assert_eq!(
+            ActiveProjects::load(file.clone()).restorable_at(forget_after, 26 * day),
+            vec![newest.clone(), recent]
+        );
+        // forgotten projects are dropped from the file
+        assert_eq!(ActiveProjects::load(file).last_active.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_file() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("active_projects.json");
+        std::fs::write(&file, "not json")?;
+        assert!(ActiveProjects::load(file)
+            .restorable(Duration::from_secs(60))
+            .is_empty());
+        Ok(())
+    }
+}
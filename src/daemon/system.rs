@@ -0,0 +1,519 @@
+//! The system daemon (`lorri daemon --system`), one daemon serving all users
+//! of a shared machine.
+//!
+//! It runs as root and listens on a socket every user can connect to.
+//! Clients are told apart by the credentials of their connection (`SO_PEERCRED`).
+//! Each connection is passed on to a `lorri daemon` running as the connecting
+//! user, which the system daemon starts when needed. Builds thus run with the
+//! permissions of that user, and GC roots and events stay separate per user.
+//!
+//! The user daemon keeps its files where the user’s lorri commands look for them:
+//! the `XDG_*` directories are taken from the environment of the connecting client.
+
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::socket::{Binding, SocketPath};
+use nix::libc;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::{Gid, Uid};
+use slog_scope::{debug, info, warn};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a user daemon may take to start listening.
+const USER_DAEMON_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Environment variables passed on to the user daemons.
+/// All others are cleared, so the daemons use the directories of their user.
+const PASSED_ENV: &[&str] = &["PATH", "NIX_PATH", "NIX_REMOTE", "NIX_SSL_CERT_FILE"];
+
+/// Where lorri keeps the files of a user, see `Paths::initialize`.
+/// `None` means the default below the home directory.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct UserDirs {
+    /// `$XDG_CACHE_HOME`
+    cache: Option<PathBuf>,
+    /// `$XDG_CONFIG_HOME`
+    config: Option<PathBuf>,
+    /// `$XDG_RUNTIME_DIR`
+    runtime: Option<PathBuf>,
+}
+
+impl UserDirs {
+    /// The directories in the environment of process `pid`, if it belongs to `uid`.
+    /// The default directories if its environment cannot be read.
+    fn of_process(pid: libc::pid_t, uid: Uid) -> UserDirs {
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+        // the pid might be reused by now, or belong to another pid namespace
+        match std::fs::metadata(&proc_dir) {
+            Ok(meta) if meta.uid() == uid.as_raw() => {}
+            _ => return UserDirs::default(),
+        }
+        match std::fs::read(proc_dir.join("environ")) {
+            Ok(environ) => UserDirs::from_environ(&environ),
+            Err(e) => {
+                warn!("cannot read the environment of a client"; "pid" => pid, "error" => ?e);
+                UserDirs::default()
+            }
+        }
+    }
+
+    /// The directories in `environ`, NUL-separated `NAME=value` pairs.
+    /// Relative paths are ignored, like the XDG base directory specification says.
+    fn from_environ(environ: &[u8]) -> UserDirs {
+        let mut dirs = UserDirs::default();
+        for var in environ.split(|b| *b == 0) {
+            let mut parts = var.splitn(2, |b| *b == b'=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name, PathBuf::from(OsStr::from_bytes(value))),
+                _ => continue,
+            };
+            if !value.is_absolute() {
+                continue;
+            }
+            match name {
+                b"XDG_CACHE_HOME" => dirs.cache = Some(value),
+                b"XDG_CONFIG_HOME" => dirs.config = Some(value),
+                b"XDG_RUNTIME_DIR" => dirs.runtime = Some(value),
+                _ => {}
+            }
+        }
+        dirs
+    }
+
+    /// The environment variables setting these directories.
+    fn env(&self) -> Vec<(&'static str, &Path)> {
+        let mut env = vec![];
+        if let Some(cache) = &self.cache {
+            env.push(("XDG_CACHE_HOME", cache.as_path()));
+        }
+        if let Some(config) = &self.config {
+            env.push(("XDG_CONFIG_HOME", config.as_path()));
+        }
+        if let Some(runtime) = &self.runtime {
+            env.push(("XDG_RUNTIME_DIR", runtime.as_path()));
+        }
+        env
+    }
+}
+
+/// A user account, as found in the password database.
+struct Account {
+    name: CString,
+    uid: Uid,
+    gid: Gid,
+    home: PathBuf,
+}
+
+impl Account {
+    /// Look up the account of `uid`.
+    fn of(uid: Uid) -> Result<Account, String> {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut buf: Vec<libc::c_char> = vec![0; 1024];
+        let mut found: *mut libc::passwd = std::ptr::null_mut();
+        loop {
+            // the strings in `pwd` point into `buf`, which outlives them
+            let ret = unsafe {
+                libc::getpwuid_r(
+                    uid.as_raw(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                )
+            };
+            match ret {
+                0 => break,
+                libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+                e => {
+                    return Err(format!(
+                        "cannot look up user {}: {}",
+                        uid,
+                        std::io::Error::from_raw_os_error(e)
+                    ))
+                }
+            }
+        }
+        if found.is_null() {
+            return Err(format!("user {} does not exist", uid));
+        }
+        let (name, home) = unsafe { (CStr::from_ptr(pwd.pw_name), CStr::from_ptr(pwd.pw_dir)) };
+        Ok(Account {
+            name: name.to_owned(),
+            uid,
+            gid: Gid::from_raw(pwd.pw_gid),
+            home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+        })
+    }
+
+    /// Where the daemon of this user with `dirs` listens, see `Paths::initialize`.
+    fn daemon_socket(&self, dirs: &UserDirs) -> PathBuf {
+        let dir = match (&dirs.runtime, &dirs.cache) {
+            (Some(runtime), _) => runtime.clone(),
+            (None, Some(cache)) => cache.clone(),
+            (None, None) => self.home.join(".cache"),
+        };
+        dir.join("lorri").join("daemon.socket")
+    }
+}
+
+/// The user daemons started by the system daemon, by socket.
+type UserDaemons = Arc<Mutex<HashMap<PathBuf, Child>>>;
+
+/// Listen on `socket_path` and relay every connection to the daemon of the
+/// connecting user. `extra_nix_options` are passed on to the user daemons.
+pub fn serve(
+    socket_path: SocketPath,
+    extra_nix_options: Option<&crate::cli::NixOptions>,
+) -> OpResult {
+    if !nix::unistd::geteuid().is_root() {
+        return Err(ExitError::user_error(
+            "the system daemon has to run as root, to start daemons as the connecting users",
+        ));
+    }
+    if let Some(dir) = socket_path.path().parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| ExitError::temporary(format!("cannot create {}: {}", dir.display(), e)))?;
+    }
+    let _lock = socket_path.lock().map_err(|e| {
+        ExitError::temporary(format!(
+            "unable to lock the system daemon socket at {}: {:?}",
+            socket_path.path().display(),
+            e
+        ))
+    })?;
+    let (listener, binding) = socket_path.listen()?;
+    if binding == Binding::Bound {
+        // everybody may connect, users are told apart by their credentials
+        std::fs::set_permissions(socket_path.path(), PermissionsExt::from_mode(0o666)).map_err(
+            |e| {
+                ExitError::temporary(format!(
+                    "cannot make {} accessible to all users: {}",
+                    socket_path.path().display(),
+                    e
+                ))
+            },
+        )?;
+    }
+    let daemon_args = Arc::new(daemon_args(extra_nix_options)?);
+    let daemons: UserDaemons = Arc::new(Mutex::new(HashMap::new()));
+    info!("system daemon ready"; "socket" => ?socket_path.path(), "binding" => ?binding);
+
+    for stream in listener.incoming() {
+        let client = match stream {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("cannot accept a connection"; "error" => ?e);
+                continue;
+            }
+        };
+        let daemons = daemons.clone();
+        let daemon_args = daemon_args.clone();
+        std::thread::Builder::new()
+            .name(String::from("relay"))
+            .spawn(move || {
+                if let Err(e) = relay(client, &daemons, &daemon_args) {
+                    warn!("cannot serve a client"; "error" => e);
+                }
+            })
+            .map_err(|e| ExitError::temporary(format!("cannot start a thread: {}", e)))?;
+    }
+    ok()
+}
+
+/// The arguments of the `lorri daemon` started for each user.
+fn daemon_args(
+    extra_nix_options: Option<&crate::cli::NixOptions>,
+) -> Result<Vec<String>, ExitError> {
+    let mut args = vec![String::from("daemon")];
+    if let Some(options) = extra_nix_options {
+        args.push(String::from("--extra-nix-options"));
+        args.push(serde_json::to_string(options).map_err(|e| {
+            ExitError::user_error(format!("cannot pass on the nix options: {}", e))
+        })?);
+    }
+    Ok(args)
+}
+
+/// Pass the connection of `client` on to the daemon of its user,
+/// starting that daemon first if necessary.
+fn relay(client: UnixStream, daemons: &UserDaemons, daemon_args: &[String]) -> Result<(), String> {
+    let (uid, pid) = peer(&client)?;
+    let account = Account::of(uid)?;
+    let dirs = UserDirs::of_process(pid, uid);
+    debug!("relaying a client"; "user" => ?account.name, "uid" => uid.as_raw(), "dirs" => ?dirs);
+    let daemon = connect_user_daemon(&account, &dirs, daemons, daemon_args)?;
+    relay_to(client, uid, daemon)
+}
+
+/// Pass the connection of `client`, of user `uid`, on to `daemon`,
+/// which has to be a daemon of the same user.
+fn relay_to(client: UnixStream, uid: Uid, daemon: UnixStream) -> Result<(), String> {
+    // the socket lives in a directory of the user, who could swap it
+    // for the socket of somebody else
+    let (daemon_uid, _) = peer(&daemon)?;
+    if daemon_uid != uid {
+        return Err(format!(
+            "the daemon socket of user {} belongs to user {}",
+            uid, daemon_uid
+        ));
+    }
+    copy_both_ways(client, daemon)
+}
+
+/// The user and process on the other end of `stream`.
+fn peer(stream: &UnixStream) -> Result<(Uid, libc::pid_t), String> {
+    getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .map(|cred| (Uid::from_raw(cred.uid()), cred.pid()))
+        .map_err(|e| format!("cannot get the credentials of a connection: {}", e))
+}
+
+/// Connect to the daemon of `account` with `dirs`, starting it if it is not running.
+fn connect_user_daemon(
+    account: &Account,
+    dirs: &UserDirs,
+    daemons: &UserDaemons,
+    daemon_args: &[String],
+) -> Result<UnixStream, String> {
+    let socket = account.daemon_socket(dirs);
+    if let Ok(stream) = UnixStream::connect(&socket) {
+        return Ok(stream);
+    }
+    {
+        let mut daemons = daemons.lock().expect("user daemons poisoned");
+        let running = match daemons.get_mut(&socket) {
+            Some(child) => match child.try_wait() {
+                Ok(None) => true,
+                Ok(Some(status)) => {
+                    warn!("the daemon of a user exited"; "uid" => account.uid.as_raw(), "status" => ?status);
+                    false
+                }
+                Err(_) => false,
+            },
+            None => false,
+        };
+        if !running {
+            let child = start_user_daemon(account, dirs, daemon_args)
+                .map_err(|e| format!("cannot start the daemon of user {}: {}", account.uid, e))?;
+            info!("started the daemon of a user"; "uid" => account.uid.as_raw(), "pid" => child.id(), "socket" => ?socket);
+            daemons.insert(socket.clone(), child);
+        }
+    }
+    let start = Instant::now();
+    loop {
+        match UnixStream::connect(&socket) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if start.elapsed() > USER_DAEMON_TIMEOUT => {
+                return Err(format!(
+                    "the daemon of user {} is not listening on {}: {}",
+                    account.uid,
+                    socket.display(),
+                    e
+                ))
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Start `lorri daemon` as the user of `account` with `dirs`, in their home directory.
+fn start_user_daemon(
+    account: &Account,
+    dirs: &UserDirs,
+    daemon_args: &[String],
+) -> std::io::Result<Child> {
+    let groups = nix::unistd::getgrouplist(&account.name, account.gid).map_err(nix_to_io)?;
+    let (uid, gid) = (account.uid, account.gid);
+    let name = OsStr::from_bytes(account.name.to_bytes());
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(daemon_args)
+        .current_dir(&account.home)
+        .env_clear()
+        .env("HOME", &account.home)
+        .env("USER", name)
+        .env("LOGNAME", name)
+        .envs(dirs.env());
+    for var in PASSED_ENV {
+        if let Some(value) = std::env::var_os(var) {
+            command.env(var, value);
+        }
+    }
+    // The groups are looked up before forking; only plain system calls
+    // are allowed between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            nix::unistd::setgroups(&groups).map_err(nix_to_io)?;
+            nix::unistd::setgid(gid).map_err(nix_to_io)?;
+            nix::unistd::setuid(uid).map_err(nix_to_io)
+        });
+    }
+    command.spawn()
+}
+
+fn nix_to_io(e: nix::Error) -> std::io::Error {
+    match e.as_errno() {
+        Some(errno) => std::io::Error::from(errno),
+        None => std::io::Error::from_raw_os_error(libc::EINVAL),
+    }
+}
+
+/// Copy everything `a` sends to `b` and the other way around,
+/// until both sides are done.
+fn copy_both_ways(a: UnixStream, b: UnixStream) -> Result<(), String> {
+    fn copy(mut from: UnixStream, mut to: UnixStream) {
+        let _ = std::io::copy(&mut from, &mut to);
+        let _ = to.shutdown(std::net::Shutdown::Write);
+    }
+    let clone = |s: &UnixStream| {
+        s.try_clone()
+            .map_err(|e| format!("cannot relay a connection: {}", e))
+    };
+    let (a2, b2) = (clone(&a)?, clone(&b)?);
+    let back = std::thread::Builder::new()
+        .name(String::from("relay-back"))
+        .spawn(move || copy(b2, a2))
+        .map_err(|e| format!("cannot start a thread: {}", e))?;
+    copy(a, b);
+    let _ = back.join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    fn dirs(runtime: Option<&str>, cache: Option<&str>) -> UserDirs {
+        UserDirs {
+            cache: cache.map(PathBuf::from),
+            config: None,
+            runtime: runtime.map(PathBuf::from),
+        }
+    }
+
+    #[test]
+    fn daemon_socket_follows_the_directories_of_the_client() {
+        let account = Account::of(nix::unistd::getuid()).unwrap();
+        assert_eq!(
+            account.daemon_socket(&UserDirs::default()),
+            account.home.join(".cache/lorri/daemon.socket")
+        );
+        assert_eq!(
+            account.daemon_socket(&dirs(None, Some("/c"))),
+            Path::new("/c/lorri/daemon.socket")
+        );
+        assert_eq!(
+            account.daemon_socket(&dirs(Some("/r"), Some("/c"))),
+            Path::new("/r/lorri/daemon.socket")
+        );
+
+        assert_eq!(
+            UserDirs::from_environ(
+                b"HOME=/h\0XDG_RUNTIME_DIR=/r\0XDG_CACHE_HOME=relative\0XDG_CONFIG_HOME=/x=y\0"
+            ),
+            UserDirs {
+                cache: None,
+                config: Some(PathBuf::from("/x=y")),
+                runtime: Some(PathBuf::from("/r")),
+            }
+        );
+    }
+
+    #[test]
+    fn directories_are_read_from_the_client_process() -> std::io::Result<()> {
+        let mut client = Command::new("sleep")
+            .arg("10")
+            .env("XDG_CACHE_HOME", "/lorri-test/cache")
+            .env_remove("XDG_RUNTIME_DIR")
+            .env_remove("XDG_CONFIG_HOME")
+            .spawn()?;
+        let uid = nix::unistd::getuid();
+        let pid = client.id() as libc::pid_t;
+        // until `sleep` is executed, the child has the environment of the test,
+        // and while it is executed, none
+        let comm = format!("/proc/{}/comm", pid);
+        let environ = format!("/proc/{}/environ", pid);
+        let start = Instant::now();
+        while std::fs::read_to_string(&comm)? != "sleep\n" || std::fs::read(&environ)?.is_empty() {
+            assert!(start.elapsed() < USER_DAEMON_TIMEOUT, "sleep did not start");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let of_client = UserDirs::of_process(pid, uid);
+        // a process of another user is not trusted
+        let of_other = UserDirs::of_process(pid, Uid::from_raw(uid.as_raw() + 1));
+        client.kill()?;
+        client.wait()?;
+        assert_eq!(of_client, dirs(None, Some("/lorri-test/cache")));
+        assert_eq!(of_other, UserDirs::default());
+        Ok(())
+    }
+
+    /// Connect to `listener`, returning both ends of the connection.
+    fn connection(listener: &UnixListener) -> (UnixStream, UnixStream) {
+        let address = listener.local_addr().unwrap();
+        let client = UnixStream::connect(address.as_pathname().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn relay_to_the_daemon_of_the_user() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let account = Account::of(nix::unistd::getuid()).unwrap();
+        let dirs = dirs(Some(tmp.path().to_str().unwrap()), None);
+        let socket = account.daemon_socket(&dirs);
+        std::fs::create_dir_all(socket.parent().unwrap())?;
+        let user_daemon = UnixListener::bind(&socket)?;
+        let system_daemon = UnixListener::bind(tmp.path().join("system.socket"))?;
+
+        // the user daemon is running already, so none is started
+        let daemons = UserDaemons::default();
+        let daemon = connect_user_daemon(&account, &dirs, &daemons, &[]).unwrap();
+        assert!(daemons.lock().unwrap().is_empty());
+        let (mut client, relayed) = connection(&system_daemon);
+        let relay = std::thread::spawn(move || relay_to(relayed, account.uid, daemon));
+
+        let (mut served, _) = user_daemon.accept()?;
+        client.write_all(b"ping")?;
+        client.shutdown(std::net::Shutdown::Write)?;
+        let mut request = String::new();
+        served.read_to_string(&mut request)?;
+        assert_eq!(request, "ping");
+        served.write_all(b"pong")?;
+        drop(served);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply)?;
+        assert_eq!(reply, "pong");
+        assert_eq!(relay.join().unwrap(), Ok(()));
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_the_daemon_of_another_user() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let user_daemon = UnixListener::bind(tmp.path().join("user.socket"))?;
+        let system_daemon = UnixListener::bind(tmp.path().join("system.socket"))?;
+        let (daemon, _served) = connection(&user_daemon);
+        let (mut client, relayed) = connection(&system_daemon);
+
+        // the daemon socket is ours, but the client claims to be somebody else
+        let other = Uid::from_raw(nix::unistd::getuid().as_raw() + 1);
+        let err = relay_to(relayed, other, daemon).unwrap_err();
+        assert!(err.contains("belongs to user"), "{}", err);
+        // nothing was relayed, the client is disconnected
+        let mut reply = vec![];
+        client.read_to_end(&mut reply)?;
+        assert!(reply.is_empty());
+        Ok(())
+    }
+}
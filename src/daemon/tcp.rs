@@ -0,0 +1,175 @@
+//! Authentication of the clients of the daemon’s TCP listener.
+//!
+//! The unix socket is only reachable for the user of the daemon, but a TCP port
+//! is reachable for anybody who can reach the host. So clients connecting over TCP
+//! first have to call `Authenticate` with the token of the daemon, which is kept in
+//! a file only the user can read (see `Paths::daemon_token_file`).
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use slog_scope::warn;
+
+/// Number of random bytes in a generated token.
+const TOKEN_BYTES: usize = 32;
+
+/// Where the daemon listens for TCP clients, and the token they need.
+pub struct Endpoint {
+    /// The address to bind, like `127.0.0.1:9876`
+    pub address: String,
+    /// The token clients authenticate with
+    pub token: String,
+}
+
+/// Read the token from `file`, creating the file with a new random token
+/// (readable only by the user) if it does not exist or the token in it is
+/// shorter than a generated one.
+///
+/// Fails if the file can be read by other users, since they could
+/// authenticate with the token.
+pub fn token(file: &Path) -> std::io::Result<String> {
+    match std::fs::metadata(file) {
+        Ok(metadata) => {
+            if metadata.permissions().mode() & 0o077 != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!(
+                        "the token file can be accessed by other users, \
+                         remove it or run `chmod 600 {}`",
+                        file.display()
+                    ),
+                ));
+            }
+            let token = std::fs::read_to_string(file)?.trim().to_string();
+            if token.len() >= 2 * TOKEN_BYTES {
+                return Ok(token);
+            }
+            warn!("daemon token is too short, generating a new one"; "file" => ?file);
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut random = [0u8; TOKEN_BYTES];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let token = random
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(file)?
+        .write_all(token.as_bytes())?;
+    Ok(token)
+}
+
+/// A TCP connection, which has to be authenticated before it is served.
+pub struct Session {
+    token: Arc<String>,
+    authenticated: AtomicBool,
+}
+
+impl Session {
+    /// A new, unauthenticated connection to a daemon with `token`.
+    pub fn new(token: Arc<String>) -> Session {
+        Session {
+            token,
+            authenticated: AtomicBool::new(false),
+        }
+    }
+
+    /// Authenticate the connection if `token` is the token of the daemon.
+    /// Returns whether it is.
+    pub fn authenticate(&self, token: &str) -> bool {
+        let valid = same_token(self.token.as_bytes(), token.as_bytes());
+        if valid {
+            self.authenticated.store(true, Ordering::SeqCst);
+        }
+        valid
+    }
+
+    /// Whether `authenticate` succeeded on this connection.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::SeqCst)
+    }
+}
+
+/// Compare the tokens in constant time, so the time a comparison takes
+/// does not tell how much of a guessed token is right.
+fn same_token(expected: &[u8], given: &[u8]) -> bool {
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_created_once() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("daemon_token");
+        let created = token(&file)?;
+        assert_eq!(created.len(), 2 * TOKEN_BYTES);
+        assert_eq!(
+            std::fs::metadata(&file)?.permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(token(&file)?, created);
+        Ok(())
+    }
+
+    #[test]
+    fn short_token_is_replaced() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("daemon_token");
+        for short in &["", "\n", "secret\n"] {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&file)?
+                .write_all(short.as_bytes())?;
+            let replaced = token(&file)?;
+            assert_eq!(replaced.len(), 2 * TOKEN_BYTES);
+            assert_eq!(std::fs::read_to_string(&file)?, replaced);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn token_readable_by_others_is_refused() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("daemon_token");
+        let created = token(&file)?;
+        for mode in &[0o640, 0o604, 0o620] {
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(*mode))?;
+            let err = token(&file).expect_err("token file can be read by others");
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        }
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600))?;
+        assert_eq!(token(&file)?, created);
+        Ok(())
+    }
+
+    #[test]
+    fn authenticate() {
+        let session = Session::new(Arc::new("secret".to_string()));
+        assert!(!session.authenticate("secre"));
+        assert!(!session.authenticate("secreT"));
+        assert!(!session.is_authenticated());
+        assert!(session.authenticate("secret"));
+        assert!(session.is_authenticated());
+    }
+}
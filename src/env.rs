@@ -0,0 +1,424 @@
+//! The environment of a built project, as dumped by
+//! `logged-evaluation.nix` into the `bash-export` file.
+
+pub mod diff;
+pub mod format;
+pub mod rules;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Environment variables by name.
+pub type Env = BTreeMap<String, String>;
+
+/// A single `declare -x NAME="value"` line of the `bash-export` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Declaration {
+    /// Name of the variable
+    pub name: String,
+    /// `None` if the variable is exported, but has no value (`declare -x NAME`)
+    pub value: Option<String>,
+}
+
+/// The `bash-export` file could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based line the error occurred on
+    pub line: usize,
+    /// What went wrong
+    pub msg: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// Read the environment from the `bash-export` file of the built
+/// environment at `root` (e.g. a `shell_gc_root`).
+/// Variables without value are left out.
+pub fn read_env(root: &Path) -> std::io::Result<Env> {
+    let contents = std::fs::read(root.join("bash-export"))?;
+    let declarations = parse_bash_export(&String::from_utf8_lossy(&contents))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(declarations
+        .into_iter()
+        .filter_map(|Declaration { name, value }| value.map(|v| (name, v)))
+        .collect())
+}
+
+/// The variables loading the built environment at `root` into `base` sets,
+/// see `rules::apply`.
+pub fn evaluate(root: &Path, base: &Env) -> std::io::Result<Env> {
+    let contents = std::fs::read(root.join("bash-export"))?;
+    let declarations = parse_bash_export(&String::from_utf8_lossy(&contents))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    let varmap = match std::fs::read(root.join("varmap-v1")) {
+        Ok(contents) => rules::parse_varmap(&String::from_utf8_lossy(&contents)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e),
+    };
+    Ok(rules::apply(&declarations, &varmap, base))
+}
+
+/// Variables of the user’s session that are kept in pure mode,
+/// like `nix-shell --pure` does.
+pub const KEPT_WHEN_PURE: &[&str] = &[
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XDG_RUNTIME_DIR",
+    "TERM",
+    "TZ",
+    "PAGER",
+    "SHLVL",
+];
+
+/// The variables of `current` that are kept in pure mode:
+/// the ones in `KEPT_WHEN_PURE` and in `keep`.
+pub fn pure(current: &Env, keep: &[String]) -> Env {
+    current
+        .iter()
+        .filter(|(name, _)| KEPT_WHEN_PURE.contains(&name.as_str()) || keep.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Parse the output of bash’s `export` builtin.
+///
+/// Every line has the form `declare -x NAME="value"`, with `"`, `\`, `$`
+/// and `` ` `` escaped by a backslash. Newer bash versions print values
+/// containing control characters ANSI-C quoted instead: `NAME=$'a\nb'`.
+/// Values can span multiple lines.
+pub fn parse_bash_export(input: &str) -> Result<Vec<Declaration>, ParseError> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+    let mut declarations = vec![];
+    loop {
+        parser.skip_whitespace();
+        if parser.chars.peek().is_none() {
+            return Ok(declarations);
+        }
+        declarations.push(parser.declaration()?);
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            msg: msg.to_string(),
+        })
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(true) = self.chars.peek().map(|c| c.is_whitespace()) {
+            self.next();
+        }
+    }
+
+    fn skip_blanks(&mut self) {
+        while let Some(' ') | Some('\t') = self.chars.peek() {
+            self.next();
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() || c == '=' {
+                break;
+            }
+            word.push(c);
+            self.next();
+        }
+        word
+    }
+
+    fn declaration(&mut self) -> Result<Declaration, ParseError> {
+        if self.word() != "declare" {
+            return self.error("expected `declare`");
+        }
+        self.skip_blanks();
+        // flags like `-x` or `-rx`
+        if self.chars.peek() == Some(&'-') {
+            self.word();
+            self.skip_blanks();
+        }
+        let name = self.word();
+        if name.is_empty() {
+            return self.error("expected a variable name");
+        }
+        let value = if self.chars.peek() == Some(&'=') {
+            self.next();
+            Some(self.value()?)
+        } else {
+            None
+        };
+        self.skip_blanks();
+        match self.next() {
+            None | Some('\n') => Ok(Declaration { name, value }),
+            Some(_) => self.error("unexpected characters after the declaration"),
+        }
+    }
+
+    fn value(&mut self) -> Result<String, ParseError> {
+        match self.chars.peek() {
+            Some('"') => {
+                self.next();
+                self.double_quoted()
+            }
+            Some('$') => {
+                self.next();
+                if self.next() != Some('\'') {
+                    return self.error("expected `$'`");
+                }
+                self.ansi_c_quoted()
+            }
+            _ => Ok(self.word()),
+        }
+    }
+
+    fn double_quoted(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                None => return self.error("unterminated double-quoted value"),
+                Some('"') => return Ok(value),
+                Some('\\') => match self.next() {
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') | Some(c @ '`') => value.push(c),
+                    // a line continuation
+                    Some('\n') => {}
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c)
+                    }
+                    None => return self.error("unterminated double-quoted value"),
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn ansi_c_quoted(&mut self) -> Result<String, ParseError> {
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                None => return self.error("unterminated $'…' value"),
+                Some('\'') => return Ok(value),
+                Some('\\') => {
+                    let c = match self.next() {
+                        None => return self.error("unterminated $'…' value"),
+                        Some('a') => '\x07',
+                        Some('b') => '\x08',
+                        Some('e') | Some('E') => '\x1b',
+                        Some('f') => '\x0c',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('v') => '\x0b',
+                        Some('x') => self.numeric_escape(16, 2)?,
+                        Some('u') => self.numeric_escape(16, 4)?,
+                        Some('U') => self.numeric_escape(16, 8)?,
+                        Some(d @ '0'..='7') => {
+                            let mut n = d.to_digit(8).unwrap();
+                            for _ in 0..2 {
+                                match self.chars.peek().and_then(|c| c.to_digit(8)) {
+                                    Some(digit) => {
+                                        n = n * 8 + digit;
+                                        self.next();
+                                    }
+                                    None => break,
+                                }
+                            }
+                            std::char::from_u32(n).unwrap_or('\u{fffd}')
+                        }
+                        // `\\`, `\'`, `\"` and `\?` are literal
+                        Some(c) => c,
+                    };
+                    value.push(c)
+                }
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn numeric_escape(&mut self, radix: u32, max_digits: usize) -> Result<char, ParseError> {
+        let mut n = 0;
+        let mut digits = 0;
+        while digits < max_digits {
+            match self.chars.peek().and_then(|c| c.to_digit(radix)) {
+                Some(digit) => {
+                    n = n * radix + digit;
+                    digits += 1;
+                    self.next();
+                }
+                None => break,
+            }
+        }
+        if digits == 0 {
+            self.error("expected a numeric escape")
+        } else {
+            Ok(std::char::from_u32(n).unwrap_or('\u{fffd}'))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decl(name: &str, value: Option<&str>) -> Declaration {
+        Declaration {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    #[test]
+    fn parse_declarations() {
+        let input = r#"declare -x A="plain"
+declare -x B="a\"b\$c\\d\`e"
+declare -x C
+declare -rx D=""
+declare -x E="multi
+line"
+declare -x F=$'tab\there\nnew\x41\101\'q'
+"#;
+        assert_eq!(
+            parse_bash_export(input),
+            Ok(vec![
+                decl("A", Some("plain")),
+                decl("B", Some("a\"b$c\\d`e")),
+                decl("C", None),
+                decl("D", Some("")),
+                decl("E", Some("multi\nline")),
+                decl("F", Some("tab\there\nnewAA'q")),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line_numbers() {
+        assert_eq!(
+            parse_bash_export("declare -x A=\"a\"\nexport B=c\n"),
+            Err(ParseError {
+                line: 2,
+                msg: "expected `declare`".to_string()
+            })
+        );
+        assert!(parse_bash_export("declare -x A=\"unterminated").is_err());
+    }
+
+    #[test]
+    fn evaluate_follows_the_envrc_rules() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(
+            root.path().join("bash-export"),
+            r#"declare -x HOME="/homeless-shelter"
+declare -x PATH="/nix/store/foo/bin"
+declare -x XDG_DATA_DIRS="/nix/store/foo/share"
+declare -x origPreHook="hook"
+declare -x preHook="ignored"
+declare -x CC="gcc"
+declare -x NOVALUE
+"#,
+        )?;
+        std::fs::write(root.path().join("varmap-v1"), "append\0XDG_DATA_DIRS\0:\0")?;
+        let base = [
+            ("HOME", "/home/user"),
+            ("PATH", "/usr/bin"),
+            ("XDG_DATA_DIRS", "/usr/share"),
+            ("CC", "clang"),
+        ]
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect::<Env>();
+        let env = evaluate(root.path(), &base)?;
+        let expected = [
+            ("CC", "gcc"),
+            ("IN_NIX_SHELL", "impure"),
+            ("PATH", "/nix/store/foo/bin:/usr/bin"),
+            ("XDG_DATA_DIRS", "/usr/share:/nix/store/foo/share"),
+            ("preHook", "hook"),
+        ]
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect::<Env>();
+        assert_eq!(env, expected);
+
+        // without a value in the base environment, nothing is prepended or appended
+        let env = evaluate(root.path(), &Env::new())?;
+        assert_eq!(env["PATH"], "/nix/store/foo/bin");
+        assert_eq!(env["XDG_DATA_DIRS"], "/nix/store/foo/share");
+        Ok(())
+    }
+
+    #[test]
+    fn pure_keeps_only_the_session() {
+        let current = [
+            ("HOME", "/home/user"),
+            ("PATH", "/usr/bin"),
+            ("SSH_AUTH_SOCK", "/run/agent"),
+            ("CC", "clang"),
+        ]
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect::<Env>();
+        assert_eq!(
+            pure(&current, &["SSH_AUTH_SOCK".to_string()])
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["HOME", "SSH_AUTH_SOCK"]
+        );
+        // nothing is prepended to the host PATH
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(
+            root.path().join("bash-export"),
+            "declare -x PATH=\"/nix/store/foo/bin\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            evaluate(root.path(), &pure(&current, &[])).unwrap()["PATH"],
+            "/nix/store/foo/bin"
+        );
+    }
+
+    #[test]
+    fn parse_real_bash_output() {
+        let out = std::process::Command::new("bash")
+            .args(["-c", "export"])
+            .env_clear()
+            .env("SIMPLE", "value")
+            .env("QUOTES", "\"'$`\\")
+            .env("CONTROL", "a\nb\tc")
+            .output()
+            .expect("bash must be available");
+        let env = parse_bash_export(&String::from_utf8(out.stdout).unwrap())
+            .unwrap()
+            .into_iter()
+            .filter_map(|Declaration { name, value }| value.map(|v| (name, v)))
+            .collect::<Env>();
+        assert_eq!(env.get("SIMPLE").map(String::as_str), Some("value"));
+        assert_eq!(env.get("QUOTES").map(String::as_str), Some("\"'$`\\"));
+        assert_eq!(env.get("CONTROL").map(String::as_str), Some("a\nb\tc"));
+    }
+}
@@ -0,0 +1,199 @@
+//! Compare two environments, e.g. of two builds of a project.
+
+use super::Env;
+
+/// How one environment differs from another.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EnvDiff {
+    /// Variables only in the new environment, with their values
+    pub added: Vec<(String, String)>,
+    /// Variables only in the old environment, with their values
+    pub removed: Vec<(String, String)>,
+    /// Variables in both environments, with different values
+    pub changed: Vec<Changed>,
+}
+
+/// A variable with a different value in the new environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Changed {
+    /// Name of the variable
+    pub name: String,
+    /// Value in the old environment
+    pub old: String,
+    /// Value in the new environment
+    pub new: String,
+    /// For search path variables like `PATH`, the changed components
+    pub components: Option<ComponentDiff>,
+}
+
+/// The components added to and removed from a search path variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentDiff {
+    /// Components only in the new value, in order
+    pub added: Vec<String>,
+    /// Components only in the old value, in order
+    pub removed: Vec<String>,
+}
+
+/// Just the names of the variables that changed,
+/// for places where the values would be too much.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Names of the added variables
+    pub added: Vec<String>,
+    /// Names of the removed variables
+    pub removed: Vec<String>,
+    /// Names of the variables with a different value
+    pub changed: Vec<String>,
+}
+
+/// Whether `name` is a list of paths separated by `:`,
+/// which is diffed component-wise.
+fn is_search_path(name: &str) -> bool {
+    name.ends_with("PATH") || name.ends_with("_DIRS")
+}
+
+/// Compare `old` to `new`.
+pub fn diff(old: &Env, new: &Env) -> EnvDiff {
+    let mut result = EnvDiff::default();
+    for (name, new_value) in new {
+        match old.get(name) {
+            None => result.added.push((name.clone(), new_value.clone())),
+            Some(old_value) if old_value != new_value => result.changed.push(Changed {
+                name: name.clone(),
+                old: old_value.clone(),
+                new: new_value.clone(),
+                components: if is_search_path(name) {
+                    Some(diff_components(old_value, new_value))
+                } else {
+                    None
+                },
+            }),
+            Some(_) => {}
+        }
+    }
+    for (name, old_value) in old {
+        if !new.contains_key(name) {
+            result.removed.push((name.clone(), old_value.clone()))
+        }
+    }
+    result
+}
+
+fn diff_components(old: &str, new: &str) -> ComponentDiff {
+    let old = old.split(':').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+    let new = new.split(':').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+    ComponentDiff {
+        added: new
+            .iter()
+            .filter(|c| !old.contains(c))
+            .map(|c| c.to_string())
+            .collect(),
+        removed: old
+            .iter()
+            .filter(|c| !new.contains(c))
+            .map(|c| c.to_string())
+            .collect(),
+    }
+}
+
+impl EnvDiff {
+    /// No variable differs.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// The names of all variables that differ.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            added: self.added.iter().map(|(n, _)| n.clone()).collect(),
+            removed: self.removed.iter().map(|(n, _)| n.clone()).collect(),
+            changed: self.changed.iter().map(|c| c.name.clone()).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for EnvDiff {
+    /// One line per added (`+`), removed (`-`) or changed (`~`) variable.
+    /// Changed search paths list their added and removed components.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (name, value) in &self.added {
+            writeln!(f, "+ {}={}", name, value)?;
+        }
+        for (name, value) in &self.removed {
+            writeln!(f, "- {}={}", name, value)?;
+        }
+        for changed in &self.changed {
+            match &changed.components {
+                Some(ComponentDiff { added, removed }) => {
+                    writeln!(f, "~ {}", changed.name)?;
+                    for c in added {
+                        writeln!(f, "    + {}", c)?;
+                    }
+                    for c in removed {
+                        writeln!(f, "    - {}", c)?;
+                    }
+                    if added.is_empty() && removed.is_empty() {
+                        writeln!(f, "    (same components, different order)")?;
+                    }
+                }
+                None => writeln!(f, "~ {}: {} -> {}", changed.name, changed.old, changed.new)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Env {
+        vars.iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn added_removed_changed() {
+        let old = env(&[("CC", "gcc-9"), ("KEPT", "x"), ("GONE", "y")]);
+        let new = env(&[("CC", "gcc-10"), ("KEPT", "x"), ("NEW", "z")]);
+        let d = diff(&old, &new);
+        assert_eq!(d.added, vec![("NEW".to_string(), "z".to_string())]);
+        assert_eq!(d.removed, vec![("GONE".to_string(), "y".to_string())]);
+        assert_eq!(
+            d.changed,
+            vec![Changed {
+                name: "CC".to_string(),
+                old: "gcc-9".to_string(),
+                new: "gcc-10".to_string(),
+                components: None
+            }]
+        );
+        assert_eq!(d.to_string(), "+ NEW=z\n- GONE=y\n~ CC: gcc-9 -> gcc-10\n");
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn search_path_components() {
+        let old = env(&[("PATH", "/a/bin:/b/bin:/c/bin")]);
+        let new = env(&[("PATH", "/a/bin:/d/bin:/c/bin")]);
+        let d = diff(&old, &new);
+        assert_eq!(
+            d.changed[0].components,
+            Some(ComponentDiff {
+                added: vec!["/d/bin".to_string()],
+                removed: vec!["/b/bin".to_string()],
+            })
+        );
+        assert_eq!(d.to_string(), "~ PATH\n    + /d/bin\n    - /b/bin\n");
+        assert_eq!(
+            d.summary(),
+            Summary {
+                added: vec![],
+                removed: vec![],
+                changed: vec!["PATH".to_string()]
+            }
+        );
+    }
+}
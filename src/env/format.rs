@@ -0,0 +1,178 @@
+//! Print an environment in the formats other tools understand.
+
+use super::Env;
+use slog_scope::warn;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The output format of `lorri env`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON object of names to values
+    Json,
+    /// `NAME="value"` lines, as read by most `.env` file loaders
+    Dotenv,
+    /// `set -gx` commands for the fish shell
+    Fish,
+    /// A `load-env` command for nushell
+    Nushell,
+    /// A systemd `EnvironmentFile=`
+    Systemd,
+    /// A file for `docker run --env-file`
+    DockerEnv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "dotenv" => Ok(Format::Dotenv),
+            "fish" => Ok(Format::Fish),
+            "nushell" => Ok(Format::Nushell),
+            "systemd" => Ok(Format::Systemd),
+            "docker-env" => Ok(Format::DockerEnv),
+            _ => Err(format!(
+                "{} not in json,dotenv,fish,nushell,systemd,docker-env",
+                s
+            )),
+        }
+    }
+}
+
+/// Write `env` to `out` in the given format.
+pub fn write<W: Write>(format: Format, env: &Env, mut out: W) -> std::io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut out, env)?;
+            writeln!(out)
+        }
+        Format::Dotenv => {
+            for (name, value) in env {
+                writeln!(
+                    out,
+                    "{}=\"{}\"",
+                    name,
+                    escape(value, &['\\', '"', '$', '`']).replace('\n', "\\n")
+                )?;
+            }
+            Ok(())
+        }
+        Format::Fish => {
+            for (name, value) in env {
+                write!(out, "set -gx {}", name)?;
+                // fish treats variables ending in PATH as lists
+                if name.ends_with("PATH") {
+                    for component in value.split(':') {
+                        write!(out, " '{}'", escape(component, &['\\', '\'']))?;
+                    }
+                } else {
+                    write!(out, " '{}'", escape(value, &['\\', '\'']))?;
+                }
+                writeln!(out, ";")?;
+            }
+            Ok(())
+        }
+        Format::Nushell => {
+            // nushell’s double-quoted strings understand JSON escapes
+            writeln!(out, "load-env {}", serde_json::to_string(env)?)
+        }
+        Format::Systemd => {
+            for (name, value) in env {
+                writeln!(
+                    out,
+                    "{}=\"{}\"",
+                    name,
+                    escape(value, &['\\', '"', '$', '`'])
+                )?;
+            }
+            Ok(())
+        }
+        Format::DockerEnv => {
+            for (name, value) in env {
+                // docker reads values verbatim up to the end of the line
+                if value.contains('\n') {
+                    warn!("skipping multi-line variable, docker env files cannot contain it"; "name" => name);
+                    continue;
+                }
+                writeln!(out, "{}={}", name, value)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Put a backslash before each of the `special` characters.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: Format) -> String {
+        let env = [
+            ("PATH", "/a/bin:/b/bin"),
+            ("QUOTED", "it's \"$x\""),
+            ("HOOK", "echo hi\necho ho"),
+        ]
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect::<Env>();
+        let mut out = vec![];
+        write(format, &env, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            render(Format::Dotenv),
+            r#"HOOK="echo hi\necho ho"
+PATH="/a/bin:/b/bin"
+QUOTED="it's \"\$x\""
+"#
+        );
+        assert_eq!(
+            render(Format::Fish),
+            r#"set -gx HOOK 'echo hi
+echo ho';
+set -gx PATH '/a/bin' '/b/bin';
+set -gx QUOTED 'it\'s "$x"';
+"#
+        );
+        assert_eq!(
+            render(Format::Nushell),
+            r#"load-env {"HOOK":"echo hi\necho ho","PATH":"/a/bin:/b/bin","QUOTED":"it's \"$x\""}
+"#
+        );
+        assert_eq!(
+            render(Format::Systemd),
+            r#"HOOK="echo hi
+echo ho"
+PATH="/a/bin:/b/bin"
+QUOTED="it's \"\$x\""
+"#
+        );
+        assert_eq!(
+            render(Format::DockerEnv),
+            "PATH=/a/bin:/b/bin\nQUOTED=it's \"$x\"\n"
+        );
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let env: Env = serde_json::from_str(&render(Format::Json)).unwrap();
+        assert_eq!(env["HOOK"], "echo hi\necho ho");
+        assert_eq!(env["QUOTED"], "it's \"$x\"");
+    }
+}
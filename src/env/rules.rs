@@ -0,0 +1,344 @@
+//! The rules deciding how the variables of a built environment are loaded
+//! into the user’s environment.
+//!
+//! This is the logic of `ops/direnv/envrc.bash`, which `lorri direnv` hands
+//! to direnv. `lorri env` and friends use this module instead, so both have
+//! to be kept in sync (`matches_envrc_bash` checks that they are).
+
+use super::{Declaration, Env};
+
+/// Variables of the build environment that are never loaded,
+/// because they describe the build sandbox or the user’s session.
+pub const PUNTED: &[&str] = &[
+    // https://github.com/NixOS/nix/blob/92d08c02c84be34ec0df56ed718526c382845d1a/src/nix-build/nix-build.cc#L100
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "DISPLAY",
+    "TERM",
+    "IN_NIX_SHELL",
+    "TZ",
+    "PAGER",
+    "NIX_BUILD_SHELL",
+    "SHLVL",
+    // https://github.com/NixOS/nix/blob/92d08c02c84be34ec0df56ed718526c382845d1a/src/nix-build/nix-build.cc#L385
+    "TEMPDIR",
+    "TMPDIR",
+    "TEMP",
+    "TMP",
+    // https://github.com/NixOS/nix/blob/92d08c02c84be34ec0df56ed718526c382845d1a/src/nix-build/nix-build.cc#L421
+    "NIX_ENFORCE_PURITY",
+    // https://github.com/target/lorri/issues/153
+    "OLDPWD",
+    "PWD",
+    "SHELL",
+    // https://github.com/target/lorri/issues/97
+    "preHook",
+];
+
+/// What happens to a declared variable when the environment is loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    /// The variable is not set at all.
+    Punt,
+    /// The new value is put before the current value.
+    Prepend {
+        /// Put between the new and the current value, if there is a current value
+        separator: String,
+    },
+    /// The new value is put after the current value.
+    Append {
+        /// Put between the current and the new value, if there is a current value
+        separator: String,
+    },
+    /// The value is set on another variable instead.
+    Move {
+        /// Name of the variable that is set
+        to: String,
+    },
+    /// The variable is set to the new value.
+    Export,
+}
+
+/// An instruction of the `varmap-v1` file,
+/// written by the setup hooks in `logged-evaluation.nix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VarmapEntry {
+    /// What to do with the variable
+    pub instruction: Instruction,
+    /// Name of the variable
+    pub variable: String,
+    /// Separator between the values
+    pub separator: String,
+}
+
+/// The instructions a `varmap-v1` file can contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Append the new value to the current one, see `Rule::Append`
+    Append,
+}
+
+/// Parse a `varmap-v1` file, which consists of NUL-terminated triples
+/// of instruction, variable and separator.
+/// Unknown instructions and an incomplete last triple are ignored,
+/// just like `envrc.bash` does.
+pub fn parse_varmap(contents: &str) -> Vec<VarmapEntry> {
+    contents
+        .split('\0')
+        .collect::<Vec<_>>()
+        .chunks_exact(3)
+        .filter_map(|triple| {
+            let instruction = match triple[0] {
+                "append" => Instruction::Append,
+                _ => return None,
+            };
+            Some(VarmapEntry {
+                instruction,
+                variable: triple[1].to_string(),
+                separator: triple[2].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The rule for the variable `name`.
+pub fn rule(name: &str, varmap: &[VarmapEntry]) -> Rule {
+    if PUNTED.contains(&name) {
+        return Rule::Punt;
+    }
+    match name {
+        "PATH" => Rule::Prepend {
+            separator: ":".to_string(),
+        },
+        "origPreHook" => Rule::Move {
+            to: "preHook".to_string(),
+        },
+        _ => match varmap.iter().find(|entry| entry.variable == name) {
+            Some(VarmapEntry {
+                instruction: Instruction::Append,
+                separator,
+                ..
+            }) => Rule::Append {
+                separator: separator.clone(),
+            },
+            None => Rule::Export,
+        },
+    }
+}
+
+/// The variables that loading `declarations` into `base` sets,
+/// with their new values.
+pub fn apply(declarations: &[Declaration], varmap: &[VarmapEntry], base: &Env) -> Env {
+    let mut env = Env::new();
+    env.insert("IN_NIX_SHELL".to_string(), "impure".to_string());
+    for Declaration { name, value } in declarations {
+        let value = match value {
+            Some(value) => value.clone(),
+            // only marks an existing variable as exported
+            None => continue,
+        };
+        let current = env
+            .get(name)
+            .or_else(|| base.get(name))
+            .cloned()
+            .unwrap_or_default();
+        match rule(name, varmap) {
+            Rule::Punt => {}
+            Rule::Prepend { separator } => {
+                let value = if current.is_empty() {
+                    value
+                } else {
+                    format!("{}{}{}", value, separator, current)
+                };
+                env.insert(name.clone(), value);
+            }
+            Rule::Append { separator } => {
+                let value = if current.is_empty() {
+                    value
+                } else {
+                    format!("{}{}{}", current, separator, value)
+                };
+                env.insert(name.clone(), value);
+            }
+            Rule::Move { to } => {
+                env.insert(to, value);
+            }
+            Rule::Export => {
+                env.insert(name.clone(), value);
+            }
+        }
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::{prop_assert, prop_assert_eq, prop_assume, proptest};
+
+    fn declare(name: &str, value: &str) -> Declaration {
+        Declaration {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+        }
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Env {
+        vars.iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn append(variable: &str, separator: &str) -> VarmapEntry {
+        VarmapEntry {
+            instruction: Instruction::Append,
+            variable: variable.to_string(),
+            separator: separator.to_string(),
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let varmap = vec![append("XDG_DATA_DIRS", ":")];
+        assert_eq!(rule("HOME", &varmap), Rule::Punt);
+        assert_eq!(rule("preHook", &varmap), Rule::Punt);
+        assert_eq!(
+            rule("PATH", &varmap),
+            Rule::Prepend {
+                separator: ":".to_string()
+            }
+        );
+        assert_eq!(
+            rule("XDG_DATA_DIRS", &varmap),
+            Rule::Append {
+                separator: ":".to_string()
+            }
+        );
+        assert_eq!(
+            rule("origPreHook", &varmap),
+            Rule::Move {
+                to: "preHook".to_string()
+            }
+        );
+        assert_eq!(rule("CC", &varmap), Rule::Export);
+    }
+
+    #[test]
+    fn varmap_parsing() {
+        assert_eq!(
+            parse_varmap("append\0XDG_DATA_DIRS\0:\0unknown\0FOO\0;\0append\0A\0 \0append\0B"),
+            vec![append("XDG_DATA_DIRS", ":"), append("A", " ")]
+        );
+        assert_eq!(parse_varmap(""), vec![]);
+    }
+
+    /// Load `bash-export` and `varmap-v1` with `envrc.bash` in bash,
+    /// and check that the same variables are set.
+    #[test]
+    fn matches_envrc_bash() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        let bash_export = r#"declare -x HOME="/homeless-shelter"
+declare -x PATH="/nix/store/foo/bin"
+declare -x XDG_DATA_DIRS="/nix/store/foo/share"
+declare -x NEW_DIRS="/nix/store/bar"
+declare -x origPreHook="hook"
+declare -x CC="gcc"
+declare -x QUOTED="a \"b\" \$c"
+declare -x NOVALUE
+"#;
+        let varmap = "append\0XDG_DATA_DIRS\0:\0append\0NEW_DIRS\0:\0";
+        std::fs::write(root.path().join("bash-export"), bash_export)?;
+        std::fs::write(root.path().join("varmap-v1"), varmap)?;
+        let base = env(&[
+            ("HOME", "/home/user"),
+            ("PATH", "/usr/bin:/bin"),
+            ("XDG_DATA_DIRS", "/usr/share"),
+            ("CC", "clang"),
+        ]);
+
+        let script = format!("{}\nexport", include_str!("../ops/direnv/envrc.bash"));
+        let out = std::process::Command::new("bash")
+            .args(["-c", script.as_str()])
+            .env_clear()
+            .envs(&base)
+            .env("EVALUATION_ROOT", root.path())
+            .output()
+            .expect("bash must be available");
+        assert!(out.status.success(), "{:?}", out);
+        let from_bash = crate::env::parse_bash_export(&String::from_utf8_lossy(&out.stdout))
+            .unwrap()
+            .into_iter()
+            .filter_map(|Declaration { name, value }| value.map(|v| (name, v)))
+            .filter(|(name, value)| base.get(name) != Some(value))
+            // set by bash itself
+            .filter(|(name, _)| {
+                !["EVALUATION_ROOT", "OLDPWD", "PWD", "SHLVL", "_"].contains(&name.as_str())
+            })
+            .collect::<Env>();
+
+        let declarations = crate::env::parse_bash_export(bash_export).unwrap();
+        let loaded = apply(&declarations, &parse_varmap(varmap), &base);
+        assert_eq!(loaded, from_bash);
+        assert_eq!(loaded["PATH"], "/nix/store/foo/bin:/usr/bin:/bin");
+        assert_eq!(loaded["XDG_DATA_DIRS"], "/usr/share:/nix/store/foo/share");
+        assert_eq!(loaded["NEW_DIRS"], "/nix/store/bar");
+        assert_eq!(loaded["preHook"], "hook");
+        Ok(())
+    }
+
+    proptest! {
+        /// Punted variables are never set, whatever their value.
+        #[test]
+        fn punted_are_never_set(index in 0..PUNTED.len(), value in ".*") {
+            let loaded = apply(&[declare(PUNTED[index], &value)], &[], &Env::new());
+            prop_assert_eq!(loaded, env(&[("IN_NIX_SHELL", "impure")]));
+        }
+
+        /// `PATH` keeps the current components after the new ones.
+        #[test]
+        fn path_is_prepended(new in "[^:]*", current in "[^:]*") {
+            let loaded = apply(&[declare("PATH", &new)], &[], &env(&[("PATH", &current)]));
+            if current.is_empty() {
+                prop_assert_eq!(&loaded["PATH"], &new);
+            } else {
+                prop_assert_eq!(&loaded["PATH"], &format!("{}:{}", new, current));
+            }
+        }
+
+        /// Variables from the varmap keep their current value in front.
+        #[test]
+        fn varmap_appends(new in ".*", current in ".*", separator in "[:; ]") {
+            let varmap = vec![append("DIRS", &separator)];
+            let loaded = apply(&[declare("DIRS", &new)], &varmap, &env(&[("DIRS", &current)]));
+            prop_assert!(loaded["DIRS"].starts_with(&current));
+            prop_assert!(loaded["DIRS"].ends_with(&new));
+            if !current.is_empty() {
+                prop_assert_eq!(loaded["DIRS"].len(), current.len() + separator.len() + new.len());
+            }
+        }
+
+        /// Other variables are exported as they are.
+        #[test]
+        fn others_are_exported(name in "[a-z][a-zA-Z0-9_]*", new in ".*", current in ".*") {
+            prop_assume!(rule(&name, &[]) == Rule::Export);
+            let loaded = apply(&[declare(&name, &new)], &[], &env(&[(&name, &current)]));
+            prop_assert_eq!(&loaded[&name], &new);
+        }
+
+        /// Writing entries in the `varmap-v1` format and parsing them again
+        /// gives the same entries.
+        #[test]
+        fn varmap_roundtrip(entries in proptest::collection::vec(("[A-Z_]+", "[^\0]*"), 0..5)) {
+            let entries = entries
+                .iter()
+                .map(|(variable, separator)| append(variable, separator))
+                .collect::<Vec<_>>();
+            let contents = entries
+                .iter()
+                .map(|e| format!("append\0{}\0{}\0", e.variable, e.separator))
+                .collect::<String>();
+            prop_assert_eq!(parse_varmap(&contents), entries);
+        }
+    }
+}
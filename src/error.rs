@@ -120,6 +120,19 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn summary_is_the_nix_error() {
+        let mut err = build_exit();
+        assert_eq!(err.summary(), "Nix process returned exit code 1");
+        if let BuildError::Exit { logs, .. } = &mut err {
+            logs.push(OsString::from("error: undefined variable 'foo' at /p/shell.nix:1:1").into());
+        }
+        assert_eq!(
+            err.summary(),
+            "error: undefined variable 'foo' at /p/shell.nix:1:1"
+        );
+    }
+
     #[test]
     fn logline_json_roundtrip() -> Result<(), serde_json::Error> {
         serde_json::from_str::<serde_json::Value>(&serde_json::to_string(&build_exit())?)
@@ -210,6 +223,25 @@ impl BuildError {
         BuildError::Output { msg }
     }
 
+    /// A one-line description of the error, for places where the
+    /// full error (e.g. all nix logs) would be too much.
+    pub fn summary(&self) -> String {
+        match self {
+            BuildError::Exit { status, logs, .. } => logs
+                .iter()
+                .map(|l| l.0.to_string_lossy())
+                .find(|l| l.trim_start().starts_with("error:"))
+                .map(|l| l.trim().to_string())
+                .unwrap_or_else(|| {
+                    format!(
+                        "Nix process returned exit code {}",
+                        status.map_or("<unknown>".to_string(), |c| i32::to_string(&c))
+                    )
+                }),
+            other => other.to_string().lines().next().unwrap_or("").to_string(),
+        }
+    }
+
     /// Is there something the user can do about this error?
     pub fn is_actionable(&self) -> bool {
         match self {
@@ -0,0 +1,198 @@
+//! Find the files in a local git repository that `builtins.fetchGit` depends on.
+//!
+//! With a `rev` or `ref`, `fetchGit` on a local repository only depends on
+//! the state of the repository’s refs, not on every file in its working tree.
+//! Without, it copies the tracked files of the working tree, uncommitted
+//! changes included, so the directories containing them are watched as well.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Locate the git directory of the repository at `repo`.
+///
+/// Handles normal checkouts (`repo/.git` is a directory),
+/// worktrees and submodules (`repo/.git` is a file containing a
+/// `gitdir: <path>` line) and bare repositories (`repo` itself
+/// contains a `HEAD` file).
+///
+/// `None` if `repo` does not look like a git repository.
+pub fn git_dir(repo: &Path) -> Option<PathBuf> {
+    let dot_git = repo.join(".git");
+    if dot_git.is_dir() {
+        Some(dot_git)
+    } else if dot_git.is_file() {
+        let contents = std::fs::read_to_string(&dot_git).ok()?;
+        let gitdir =
+            contents.lines().find(|line| line.starts_with("gitdir:"))?["gitdir:".len()..].trim();
+        // relative gitdirs are relative to the repository
+        Some(repo.join(gitdir))
+    } else if repo.join("HEAD").is_file() && repo.join("refs").is_dir() {
+        Some(repo.to_owned())
+    } else {
+        None
+    }
+}
+
+/// The paths in the repository at `repo` which change when
+/// `HEAD` or any local branch is updated.
+///
+/// These are `HEAD`, `packed-refs` and the `refs/heads` directory
+/// (which should be watched recursively, branch names can contain `/`).
+/// Worktrees keep their `HEAD` in the worktree-specific git directory,
+/// but share the refs of the main repository via `commondir`.
+///
+/// `None` if `repo` does not look like a git repository.
+pub fn ref_paths(repo: &Path) -> Option<Vec<PathBuf>> {
+    let git_dir = git_dir(repo)?;
+    let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim()),
+        Err(_) => git_dir.clone(),
+    };
+
+    let mut paths = vec![git_dir.join("HEAD")];
+    for p in &[
+        common_dir.join("packed-refs"),
+        common_dir.join("refs/heads"),
+    ] {
+        if p.exists() {
+            paths.push(p.clone())
+        }
+    }
+    Some(paths)
+}
+
+/// The paths in a repository which `fetchGit` of its working tree depends on.
+#[derive(Debug, PartialEq)]
+pub struct WorkingTreePaths {
+    /// The refs (see `ref_paths`) and the index,
+    /// which changes when files are added or removed
+    pub refs: Vec<PathBuf>,
+    /// The directories containing tracked files. Watching their entries
+    /// (but not their subdirectories) covers changes of the tracked files
+    /// with one watch per directory instead of one per file.
+    pub directories: Vec<PathBuf>,
+}
+
+/// The paths in the repository at `repo` which `fetchGit` of its
+/// working tree depends on, see `WorkingTreePaths`.
+///
+/// `None` if `repo` does not look like a git repository or `git` fails.
+pub fn working_tree_paths(repo: &Path) -> Option<WorkingTreePaths> {
+    let mut refs = ref_paths(repo)?;
+    refs.push(git_dir(repo)?.join("index"));
+    let output = Command::new("git")
+        .args(["ls-files", "-z"])
+        .current_dir(repo)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let directories = output
+        .stdout
+        .split(|b| *b == 0)
+        .filter(|file| !file.is_empty())
+        .filter_map(|file| {
+            repo.join(OsStr::from_bytes(file))
+                .parent()
+                .map(Path::to_owned)
+        })
+        .collect::<BTreeSet<_>>();
+    Some(WorkingTreePaths {
+        refs,
+        directories: directories.into_iter().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_repo(git_dir: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(git_dir.join("refs/heads/feature"))?;
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/master\n")?;
+        std::fs::write(git_dir.join("refs/heads/master"), "")?;
+        std::fs::write(git_dir.join("refs/heads/feature/x"), "")?;
+        Ok(())
+    }
+
+    #[test]
+    fn checkout_refs() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path();
+        fake_repo(&repo.join(".git"))?;
+
+        assert_eq!(
+            ref_paths(repo),
+            Some(vec![repo.join(".git/HEAD"), repo.join(".git/refs/heads")])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn worktree_refs() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let main = tmp.path().join("main");
+        fake_repo(&main.join(".git"))?;
+        std::fs::write(main.join(".git/packed-refs"), "")?;
+
+        let worktree = tmp.path().join("worktree");
+        let worktree_git_dir = main.join(".git/worktrees/worktree");
+        std::fs::create_dir_all(&worktree_git_dir)?;
+        std::fs::write(worktree_git_dir.join("HEAD"), "ref: refs/heads/feature/x\n")?;
+        std::fs::write(worktree_git_dir.join("commondir"), "../..\n")?;
+        std::fs::create_dir_all(&worktree)?;
+        std::fs::write(
+            worktree.join(".git"),
+            format!("gitdir: {}\n", worktree_git_dir.display()),
+        )?;
+
+        assert_eq!(
+            ref_paths(&worktree),
+            Some(vec![
+                worktree_git_dir.join("HEAD"),
+                worktree_git_dir.join("../../packed-refs"),
+                worktree_git_dir.join("../../refs/heads"),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn not_a_repository() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        assert_eq!(ref_paths(tmp.path()), None);
+        assert_eq!(working_tree_paths(tmp.path()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn working_tree_tracked_directories() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path();
+        std::fs::create_dir_all(repo.join("dir/sub"))?;
+        std::fs::create_dir(repo.join("untracked_dir"))?;
+        for i in 0..100 {
+            std::fs::write(repo.join(format!("dir/tracked{}", i)), "")?;
+            std::fs::write(repo.join(format!("dir/sub/tracked{}", i)), "")?;
+        }
+        std::fs::write(repo.join("top"), "")?;
+        std::fs::write(repo.join("untracked_dir/untracked"), "")?;
+        crate::bash::expect_bash(
+            r#"cd "$1" && git init -q && git add dir top"#,
+            [repo.as_os_str()],
+        );
+        let paths = working_tree_paths(repo).expect("a git repository");
+        assert!(paths.refs.contains(&repo.join(".git/HEAD")));
+        assert!(paths.refs.contains(&repo.join(".git/index")));
+        // one path per directory, not per tracked file
+        assert_eq!(
+            paths.directories,
+            vec![repo.to_owned(), repo.join("dir"), repo.join("dir/sub")]
+        );
+        Ok(())
+    }
+}
@@ -15,9 +15,12 @@ pub mod builder;
 pub mod cas;
 pub mod changelog;
 pub mod cli;
+pub mod config;
 pub mod constants;
 pub mod daemon;
+pub mod env;
 pub mod error;
+pub mod git;
 pub mod locate_file;
 pub mod logging;
 pub mod nix;
@@ -59,7 +62,7 @@ use std::path::{Path, PathBuf};
 include!(concat!(env!("OUT_DIR"), "/build_rev.rs"));
 
 /// A .nix file.
-#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize)]
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct NixFile(PathBuf);
 
@@ -5,14 +5,31 @@ let
   # Taken from https://github.com/NixOS/nixpkgs/blob/master/lib/strings.nix
   escapeShellArg = arg: "'${builtins.replaceStrings [ "'" ] [ "'\\''" ] (toString arg)}'";
 
+  # The url argument of the fetchers is either given directly
+  # or as the `url` attribute of an attrset.
+  fetcherUrl = args: toString (if builtins.isAttrs args then args.url else args);
+
   # using scopedImport, replace readDir and readFile with
   # implementations which will log files and paths they see.
+  # The fetchers log their urls, so that we can watch local
+  # git repositories and local tarballs.
+  # Without a `rev` or `ref`, fetchGit copies the working tree of a local
+  # repository (including uncommitted changes to tracked files).
+  fetchesWorkingTree = args: !(builtins.isAttrs args && (args ? rev || args ? ref));
+  fetchGit = args: builtins.trace
+    "lorri fetchGit${if fetchesWorkingTree args then " working tree" else ""}: '${fetcherUrl args}'"
+    (builtins.fetchGit args);
+  fetchTarball = args: builtins.trace "lorri fetch: '${fetcherUrl args}'" (builtins.fetchTarball args);
+  fetchurl = args: builtins.trace "lorri fetch: '${fetcherUrl args}'" (builtins.fetchurl args);
+
   overrides = {
     import = scopedImport overrides;
     scopedImport = x: builtins.scopedImport (overrides // x);
+    inherit fetchGit fetchTarball;
     builtins = builtins // {
       readFile = file: builtins.trace "lorri read: '${toString file}'" (builtins.readFile file);
       readDir = path: builtins.trace "lorri read: '${toString path}'" (builtins.readDir path);
+      inherit fetchGit fetchTarball fetchurl;
     };
   };
 
@@ -1,10 +1,11 @@
-use lorri::cli::{Arguments, Command, Internal_};
+use lorri::cli::{Arguments, Command, DaemonCommand, EnvCommand, Internal_};
 use lorri::constants;
 use lorri::locate_file;
 use lorri::logging;
 use lorri::ops::error::{ExitError, OpResult};
 use lorri::ops::{
-    daemon, direnv, info, init, ping, shell, start_user_shell, stream_events, upgrade, watch,
+    daemon, direnv, doctor, env as env_op, exec, explain_rebuild, explain_watches, generations,
+    info, init, ping, rollback, shell, start_user_shell, stream_events, upgrade, watch,
 };
 use lorri::project::Project;
 use lorri::NixFile;
@@ -77,8 +78,13 @@ fn get_shell_nix(shellfile: &PathBuf) -> Result<NixFile, ExitError> {
 }
 
 fn create_project(paths: &constants::Paths, shell_nix: NixFile) -> Result<Project, ExitError> {
-    Project::new(shell_nix, &paths.gc_root_dir(), paths.cas_store().clone())
-        .map_err(|e| ExitError::temporary(format!("Could not set up project paths: {:#?}", e)))
+    Project::new(
+        shell_nix,
+        &paths.gc_root_dir(),
+        paths.cas_store().clone(),
+        lorri::ops::get_config(paths)?,
+    )
+    .map_err(|e| ExitError::temporary(format!("Could not set up project paths: {:#?}", e)))
 }
 
 /// Run the main function of the relevant command.
@@ -107,16 +113,44 @@ fn run_command(log: slog::Logger, opts: Arguments) -> OpResult {
             let (project, _guard) = with_project(&opts.nix_file)?;
             shell::main(project, opts)
         }
+        Command::Exec(opts) => {
+            let (project, _guard) = with_project(&opts.nix_file)?;
+            exec::main(project, opts)
+        }
 
         Command::Watch(opts) => {
             let (project, _guard) = with_project(&opts.nix_file)?;
             watch::main(project, opts)
         }
-        Command::Daemon(opts) => {
-            install_signal_handler();
-            let _guard = without_project();
-            daemon::main(opts)
+        Command::Generations(opts) => {
+            let (project, _guard) = with_project(&opts.nix_file)?;
+            generations::main(project)
+        }
+        Command::Rollback(opts) => {
+            let (project, _guard) = with_project(&opts.nix_file)?;
+            rollback::main(project, opts)
         }
+        Command::Env(opts) => match opts.command {
+            Some(EnvCommand::Diff(diff_opts)) => {
+                let (project, _guard) = with_project(&diff_opts.nix_file)?;
+                env_op::diff_main(project, diff_opts)
+            }
+            None => {
+                let (project, _guard) = with_project(&opts.nix_file)?;
+                env_op::main(project, opts.format)
+            }
+        },
+        Command::Daemon(opts) => match opts.command {
+            Some(DaemonCommand::Stop(stop_opts)) => {
+                let _guard = without_project();
+                daemon::stop(stop_opts)
+            }
+            None => {
+                install_signal_handler();
+                let _guard = without_project();
+                daemon::main(opts)
+            }
+        },
         Command::Upgrade(opts) => {
             let _guard = without_project();
             upgrade::main(opts, paths.cas_store())
@@ -125,6 +159,10 @@ fn run_command(log: slog::Logger, opts: Arguments) -> OpResult {
             let _guard = without_project();
             init::main(TRIVIAL_SHELL_SRC, DEFAULT_ENVRC)
         }
+        Command::Doctor(opts) => {
+            let _guard = without_project();
+            doctor::main(&paths, opts)
+        }
 
         Command::Internal { command } => match command {
             Internal_::Ping_(opts) => {
@@ -137,7 +175,15 @@ fn run_command(log: slog::Logger, opts: Arguments) -> OpResult {
             }
             Internal_::StreamEvents_(se) => {
                 let _guard = without_project();
-                stream_events::main(se.kind)
+                stream_events::main(se)
+            }
+            Internal_::ExplainWatches_(opts) => {
+                let (project, _guard) = with_project(&opts.nix_file)?;
+                explain_watches::main(project)
+            }
+            Internal_::ExplainRebuild_(opts) => {
+                let (project, _guard) = with_project(&opts.nix_file)?;
+                explain_rebuild::main(project, opts)
             }
         },
     }
@@ -389,7 +389,9 @@ impl<'a> CallOpts<'a> {
             thread::spawn(move || stdout_fn(std::io::BufReader::new(stdout_handle)));
 
         // 3. wait on the process
+        let running = track(&nix_proc);
         let nix_proc_result = nix_proc.wait()?;
+        drop(running);
 
         // 4. join the stderr handler
         stderr_thread
@@ -453,6 +455,98 @@ impl<'a> CallOpts<'a> {
     }
 }
 
+/// Run `nix-store --query` with the query `args` on `paths`
+/// and return the lines it prints.
+pub fn query_store<P: AsRef<OsStr>>(args: &[&str], paths: &[P]) -> Result<Vec<String>, BuildError> {
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut cmd = Command::new("nix-store");
+    cmd.arg("--query")
+        .args(args)
+        .arg("--")
+        .args(paths)
+        .stdin(Stdio::null());
+
+    debug!("nix-store"; "command" => ?cmd);
+
+    let output = cmd.output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
+        _ => BuildError::io(e),
+    })?;
+    if !output.status.success() {
+        return Err(BuildError::exit(
+            &cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .map(OsString::from)
+                .collect(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+lazy_static::lazy_static! {
+    /// The nix processes lorri is waiting for, see `track`.
+    static ref RUNNING: std::sync::Mutex<Running> = std::sync::Mutex::new(Running {
+        pids: std::collections::HashSet::new(),
+        stopping: false,
+    });
+}
+
+struct Running {
+    pids: std::collections::HashSet<u32>,
+    /// Set by `stop_running`, after which new processes are stopped right away.
+    stopping: bool,
+}
+
+/// Keeps a nix process in the set of running processes until it is dropped.
+pub struct RunningProcess(u32);
+
+impl Drop for RunningProcess {
+    fn drop(&mut self) {
+        RUNNING
+            .lock()
+            .expect("running nix processes poisoned")
+            .pids
+            .remove(&self.0);
+    }
+}
+
+/// Remember `child` as running until the returned value is dropped,
+/// so `stop_running` can stop it.
+pub fn track(child: &std::process::Child) -> RunningProcess {
+    let mut running = RUNNING.lock().expect("running nix processes poisoned");
+    if running.stopping {
+        terminate(child.id());
+    }
+    running.pids.insert(child.id());
+    RunningProcess(child.id())
+}
+
+/// Stop all running nix processes, and all processes started from now on.
+/// Their builds fail, which is how the daemon cancels builds when it shuts down.
+pub fn stop_running() {
+    let mut running = RUNNING.lock().expect("running nix processes poisoned");
+    running.stopping = true;
+    for pid in running.pids.iter() {
+        terminate(*pid);
+    }
+}
+
+fn terminate(pid: u32) {
+    debug!("stopping nix process"; "pid" => pid);
+    // nix cleans up after itself on SIGTERM; the process may already have exited
+    let _ = ::nix::sys::signal::kill(
+        ::nix::unistd::Pid::from_raw(pid as i32),
+        ::nix::sys::signal::Signal::SIGTERM,
+    );
+}
+
 /// Possible error conditions encountered when executing Nix evaluation commands.
 #[derive(Debug)]
 pub enum EvaluationError {
@@ -1,14 +1,34 @@
 //! Run a BuildLoop for `shell.nix`, watching for input file changes.
 //! Can be used together with `direnv`.
 
-use crate::daemon::Daemon;
+use crate::daemon::{tcp, Daemon};
 use crate::nix::options::NixOptions;
-use crate::ops::error::{ok, OpResult};
-use crate::socket::SocketPath;
-use slog_scope::info;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::socket::{LockHolder, SocketPath};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use slog_scope::{info, warn};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a stopped daemon to release its socket.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// See the documentation for lorri::cli::Command::Daemon for details.
 pub fn main(opts: crate::cli::DaemonOptions) -> OpResult {
+    let paths = crate::ops::get_paths()?;
+    if opts.system {
+        return crate::daemon::system::serve(
+            SocketPath::from(paths.system_daemon_socket_file()),
+            opts.extra_nix_options.as_ref(),
+        );
+    }
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    if opts.replace {
+        // the new daemon builds the projects again
+        stop_running(&socket_path, true)?;
+    }
+
     let extra_nix_options = match opts.extra_nix_options {
         None => NixOptions::empty(),
         Some(v) => NixOptions {
@@ -17,7 +37,21 @@ pub fn main(opts: crate::cli::DaemonOptions) -> OpResult {
         },
     };
 
+    let config = crate::ops::get_config(&paths)?;
     let (mut daemon, build_rx) = Daemon::new(extra_nix_options);
+    if let Some(address) = &config.daemon.listen_tcp {
+        let token = tcp::token(paths.daemon_token_file()).map_err(|e| {
+            ExitError::temporary(format!(
+                "cannot read or create the daemon token {}: {}",
+                paths.daemon_token_file().display(),
+                e
+            ))
+        })?;
+        daemon.listen_tcp(tcp::Endpoint {
+            address: address.clone(),
+            token,
+        });
+    }
     let build_handle = std::thread::spawn(|| {
         for msg in build_rx {
             info!("build status"; "message" => ?msg);
@@ -25,14 +59,133 @@ pub fn main(opts: crate::cli::DaemonOptions) -> OpResult {
     });
     info!("ready");
 
-    let paths = crate::ops::get_paths()?;
     daemon.serve(
-        SocketPath::from(paths.daemon_socket_file()),
+        socket_path,
         paths.gc_root_dir().to_path_buf(),
         paths.cas_store().clone(),
+        config,
+        opts.exit_when_idle
+            .map(|minutes| Duration::from_secs(minutes * 60)),
+        paths.active_projects_file().to_path_buf(),
     )?;
     build_handle
         .join()
         .expect("failed to join build status thread");
     ok()
 }
+
+/// See the documentation for lorri::cli::DaemonCommand::Stop for details.
+pub fn stop(opts: crate::cli::DaemonStopOptions) -> OpResult {
+    let paths = crate::ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    if stop_running(&socket_path, opts.cancel_builds)? {
+        eprintln!("lorri: the daemon has stopped");
+    } else {
+        eprintln!("lorri: no daemon is running");
+    }
+    ok()
+}
+
+/// Stop the daemon listening on `socket`, if there is one,
+/// and wait until it has released the socket.
+/// Returns whether a daemon was running.
+fn stop_running(socket: &SocketPath, cancel_builds: bool) -> Result<bool, ExitError> {
+    let holder = socket.lock_holder()?;
+    if let LockHolder::Nobody = holder {
+        return Ok(false);
+    }
+    if !ask_to_shut_down(socket, cancel_builds) {
+        // daemons of older lorri versions can only be stopped with a signal
+        terminate(socket, holder)?;
+    }
+    let start = Instant::now();
+    while start.elapsed() < STOP_TIMEOUT {
+        if let LockHolder::Nobody = socket.lock_holder()? {
+            return Ok(true);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(ExitError::temporary(format!(
+        "the running daemon did not stop within {} seconds",
+        STOP_TIMEOUT.as_secs()
+    )))
+}
+
+/// Call `Shutdown` on the daemon at `socket`, which returns once the daemon
+/// has stopped building. `false` if the daemon does not understand the call.
+fn ask_to_shut_down(socket: &SocketPath, cancel_builds: bool) -> bool {
+    use crate::daemon::client::{handshake, Compatibility};
+    use crate::internal_proto::VarlinkClientInterface;
+
+    let connection = match varlink::Connection::with_address(&socket.address()) {
+        Ok(connection) => connection,
+        Err(_) => return false,
+    };
+    match handshake(connection.clone()) {
+        Compatibility::Same | Compatibility::OtherVersion { .. } => {}
+        Compatibility::Unknown | Compatibility::Incompatible { .. } => return false,
+    }
+    if cancel_builds {
+        info!("stopping the running daemon, cancelling its builds");
+    } else {
+        info!("stopping the running daemon once its builds have finished");
+    }
+    match crate::internal_proto::VarlinkClient::new(connection)
+        .shutdown(cancel_builds)
+        .call()
+    {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("the daemon did not shut down, sending it a signal"; "error" => ?e);
+            false
+        }
+    }
+}
+
+/// Send SIGTERM to the daemon holding the lock of `socket`.
+fn terminate(socket: &SocketPath, holder: LockHolder) -> OpResult {
+    let pid = match holder {
+        LockHolder::Nobody => return ok(),
+        LockHolder::Lorri { pid: Some(pid), .. } => Pid::from_raw(pid),
+        // daemons of older lorri versions don’t record their pid
+        LockHolder::Lorri { pid: None, .. } => listener_pid(socket)?,
+    };
+    info!("stopping the running daemon"; "pid" => pid.as_raw());
+    signal::kill(pid, Signal::SIGTERM).map_err(|e| {
+        ExitError::temporary(format!(
+            "cannot stop the running daemon (pid {}): {}",
+            pid, e
+        ))
+    })?;
+    ok()
+}
+
+/// The lorri process listening on `socket`.
+fn listener_pid(socket: &SocketPath) -> Result<Pid, ExitError> {
+    let stream = std::os::unix::net::UnixStream::connect(socket.path()).map_err(|e| {
+        ExitError::temporary(format!(
+            "cannot connect to the running daemon at {}: {}",
+            socket.path().display(),
+            e
+        ))
+    })?;
+    let pid = nix::sys::socket::getsockopt(
+        stream.as_raw_fd(),
+        nix::sys::socket::sockopt::PeerCredentials,
+    )
+    .map_err(|e| ExitError::temporary(format!("cannot find the running daemon: {}", e)))?
+    .pid();
+    // With socket activation, the socket belongs to the service manager,
+    // which must not be stopped.
+    let name = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+    if name.trim() == "lorri" {
+        Ok(Pid::from_raw(pid))
+    } else {
+        Err(ExitError::temporary(format!(
+            "the daemon socket {} belongs to `{}` (pid {}), not to a lorri daemon; stop it manually",
+            socket.path().display(),
+            name.trim(),
+            pid
+        )))
+    }
+}
@@ -1,6 +1,8 @@
 #!/usr/bin/env bash
 # ^ shebang is unused as this file is sourced, but present for editor
 # integration. Note: Direnv guarantees it *will* be parsed using bash.
+#
+# The same rules are implemented in src/env/rules.rs, keep them in sync.
 
 function punt () {
     :
@@ -171,3 +173,9 @@ elif [ -f "$EVALUATION_ROOT" ]; then
 fi
 
 unset declare
+
+if [ -n "${LORRI_STALE_MESSAGE:-}" ]; then
+    # The last build failed, so this environment comes from an older build.
+    export LORRI_ENV_STALE=1
+    echo "lorri: $LORRI_STALE_MESSAGE" >&2
+fi
@@ -5,10 +5,11 @@ mod version;
 use self::version::{DirenvVersion, MIN_DIRENV_VERSION};
 use crate::internal_proto;
 use crate::ops::error::{ok, ExitError, OpResult};
-use crate::project::roots::Roots;
+use crate::project::roots::{RootStatus, Roots};
 use crate::project::Project;
 use slog_scope::{info, warn};
 use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 /// See the documentation for lorri::cli::Command::Direnv for more
@@ -16,16 +17,24 @@ use std::process::Command;
 pub fn main<W: std::io::Write>(project: Project, mut shell_output: W) -> OpResult {
     check_direnv_version()?;
 
-    let root_paths = Roots::from_project(&project).paths();
+    let roots = Roots::from_project(&project);
+    let root_paths = roots.paths();
     let paths_are_cached: bool = root_paths.all_exist();
+    let stale_message = match roots.status() {
+        Ok(status) => stale_message(&status),
+        Err(e) => {
+            warn!("could not read the build status"; "error" => ?e);
+            None
+        }
+    };
     let address = crate::ops::get_paths()?.daemon_socket_address();
     let shell_nix =
         internal_proto::ShellNix::try_from(&project.nix_file).map_err(ExitError::temporary)?;
 
-    let ping_sent = if let Ok(connection) = varlink::Connection::with_address(&address) {
+    let ping_sent = if let Some(connection) = crate::daemon::client::connect(&address) {
         use internal_proto::VarlinkClientInterface;
         internal_proto::VarlinkClient::new(connection)
-            .watch_shell(shell_nix)
+            .watch_shell(shell_nix, None)
             .call()
             .is_ok()
     } else {
@@ -66,20 +75,26 @@ pub fn main<W: std::io::Write>(project: Project, mut shell_output: W) -> OpResul
     // In production code, `shell_output` will be stdout so direnv can interpret the output.
     // `shell_output` is an argument so that testing code can inject a different `std::io::Write`
     // in order to inspect the output.
+    let watches = watched_files(&roots, crate::ops::get_paths()?.client_socket_file())
+        .iter()
+        .map(|file| {
+            format!(
+                "watch_file {}\n",
+                shell_quote(file.to_str().expect("watched path not UTF-8 clean!"))
+            )
+        })
+        .collect::<String>();
     writeln!(
         shell_output,
         r#"
 EVALUATION_ROOT="{}"
+LORRI_STALE_MESSAGE={}
 
-watch_file "{}"
-watch_file "$EVALUATION_ROOT"
-
+{}
 {}"#,
         root_paths.shell_gc_root,
-        crate::ops::get_paths()?
-            .daemon_socket_file()
-            .to_str()
-            .expect("Socket path is not UTF-8 clean!"),
+        shell_quote(&stale_message.unwrap_or_default()),
+        watches,
         include_str!("envrc.bash")
     )
     .expect("failed to write shell output");
@@ -87,8 +102,39 @@ watch_file "$EVALUATION_ROOT"
     ok()
 }
 
+/// The files direnv reloads the environment on: the daemon socket, so a fresh
+/// ping is sent when the daemon starts, the environment of the latest build
+/// and the build status, which changes when a rebuild fails.
+fn watched_files(roots: &Roots, socket: &Path) -> Vec<PathBuf> {
+    vec![
+        socket.to_path_buf(),
+        roots.paths().shell_gc_root.0,
+        roots.status_file(),
+    ]
+}
+
+/// The warning to show if the environment comes from an older build,
+/// because the latest build failed.
+fn stale_message(status: &RootStatus) -> Option<String> {
+    match status {
+        RootStatus {
+            built: Some(_),
+            failed: Some(failed),
+        } => Some(format!(
+            "the last build failed, loading the environment of the previous build: {}",
+            failed.summary
+        )),
+        _ => None,
+    }
+}
+
+/// Quote `s` as a single bash word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
 /// Checks `direnv version` against the minimal version lorri requires.
-fn check_direnv_version() -> OpResult {
+pub(crate) fn check_direnv_version() -> OpResult {
     let out = with_command("direnv", |mut cmd| cmd.arg("version").output())?;
     let version = std::str::from_utf8(&out.stdout)
         .map_err(|_| ())
@@ -123,3 +169,72 @@ where
         })
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bash::expect_bash;
+    use crate::cas::ContentAddressable;
+    use crate::config::Config;
+    use crate::error::BuildError;
+    use crate::project::roots::BuildRecord;
+    use crate::NixFile;
+
+    #[test]
+    fn stale_only_after_failure() {
+        let record = |summary: &str| BuildRecord {
+            time: 0,
+            summary: summary.to_string(),
+        };
+        let mut status = RootStatus::default();
+        assert_eq!(stale_message(&status), None);
+
+        // nothing was ever built, so nothing can be stale
+        status.failed = Some(record("error: oops"));
+        assert_eq!(stale_message(&status), None);
+
+        status.built = Some(record("/nix/store/abc-env"));
+        assert_eq!(
+            stale_message(&status),
+            Some(
+                "the last build failed, loading the environment of the previous build: error: oops"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn failed_rebuild_changes_watched_files() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let project = Project::new(
+            NixFile::from(tmp.path().join("shell.nix")),
+            &tmp.path().join("gc_roots"),
+            ContentAddressable::new(tmp.path().join("cas"))?,
+            Config::default(),
+        )?;
+        let roots = Roots::from_project(&project);
+        let modified = || {
+            watched_files(&roots, &tmp.path().join("daemon.socket"))
+                .iter()
+                .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+                .collect::<Vec<_>>()
+        };
+
+        let before = modified();
+        roots.record_failure(&BuildError::output("error: oops".to_string()))?;
+        assert_ne!(modified(), before);
+        let before = modified();
+        roots.record_failure(&BuildError::output("error: again".to_string()))?;
+        assert_ne!(modified(), before);
+        Ok(())
+    }
+
+    #[test]
+    fn shell_quote_roundtrip() {
+        let s = "error: undefined variable 'foo' at \"$HOME\"/shell.nix";
+        expect_bash(
+            r#"eval "quoted=$1"; [ "$quoted" == "$2" ]"#,
+            [shell_quote(s).as_str(), s],
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! Check the environment lorri runs in for common problems.
+
+use crate::cli::DoctorOptions;
+use crate::config::Config;
+use crate::constants::Paths;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::project::roots::{gc_roots_location, GcRootsLocation};
+use crate::socket::{LockHolder, SocketPath};
+use std::path::Path;
+use std::process::Command;
+
+/// Below this many inotify watches per user, watching larger projects
+/// falls back to polling (the kernel default used to be 8192).
+const MIN_INOTIFY_WATCHES: u64 = 65536;
+
+/// How bad the outcome of a check is.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    /// Everything works
+    Ok,
+    /// lorri works, but not as well as it could
+    Warning,
+    /// lorri does not work
+    Error,
+}
+
+/// The outcome of a single check.
+#[derive(Serialize, Debug)]
+struct Check {
+    /// Short name of the check
+    name: &'static str,
+    status: Status,
+    /// What was found, and what to do about it
+    message: String,
+}
+
+impl Check {
+    fn new<S: Into<String>>(name: &'static str, status: Status, message: S) -> Check {
+        Check {
+            name,
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// See the documentation for lorri::cli::Command::Doctor for details.
+pub fn main(paths: &Paths, opts: DoctorOptions) -> OpResult {
+    let config = Config::load(paths.config_file());
+    let mut checks = vec![nix(), direnv()];
+    checks.push(match &config {
+        Ok(_) if !paths.config_file().exists() => Check::new(
+            "config",
+            Status::Ok,
+            format!(
+                "{} does not exist, using the defaults",
+                paths.config_file().display()
+            ),
+        ),
+        Ok(_) => Check::new(
+            "config",
+            Status::Ok,
+            format!("{} is valid", paths.config_file().display()),
+        ),
+        Err(e) => Check::new("config", Status::Error, e.to_string()),
+    });
+    // the remaining checks work with the defaults if the config is broken
+    let config = config.unwrap_or_default();
+    checks.push(gc_roots(&config));
+    checks.push(daemon(
+        &SocketPath::from(paths.daemon_socket_file()),
+        paths.system_daemon_socket_file(),
+    ));
+    checks.push(inotify(Path::new("/proc/sys/fs/inotify/max_user_watches")));
+    checks.push(shell(std::env::var("SHELL").ok()));
+
+    if opts.json {
+        serde_json::to_writer_pretty(std::io::stdout(), &checks)
+            .map_err(|e| ExitError::temporary(format!("cannot print the checks: {}", e)))?;
+        println!();
+    } else {
+        for check in &checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warning => "warning",
+                Status::Error => "error",
+            };
+            println!("[{}] {}: {}", status, check.name, check.message);
+        }
+    }
+    if checks.iter().any(|check| check.status == Status::Error) {
+        Err(ExitError::environment_problem(
+            "some checks failed, see above",
+        ))
+    } else {
+        ok()
+    }
+}
+
+/// Can lorri evaluate nix files?
+fn nix() -> Check {
+    let name = "nix";
+    match Command::new("nix-instantiate").arg("--version").output() {
+        Ok(ref out) if out.status.success() => Check::new(
+            name,
+            Status::Ok,
+            String::from_utf8_lossy(&out.stdout).trim(),
+        ),
+        Ok(out) => Check::new(
+            name,
+            Status::Error,
+            format!(
+                "`nix-instantiate --version` failed: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            ),
+        ),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Check::new(
+            name,
+            Status::Error,
+            "`nix-instantiate` is not in PATH, lorri needs nix to be installed",
+        ),
+        Err(e) => Check::new(
+            name,
+            Status::Error,
+            format!("cannot run `nix-instantiate`: {}", e),
+        ),
+    }
+}
+
+/// Does `lorri direnv` work with the installed direnv?
+fn direnv() -> Check {
+    let name = "direnv";
+    match crate::ops::direnv::check_direnv_version() {
+        Ok(()) => Check::new(name, Status::Ok, "direnv is recent enough"),
+        // only `lorri direnv` needs direnv
+        Err(e) if e.exitcode() == 127 => Check::new(
+            name,
+            Status::Warning,
+            format!(
+                "{}, install it to load environments when entering a project",
+                e.message()
+            ),
+        ),
+        Err(e) => Check::new(name, Status::Error, e.message()),
+    }
+}
+
+/// Can lorri protect environments from garbage collection?
+fn gc_roots(config: &Config) -> Check {
+    let name = "gc-roots";
+    match gc_roots_location(config.gc_roots.directory.as_ref()) {
+        Ok(GcRootsLocation::Directory(dir)) => Check::new(
+            name,
+            Status::Ok,
+            format!("GC roots are created in {}", dir.display()),
+        ),
+        Ok(GcRootsLocation::NixStore { per_user_dir }) => {
+            match Command::new("nix-store").arg("--version").output() {
+                Ok(ref out) if out.status.success() => Check::new(
+                    name,
+                    Status::Warning,
+                    format!(
+                        "{} is not writable, GC roots are registered with \
+                     `nix-store --add-root --indirect` instead. Set `gc_roots.directory` \
+                     in the lorri config to use another directory",
+                        per_user_dir.display()
+                    ),
+                ),
+                _ => Check::new(
+                    name,
+                    Status::Error,
+                    format!(
+                        "{} is not writable and `nix-store` cannot be run. Set \
+                     `gc_roots.directory` in the lorri config to a writable directory \
+                     below the `gcroots` directory of nix",
+                        per_user_dir.display()
+                    ),
+                ),
+            }
+        }
+        Err(e) => Check::new(name, Status::Error, e.to_string()),
+    }
+}
+
+/// Is a daemon of the same version running and reachable?
+fn daemon(socket: &SocketPath, system_socket: &Path) -> Check {
+    let name = "daemon";
+    let version = match socket.lock_holder() {
+        Ok(LockHolder::Nobody)
+            if std::os::unix::net::UnixStream::connect(system_socket).is_ok() =>
+        {
+            return Check::new(
+                name,
+                Status::Ok,
+                format!(
+                    "the system daemon at {} serves this user",
+                    system_socket.display()
+                ),
+            )
+        }
+        Ok(LockHolder::Nobody) => {
+            return Check::new(
+                name,
+                Status::Warning,
+                "the daemon is not running, so environments are not rebuilt \
+                 when files change. Start it with `lorri daemon`",
+            )
+        }
+        Ok(LockHolder::Lorri { version, .. }) => version,
+        Err(e) => {
+            return Check::new(
+                name,
+                Status::Error,
+                format!("cannot check whether the daemon is running: {:?}", e),
+            )
+        }
+    };
+    if let Err(e) = std::os::unix::net::UnixStream::connect(socket.path()) {
+        return Check::new(
+            name,
+            Status::Error,
+            format!(
+                "the daemon is running, but its socket {} is not reachable ({}). \
+                 Restart the daemon",
+                socket.path().display(),
+                e
+            ),
+        );
+    }
+    match version {
+        Some(ref v) if v == crate::LORRI_VERSION => Check::new(
+            name,
+            Status::Ok,
+            format!("the daemon (version {}) is listening", v),
+        ),
+        _ => Check::new(
+            name,
+            Status::Warning,
+            format!(
+                "the daemon runs version {}, but this is version {}. Restart the daemon",
+                version.as_ref().map_or("unknown", |v| v.as_str()),
+                crate::LORRI_VERSION
+            ),
+        ),
+    }
+}
+
+/// Can the daemon watch files without falling back to polling?
+fn inotify(max_user_watches: &Path) -> Check {
+    let name = "inotify";
+    match std::fs::read_to_string(max_user_watches)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    {
+        // not on Linux
+        None => Check::new(name, Status::Ok, "inotify is not used on this system"),
+        Some(n) if n < MIN_INOTIFY_WATCHES => Check::new(
+            name,
+            Status::Warning,
+            format!(
+                "fs.inotify.max_user_watches is {}, so watching larger projects falls back \
+                 to polling. Raise it to at least {} with `sysctl`",
+                n, MIN_INOTIFY_WATCHES
+            ),
+        ),
+        Some(n) => Check::new(
+            name,
+            Status::Ok,
+            format!("fs.inotify.max_user_watches is {}", n),
+        ),
+    }
+}
+
+/// Can `lorri shell` start the user’s shell?
+fn shell(shell: Option<String>) -> Check {
+    let name = "shell";
+    let shell = match shell {
+        Some(shell) => shell,
+        None => {
+            return Check::new(
+                name,
+                Status::Warning,
+                "$SHELL is not set, so `lorri shell` cannot start your shell",
+            )
+        }
+    };
+    let supported = ["bash", "zsh", "fish", "nu", "xonsh", "tcsh"];
+    match Path::new(&shell).file_name().and_then(|n| n.to_str()) {
+        Some(n) if supported.contains(&n) => {
+            Check::new(name, Status::Ok, format!("`lorri shell` starts {}", shell))
+        }
+        _ => Check::new(
+            name,
+            Status::Warning,
+            format!(
+                "`lorri shell` starts {}, but cannot add the (lorri) prompt prefix to it",
+                shell
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inotify_limits() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("max_user_watches");
+        assert_eq!(inotify(&file).status, Status::Ok);
+        std::fs::write(&file, "8192\n")?;
+        assert_eq!(inotify(&file).status, Status::Warning);
+        std::fs::write(&file, "524288\n")?;
+        assert_eq!(inotify(&file).status, Status::Ok);
+        Ok(())
+    }
+
+    #[test]
+    fn shells() {
+        assert_eq!(shell(None).status, Status::Warning);
+        assert_eq!(shell(Some("/bin/zsh".to_string())).status, Status::Ok);
+        assert_eq!(shell(Some("/bin/ksh".to_string())).status, Status::Warning);
+    }
+
+    #[test]
+    fn daemon_not_running() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let socket = SocketPath::from(&dir.path().join("daemon.socket"));
+        let system_socket = dir.path().join("system.socket");
+        assert_eq!(daemon(&socket, &system_socket).status, Status::Warning);
+        // the system daemon serves users without a daemon
+        let system_listener = std::os::unix::net::UnixListener::bind(&system_socket)?;
+        assert_eq!(daemon(&socket, &system_socket).status, Status::Ok);
+        drop(system_listener);
+        std::fs::remove_file(&system_socket)?;
+        let _lock = socket.lock().unwrap();
+        // the lock is held, but nobody listens on the socket
+        assert_eq!(daemon(&socket, &system_socket).status, Status::Error);
+        Ok(())
+    }
+
+    #[test]
+    fn json_output() {
+        let check = Check::new("shell", Status::Warning, "oops");
+        assert_eq!(
+            serde_json::to_string(&check).unwrap(),
+            r#"{"name":"shell","status":"warning","message":"oops"}"#
+        );
+    }
+}
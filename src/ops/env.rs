@@ -0,0 +1,98 @@
+//! Print and inspect the environments built for a project.
+
+use crate::cli::EnvDiffOptions;
+use crate::env::diff::diff;
+use crate::env::format::{write, Format};
+use crate::env::{evaluate, read_env, Env};
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::project::roots::{Generations, Roots};
+use crate::project::Project;
+use std::path::{Path, PathBuf};
+
+/// See the documentation for lorri::cli::EnvOptions for details.
+pub fn main(project: Project, format: Format) -> OpResult {
+    let root = Roots::from_project(&project).paths().shell_gc_root;
+    if !root.0.exists() {
+        return Err(ExitError::expected_error(
+            "this project has not been built yet; start `lorri daemon` or run `lorri watch --once`",
+        ));
+    }
+    let base = std::env::vars().collect::<Env>();
+    let env = evaluate(&root.0, &base).map_err(|e| {
+        ExitError::temporary(format!("cannot read the environment of {}: {}", root, e))
+    })?;
+    write(format, &env, std::io::stdout())?;
+    ok()
+}
+
+/// See the documentation for lorri::cli::EnvCommand::Diff for details.
+pub fn diff_main(project: Project, opts: EnvDiffOptions) -> OpResult {
+    let generations = Roots::from_project(&project)
+        .generations()
+        .map_err(|e| ExitError::temporary(format!("cannot read generations: {}", e)))?;
+
+    let env_diff = if opts.from_shell {
+        let to = generation_path(&generations, opts.to.or(generations.active))?;
+        let current = std::env::vars().collect::<Env>();
+        let new = evaluate(&to, &current).map_err(|e| {
+            ExitError::temporary(format!(
+                "cannot read the environment of {}: {}",
+                to.display(),
+                e
+            ))
+        })?;
+        let current = current
+            .into_iter()
+            .filter(|(name, _)| new.contains_key(name))
+            .collect::<Env>();
+        diff(&current, &new)
+    } else {
+        let to_number = opts.to.or(generations.active);
+        let from_number = match opts.from {
+            Some(n) => Some(n),
+            None => to_number.and_then(|to| {
+                generations
+                    .generations
+                    .iter()
+                    .rev()
+                    .map(|g| g.number)
+                    .find(|n| *n < to)
+            }),
+        };
+        let from = generation_path(&generations, from_number)?;
+        let to = generation_path(&generations, to_number)?;
+        diff(&read(&from)?, &read(&to)?)
+    };
+
+    if env_diff.is_empty() {
+        println!("no differences");
+    } else {
+        print!("{}", env_diff);
+    }
+    ok()
+}
+
+/// The store path of generation `number`.
+fn generation_path(generations: &Generations, number: Option<u32>) -> Result<PathBuf, ExitError> {
+    let number = number.ok_or_else(|| {
+        ExitError::user_error(
+            "not enough generations to compare; see `lorri generations` for the existing ones",
+        )
+    })?;
+    generations
+        .generations
+        .iter()
+        .find(|g| g.number == number)
+        .map(|g| g.store_path.clone())
+        .ok_or_else(|| ExitError::user_error(format!("generation {} does not exist", number)))
+}
+
+fn read(root: &Path) -> Result<Env, ExitError> {
+    read_env(root).map_err(|e| {
+        ExitError::temporary(format!(
+            "cannot read the environment of {}: {}",
+            root.display(),
+            e
+        ))
+    })
+}
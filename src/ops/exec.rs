@@ -0,0 +1,61 @@
+//! Run a command in the project environment.
+
+use crate::cli::ExecOptions;
+use crate::env::{evaluate, pure, Env};
+use crate::ops::error::ExitError;
+use crate::ops::error::OpResult;
+use crate::ops::shell::{build_root, cached_root};
+use crate::project::Project;
+use slog_scope::debug;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// See the documentation for lorri::cli::Command::Exec for details.
+///
+/// lorri execs into the command, so its exit status and the signals
+/// it receives are the command’s own.
+pub fn main(project: Project, opts: ExecOptions) -> OpResult {
+    let cached = cached_root(&project);
+    let root = if opts.cached {
+        cached?
+    } else {
+        build_root(&project, cached.is_ok())?
+    };
+    let current = std::env::vars().collect::<Env>();
+    let base = if opts.pure {
+        pure(&current, &opts.keep)
+    } else {
+        current
+    };
+    let env = evaluate(&root, &base).map_err(|e| {
+        ExitError::temporary(format!(
+            "cannot read the environment of {}: {}",
+            root.display(),
+            e
+        ))
+    })?;
+
+    let (program, args) = opts
+        .command
+        .split_first()
+        .expect("structopt requires a command");
+    debug!("executing"; "program" => ?program, "args" => ?args);
+    let mut cmd = Command::new(program);
+    if opts.pure {
+        cmd.env_clear().envs(&base);
+    }
+    let e = cmd.args(args).envs(&env).exec();
+
+    // 'exec' will never return on success, so if we get here, we know something has gone wrong.
+    Err(match e.kind() {
+        std::io::ErrorKind::NotFound => ExitError::missing_executable(format!(
+            "{}: command not found",
+            program.to_string_lossy()
+        )),
+        _ => ExitError::temporary(format!(
+            "cannot execute {}: {}",
+            program.to_string_lossy(),
+            e
+        )),
+    })
+}
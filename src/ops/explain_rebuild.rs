@@ -0,0 +1,352 @@
+//! Explain why lorri rebuilds a project, based on the diagnostics
+//! recorded by the build loop (see `project::diagnostics`).
+
+use crate::cli::ExplainRebuild_;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::project::diagnostics::{Diagnostics, Trigger};
+use crate::project::Project;
+use crate::watch::Reason;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory names which usually contain build outputs or other
+/// frequently changing files that should not influence the environment.
+/// Not `.git`: lorri watches its HEAD, refs and index for local `fetchGit`
+/// (see `crate::git`), so ignoring it would break those rebuilds.
+const NOISY_DIRECTORIES: &[&str] = &[
+    ".direnv",
+    ".stack-work",
+    "_build",
+    "dist",
+    "dist-newstyle",
+    "node_modules",
+    "result",
+    "target",
+];
+
+/// How many watched paths are shown if `--all` is not given.
+const SHOWN_WATCHED_PATHS: usize = 30;
+
+/// See the documentation for lorri::cli::Internal_::ExplainRebuild_ for details.
+pub fn main(project: Project, opts: ExplainRebuild_) -> OpResult {
+    let diagnostics = Diagnostics::from_project(&project);
+    let watched = diagnostics
+        .watched()
+        .map_err(|e| ExitError::temporary(format!("cannot read watched paths: {}", e)))?
+        .ok_or_else(|| {
+            ExitError::expected_error(
+                "no diagnostics recorded for this project yet; \
+                 they are written whenever `lorri daemon` or `lorri watch` evaluates it",
+            )
+        })?;
+    let triggers = diagnostics
+        .triggers()
+        .map_err(|e| ExitError::temporary(format!("cannot read rebuild triggers: {}", e)))?;
+
+    println!("Watched paths ({}):", watched.len());
+    let shown = if opts.all {
+        watched.len()
+    } else {
+        SHOWN_WATCHED_PATHS
+    };
+    for p in watched.iter().take(shown) {
+        println!("  {}", p.display());
+    }
+    if watched.len() > shown {
+        println!(
+            "  … and {} more (use --all to list all)",
+            watched.len() - shown
+        );
+    }
+
+    println!("\nTop directories by watch count:");
+    for (dir, count) in top_directories(&watched).into_iter().take(10) {
+        println!("  {:>6}  {}", count, dir.display());
+    }
+
+    println!("\nRecent rebuild triggers (newest first):");
+    if triggers.is_empty() {
+        println!("  none recorded");
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    for trigger in triggers.iter().rev().take(opts.triggers) {
+        println!(
+            "  {:>9}  {}",
+            ago(now.saturating_sub(trigger.time)),
+            describe(trigger)
+        );
+    }
+
+    let suggestions = suggestions(&watched, &triggers);
+    if !suggestions.is_empty() {
+        println!("\nSuggestions:");
+        for s in suggestions {
+            println!("  - {}", s);
+        }
+    }
+    ok()
+}
+
+/// Number of watched paths per parent directory, most watched first.
+fn top_directories(watched: &[PathBuf]) -> Vec<(PathBuf, usize)> {
+    let mut counts: HashMap<&Path, usize> = HashMap::new();
+    for p in watched {
+        if let Some(parent) = p.parent() {
+            *counts.entry(parent).or_insert(0) += 1;
+        }
+    }
+    let mut counts = counts
+        .into_iter()
+        .map(|(dir, count)| (dir.to_owned(), count))
+        .collect::<Vec<_>>();
+    counts.sort_by(|(d1, c1), (d2, c2)| c2.cmp(c1).then(d1.cmp(d2)));
+    counts
+}
+
+fn describe(trigger: &Trigger) -> String {
+    match &trigger.reason {
+        Reason::ProjectAdded(_) => "project added".to_string(),
+        Reason::PingReceived => "ping received".to_string(),
+        Reason::UnknownEvent(msg) => format!("unknown event: {}", msg.0),
+        Reason::FilesChanged(files) => format!(
+            "files changed [{}]: {}",
+            trigger.event_kind.as_ref().map_or("?", String::as_str),
+            files
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn ago(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s ago", s),
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 60 * 60 * 24 => format!("{}h ago", s / (60 * 60)),
+        s => format!("{}d ago", s / (60 * 60 * 24)),
+    }
+}
+
+/// The outermost noisy directory `path` is in, if any.
+fn noisy_directory(path: &Path) -> Option<PathBuf> {
+    let mut dir = PathBuf::new();
+    for component in path.components() {
+        dir.push(component);
+        if NOISY_DIRECTORIES
+            .iter()
+            .any(|noisy| component.as_os_str() == *noisy)
+        {
+            return Some(dir);
+        }
+    }
+    None
+}
+
+/// The innermost watched path `path` is in, if any.
+fn watched_ancestor<'a>(watched: &'a [PathBuf], path: &Path) -> Option<&'a Path> {
+    watched
+        .iter()
+        .filter(|w| path.starts_with(w))
+        .max_by_key(|w| w.components().count())
+        .map(PathBuf::as_path)
+}
+
+/// How a noisy directory shows up in the diagnostics.
+#[derive(Default)]
+struct Noisy<'a> {
+    /// number of watched paths inside of it
+    watched: usize,
+    /// number of changed files inside of it that triggered a rebuild
+    triggered: usize,
+    /// the watched path it is in, if it is not watched directly
+    watched_via: Option<&'a Path>,
+}
+
+fn suggestions(watched: &[PathBuf], triggers: &[Trigger]) -> Vec<String> {
+    let mut suggestions = vec![];
+
+    let mut noisy: HashMap<PathBuf, Noisy> = HashMap::new();
+    // noisy directories with watched paths inside of them
+    for p in watched {
+        if let Some(dir) = noisy_directory(p) {
+            noisy.entry(dir).or_default().watched += 1;
+        }
+    }
+    // how often a file triggered a rebuild
+    let mut file_triggers: HashMap<&Path, usize> = HashMap::new();
+    let mut metadata_triggers = 0;
+    for trigger in triggers {
+        if let Reason::FilesChanged(files) = &trigger.reason {
+            for f in files {
+                *file_triggers.entry(f).or_insert(0) += 1;
+                // noisy directories with files that triggered rebuilds,
+                // usually because a directory containing them is watched
+                if let Some(dir) = noisy_directory(f) {
+                    let entry = noisy.entry(dir.clone()).or_default();
+                    entry.triggered += 1;
+                    entry.watched_via = watched_ancestor(watched, f)
+                        .filter(|ancestor| dir.starts_with(ancestor) && *ancestor != dir);
+                }
+            }
+            if let Some(kind) = &trigger.event_kind {
+                if kind.contains("Metadata") {
+                    metadata_triggers += 1;
+                }
+            }
+        }
+    }
+
+    let mut noisy = noisy.into_iter().collect::<Vec<_>>();
+    noisy.sort_by(|(d1, _), (d2, _)| d1.cmp(d2));
+    for (dir, n) in noisy {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        let parent = dir.parent().unwrap_or(&dir).display();
+        let mut found = vec![];
+        if n.watched > 0 {
+            found.push(format!("has {} watched paths", n.watched));
+        }
+        if n.triggered > 0 {
+            found.push(format!(
+                "triggered {} of the recorded rebuilds",
+                n.triggered
+            ));
+        }
+        let via = match n.watched_via {
+            Some(ancestor) => format!(" because {} is watched", ancestor.display()),
+            None => "".to_string(),
+        };
+        suggestions.push(format!(
+            "directory `{}/` in {} {}. \
+             It is probably watched{} since it is copied into the store \
+             via `src = ./.`; filter it out with `builtins.filterSource` \
+             or `lib.cleanSource`, or add an `ignore` path reducer for it \
+             to the lorri config.",
+            name,
+            parent,
+            found.join(" and "),
+            via
+        ));
+    }
+
+    let mut frequent = file_triggers
+        .into_iter()
+        .filter(|(_, n)| *n >= 3)
+        .collect::<Vec<_>>();
+    frequent.sort_by(|(f1, n1), (f2, n2)| n2.cmp(n1).then(f1.cmp(f2)));
+    for (file, n) in frequent.into_iter().take(3) {
+        suggestions.push(format!(
+            "`{}` triggered {} of the recorded rebuilds. \
+             If it is not part of the environment, check why your nix files reference it.",
+            file.display(),
+            n
+        ));
+    }
+
+    if metadata_triggers > 0 {
+        suggestions.push(format!(
+            "{} rebuilds were triggered by metadata changes only \
+             (e.g. `touch` or permission changes), not by content changes.",
+            metadata_triggers
+        ));
+    }
+
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files_changed(files: &[&str], event_kind: &str) -> Trigger {
+        Trigger {
+            time: 0,
+            reason: Reason::FilesChanged(files.iter().map(PathBuf::from).collect()),
+            event_kind: Some(event_kind.to_string()),
+        }
+    }
+
+    #[test]
+    fn top_directories_by_count() {
+        let watched = ["/p/a", "/p/b", "/p/src/c", "/p/src/d", "/p/src/e"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            top_directories(&watched),
+            vec![(PathBuf::from("/p/src"), 3), (PathBuf::from("/p"), 2)]
+        );
+    }
+
+    #[test]
+    fn suggest_noisy_directories() {
+        // `src = ./.` watches the whole project directory
+        let watched = vec![PathBuf::from("/p")];
+        let triggers = vec![
+            files_changed(&["/p/target/debug/foo"], "Modify(Data(Any))"),
+            files_changed(&["/p/target/debug/foo"], "Modify(Data(Any))"),
+            files_changed(
+                &["/p/target/debug/foo", "/p/target/debug/bar"],
+                "Create(File)",
+            ),
+        ];
+
+        let suggestions = suggestions(&watched, &triggers);
+        assert_eq!(suggestions.len(), 2, "{:#?}", suggestions);
+        assert!(
+            suggestions[0].starts_with(
+                "directory `target/` in /p triggered 4 of the recorded rebuilds. \
+                 It is probably watched because /p is watched"
+            ),
+            "{}",
+            suggestions[0]
+        );
+        assert!(suggestions[1].starts_with("`/p/target/debug/foo` triggered 3"));
+    }
+
+    #[test]
+    fn suggest_watched_noisy_directories() {
+        let watched = ["/p/shell.nix", "/p/target/debug/foo", "/p/target/debug/bar"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+
+        let suggestions = suggestions(&watched, &[]);
+        assert_eq!(suggestions.len(), 1, "{:#?}", suggestions);
+        assert!(suggestions[0].starts_with("directory `target/` in /p has 2 watched paths."));
+    }
+
+    #[test]
+    fn git_refs_are_not_noisy() {
+        // a local `fetchGit` watches the refs of the repository
+        let watched = ["/p/shell.nix", "/p/.git/HEAD", "/p/.git/refs/heads"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let triggers = vec![
+            files_changed(&["/p/.git/refs/heads/main"], "Modify(Data(Any))"),
+            files_changed(&["/p/.git/refs/heads/feature"], "Create(File)"),
+        ];
+
+        let suggestions = suggestions(&watched, &triggers);
+        assert!(suggestions.is_empty(), "{:#?}", suggestions);
+    }
+
+    #[test]
+    fn suggest_frequent_and_metadata_triggers() {
+        let triggers = vec![
+            files_changed(&["/p/shell.nix"], "Modify(Metadata(Any))"),
+            files_changed(&["/p/shell.nix"], "Modify(Data(Any))"),
+            files_changed(&["/p/shell.nix"], "Modify(Data(Any))"),
+        ];
+        let suggestions = suggestions(&[], &triggers);
+        assert_eq!(suggestions.len(), 2, "{:#?}", suggestions);
+        assert!(suggestions[0].starts_with("`/p/shell.nix` triggered 3"));
+        assert!(suggestions[1].starts_with("1 rebuilds were triggered by metadata"));
+    }
+}
@@ -0,0 +1,62 @@
+//! Explain which files lorri watches for a project, and why.
+
+use crate::builder;
+use crate::nix::options::NixOptions;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::pathreduction::{Decision, Explanation, Reducers};
+use crate::project::Project;
+
+/// See the documentation for lorri::cli::Internal_::ExplainWatches_ for details.
+pub fn main(project: Project) -> OpResult {
+    // TODO: add the ability to pass extra_nix_options
+    let mut paths =
+        builder::referenced_paths(&project.nix_file, &project.cas, &NixOptions::empty())
+            .map_err(|e| ExitError::expected_error(format!("Evaluation failed: {}", e)))?;
+    // nix reports files every time they are used
+    paths.sort();
+    paths.dedup();
+
+    let reducers = Reducers::from_config(&project.config);
+    let explanations = reducers.explain(&paths);
+    for explanation in &explanations {
+        println!("{}", describe(explanation));
+    }
+    println!(
+        "\n{} referenced paths, {} watched",
+        paths.len(),
+        reducers.reduce(&paths).len()
+    );
+    ok()
+}
+
+fn describe(explanation: &Explanation) -> String {
+    let Explanation {
+        path,
+        reducer,
+        decision,
+        covered,
+    } = explanation;
+    let mut lines = vec![match decision {
+        Decision::Kept => format!("kept       {}", path.display()),
+        Decision::Removed => format!("removed    {}", path.display()),
+        Decision::Rewritten(to) => format!(
+            "rewritten  {} -> {}",
+            path.display(),
+            to.iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }];
+    if let Some(reducer) = reducer {
+        lines[0].push_str(&format!("  (by {})", reducer));
+    }
+    for (p, by) in covered {
+        lines.push(format!(
+            "  covered  {}  (below watched directory {})",
+            p.display(),
+            by.display()
+        ));
+    }
+    lines.join("\n")
+}
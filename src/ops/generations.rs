@@ -0,0 +1,74 @@
+//! List the environment generations of a project.
+
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::project::roots::Roots;
+use crate::project::Project;
+
+/// See the documentation for lorri::cli::Command::Generations for details.
+pub fn main(project: Project) -> OpResult {
+    let generations = Roots::from_project(&project)
+        .generations()
+        .map_err(|e| ExitError::temporary(format!("cannot read generations: {}", e)))?;
+    if generations.generations.is_empty() {
+        return Err(ExitError::expected_error(
+            "this project has no generations yet; they are created by every successful build",
+        ));
+    }
+    for generation in &generations.generations {
+        println!(
+            "{:>5}   {}   {}{}{}",
+            generation.number,
+            format_utc(generation.time),
+            generation.store_path.display(),
+            if generations.active == Some(generation.number) {
+                "   (current)"
+            } else {
+                ""
+            },
+            if generation.store_path.exists() {
+                ""
+            } else {
+                "   (garbage collected)"
+            }
+        );
+    }
+    ok()
+}
+
+/// Format seconds since the UNIX epoch as `YYYY-MM-DD HH:MM:SS` in UTC.
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil date from days since the epoch,
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_utc;
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_utc(1_595_249_999), "2020-07-20 12:59:59");
+    }
+}
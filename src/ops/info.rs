@@ -1,14 +1,17 @@
 //! The info callable is for printing
 
 use crate::builder::OutputPaths;
+use crate::error::BuildError;
 use crate::ops::error::{ok, OpResult};
 use crate::project::{roots::Roots, Project};
+use std::path::PathBuf;
 
 /// See the documentation for lorri::cli::Command::Info for more
 /// details.
 pub fn main(project: Project) -> OpResult {
     println!("lorri version: {}", crate::LORRI_VERSION);
-    let root_paths = Roots::from_project(&project).paths();
+    let roots = Roots::from_project(&project);
+    let root_paths = roots.paths();
     let OutputPaths { shell_gc_root } = &root_paths;
     if root_paths.all_exist() {
         println!(
@@ -18,5 +21,57 @@ pub fn main(project: Project) -> OpResult {
     } else {
         println!("GC roots do not exist. Has the project been built with lorri yet?",);
     }
+    println!(
+        "Keeps build inputs: {}",
+        if roots.keeps_outputs() { "yes" } else { "no" }
+    );
+    match closure_size(&roots.pinned()) {
+        Ok((count, bytes)) => println!("Pinned store paths: {} ({})", count, human_size(bytes)),
+        Err(e) => println!("Pinned store paths: unknown ({})", e.summary()),
+    }
     ok()
 }
+
+/// Number of store paths in the closure of `paths`, and their total size in bytes.
+fn closure_size(paths: &[PathBuf]) -> Result<(usize, u64), BuildError> {
+    let closure = crate::nix::query_store(&["--requisites"], paths)?;
+    let sizes = crate::nix::query_store(&["--size"], &closure)?;
+    let bytes = sizes
+        .iter()
+        .map(|size| {
+            size.trim()
+                .parse::<u64>()
+                .map_err(|e| BuildError::output(format!("invalid size {:?}: {}", size, e)))
+        })
+        .sum::<Result<u64, _>>()?;
+    Ok((closure.len(), bytes))
+}
+
+/// Format `bytes` with a binary unit prefix, like `nix-store --gc` does.
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::human_size;
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.50 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.00 GiB");
+    }
+}
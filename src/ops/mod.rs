@@ -2,9 +2,16 @@
 
 pub mod daemon;
 pub mod direnv;
+pub mod doctor;
+pub mod env;
+pub mod exec;
+pub mod explain_rebuild;
+pub mod explain_watches;
+pub mod generations;
 pub mod info;
 pub mod init;
 pub mod ping;
+pub mod rollback;
 pub mod shell;
 pub mod start_user_shell;
 pub mod stream_events;
@@ -18,6 +25,15 @@ pub fn get_paths() -> Result<crate::constants::Paths, error::ExitError> {
     })
 }
 
+/// Read the user configuration or fail.
+pub fn get_config(
+    paths: &crate::constants::Paths,
+) -> Result<crate::config::Config, error::ExitError> {
+    crate::config::Config::load(paths.config_file()).map_err(|e| {
+        error::ExitError::user_error(format!("Cannot read the lorri configuration: {}", e))
+    })
+}
+
 /// Error handling in ops.
 pub mod error {
 
@@ -144,4 +160,10 @@ pub mod error {
             ExitError::temporary(format!("{}", e))
         }
     }
+
+    impl From<crate::proto::Error> for ExitError {
+        fn from(e: crate::proto::Error) -> ExitError {
+            ExitError::temporary(format!("{}", e))
+        }
+    }
 }
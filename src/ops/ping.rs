@@ -1,7 +1,7 @@
 //! Run a BuildLoop for `shell.nix`, watching for input file changes.
 //! Can be used together with `direnv`.
 use crate::internal_proto;
-use crate::ops::error::{ok, OpResult};
+use crate::ops::error::{ok, ExitError, OpResult};
 use crate::NixFile;
 use std::convert::TryFrom;
 
@@ -15,9 +15,10 @@ pub fn main(nix_file: NixFile, addr: Option<String>) -> OpResult {
 
     use internal_proto::VarlinkClientInterface;
     internal_proto::VarlinkClient::new(
-        varlink::Connection::with_address(&address).expect("failed to connect to daemon server"),
+        crate::daemon::client::connect(&address)
+            .ok_or_else(|| ExitError::temporary("no compatible daemon is listening"))?,
     )
-    .watch_shell(shell_nix)
+    .watch_shell(shell_nix, None)
     .call()
     .expect("call to daemon server failed");
     ok()
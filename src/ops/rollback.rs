@@ -0,0 +1,23 @@
+//! Activate an older environment generation of a project.
+
+use crate::cli::RollbackOptions;
+use crate::ops::error::{ok, ExitError, OpResult};
+use crate::project::roots::{RollbackError, Roots};
+use crate::project::Project;
+
+/// See the documentation for lorri::cli::Command::Rollback for details.
+pub fn main(project: Project, opts: RollbackOptions) -> OpResult {
+    let generation = Roots::from_project(&project)
+        .rollback(opts.generation)
+        .map_err(|e| match e {
+            RollbackError::Roots(_) => ExitError::temporary(e.to_string()),
+            _ => ExitError::user_error(format!("cannot roll back: {}", e)),
+        })?;
+    println!(
+        "switched to generation {} ({})",
+        generation.number,
+        generation.store_path.display()
+    );
+    println!("it stays active until the next successful build");
+    ok()
+}
@@ -1,12 +1,16 @@
 //! Open up a project shell
 
+use crate::build_loop::Event;
 use crate::builder;
 use crate::cas::ContentAddressable;
 use crate::cli::ShellOptions;
+use crate::daemon::EventFilter;
 use crate::nix::CallOpts;
 use crate::ops::error::{ExitError, OpResult};
 use crate::project::{roots::Roots, Project};
+use crate::watch::{DebugMessage, Reason};
 use slog_scope::debug;
+use std::convert::{TryFrom, TryInto};
 use std::io;
 use std::io::Write;
 use std::path::Path;
@@ -27,8 +31,10 @@ use std::{env, thread};
 ///
 /// `lorri shell`
 /// ├── builds the project environment if --cached is false
+/// │   (or lets the daemon build it, if the daemon is running)
 /// ├── writes a bash init script that loads the project environment
 /// ├── SPAWNS bash with the init script as its `--rcfile`
+/// │   (with --pure, only the session variables of the environment are passed on)
 /// │   └── EXECS `lorri internal start-user-shell`
 /// │       ├── (*) performs shell-specific setup for $SHELL
 /// │       └── EXECS into user shell $SHELL
@@ -50,6 +56,16 @@ pub fn main(project: Project, opts: ShellOptions) -> OpResult {
         },
         &project.cas,
     )?;
+    if opts.pure {
+        let kept = crate::env::pure(&env::vars().collect(), &opts.keep);
+        for (name, _) in env::vars() {
+            if name != "BASH_ENV" && !kept.contains_key(&name) {
+                bash_cmd.env_remove(name);
+            }
+        }
+        // bash would set a default PATH, which `envrc.bash` then appends
+        bash_cmd.env("PATH", "");
+    }
 
     debug!("bash_cmd : {:?}", bash_cmd);
     let status = bash_cmd
@@ -80,7 +96,126 @@ pub fn main(project: Project, opts: ShellOptions) -> OpResult {
     }
 }
 
-fn build_root(project: &Project, cached: bool) -> Result<PathBuf, ExitError> {
+/// Build the project, showing progress on stderr.
+///
+/// If the daemon is running, it builds the project; otherwise the project is built here.
+/// `cached` says whether an older environment exists.
+pub(crate) fn build_root(project: &Project, cached: bool) -> Result<PathBuf, ExitError> {
+    let address = crate::ops::get_paths()?.daemon_socket_address();
+    match build_with_daemon(project, cached, &address)? {
+        Some(root) => Ok(root),
+        None => {
+            debug!("daemon is not running, building locally");
+            build_locally(project, cached)
+        }
+    }
+}
+
+/// Ask the daemon at `address` to build the project, and wait for the build.
+/// `None` if the daemon is not running.
+pub fn build_with_daemon(
+    project: &Project,
+    cached: bool,
+    address: &str,
+) -> Result<Option<PathBuf>, ExitError> {
+    use crate::internal_proto::VarlinkClientInterface as _;
+    use crate::proto::VarlinkClientInterface as _;
+
+    let (monitor_connection, watch_connection) = match (
+        crate::daemon::client::connect(address),
+        crate::daemon::client::open(address),
+    ) {
+        (Some(m), Some(w)) => (m, w),
+        _ => return Ok(None),
+    };
+    let shell_nix = crate::internal_proto::ShellNix::try_from(&project.nix_file)
+        .map_err(ExitError::temporary)?;
+
+    // Only the events of this project are of interest
+    // (older daemons ignore the filter and send all).
+    let filter = crate::proto::MonitorFilter::try_from(&EventFilter {
+        nix_files: vec![project.nix_file.clone()],
+        ..EventFilter::default()
+    })
+    .map_err(ExitError::temporary)?;
+
+    // Subscribe before announcing the project, so that no event is missed.
+    let mut monitor = crate::proto::VarlinkClient::new(monitor_connection);
+    let mut call = monitor.monitor(Some(filter));
+    let replies = call
+        .more()
+        .map_err(|e| ExitError::temporary(format!("cannot monitor the daemon: {:?}", e)))?;
+    crate::internal_proto::VarlinkClient::new(watch_connection)
+        .watch_shell(shell_nix, Some(true))
+        .call()
+        .map_err(|e| ExitError::temporary(format!("cannot reach the daemon: {:?}", e)))?;
+
+    // The snapshot holds builds from before we asked, which might miss the latest
+    // changes. So we wait for a build that started after we subscribed: the
+    // daemon builds the project again since we asked it to `rebuild`.
+    let mut live = false;
+    let mut building = false;
+    for reply in replies {
+        let event: Event = reply
+            .map_err(|e| ExitError::temporary(format!("lost the daemon connection: {:?}", e)))?
+            .try_into()
+            .map_err(ExitError::temporary)?;
+        match event {
+            Event::SectionEnd => live = true,
+            Event::Shutdown => break,
+            _ if !live => {}
+            Event::Started { nix_file, reason } if nix_file == project.nix_file => {
+                eprintln!(
+                    "lorri: the daemon is building the environment ({})",
+                    describe(&reason)
+                );
+                building = true;
+            }
+            Event::Completed { nix_file, .. } if building && nix_file == project.nix_file => {
+                eprintln!("lorri: environment built by the daemon");
+                return cached_root(project).map(Some);
+            }
+            Event::Failure { nix_file, failure } if building && nix_file == project.nix_file => {
+                return Err(build_failed(cached, failure))
+            }
+            _ => {}
+        }
+    }
+    Err(ExitError::temporary(
+        "the daemon stopped before the build finished",
+    ))
+}
+
+fn describe(reason: &Reason) -> String {
+    match reason {
+        Reason::ProjectAdded(_) => "project added".to_string(),
+        Reason::PingReceived => "ping received".to_string(),
+        Reason::FilesChanged(files) => match files.as_slice() {
+            [file] => format!("{} changed", file.display()),
+            _ => format!("{} files changed", files.len()),
+        },
+        Reason::UnknownEvent(DebugMessage(msg)) => msg.clone(),
+    }
+}
+
+fn build_failed<E: std::fmt::Display>(cached: bool, e: E) -> ExitError {
+    if cached {
+        ExitError::temporary(format!(
+            "Build failed. Hint: try running with `--cached` to use the most \
+             recent environment that was built successfully.\n\
+             Build error: {}",
+            e
+        ))
+    } else {
+        ExitError::temporary(format!(
+            "Build failed. No cached environment available.\n\
+             Build error: {}",
+            e
+        ))
+    }
+}
+
+fn build_locally(project: &Project, cached: bool) -> Result<PathBuf, ExitError> {
     let building = Arc::new(AtomicBool::new(true));
     let building_clone = building.clone();
     let progress_thread = thread::spawn(move || {
@@ -94,7 +229,7 @@ fn build_root(project: &Project, cached: bool) -> Result<PathBuf, ExitError> {
             if let Some(start_time) = start {
                 if start_time.elapsed() >= Duration::from_millis(10_000) {
                     eprintln!(
-                        "\nHint: you can use `lorri shell --cached` to use the most recent \
+                        "\nHint: you can use `--cached` to use the most recent \
                          environment that was built successfully."
                     );
                     start = None; // Don't show the hint again
@@ -118,28 +253,11 @@ fn build_root(project: &Project, cached: bool) -> Result<PathBuf, ExitError> {
     building.store(false, Ordering::SeqCst);
     progress_thread.join().unwrap();
 
-    let run_result = run_result
-        .map_err(|e| {
-            if cached {
-                ExitError::temporary(format!(
-                    "Build failed. Hint: try running `lorri shell --cached` to use the most \
-                     recent environment that was built successfully.\n\
-                     Build error: {}",
-                    e
-                ))
-            } else {
-                ExitError::temporary(format!(
-                    "Build failed. No cached environment available.\n\
-                     Build error: {}",
-                    e
-                ))
-            }
-        })?
-        .result;
+    let run_result = run_result.map_err(|e| build_failed(cached, e))?;
 
     Ok(Path::new(
         Roots::from_project(&project)
-            .create_roots(run_result)
+            .create_roots(run_result.result, &run_result.drv)
             .map_err(|e| ExitError::temporary(format!("rooting the environment failed: {}", e)))?
             .shell_gc_root
             .as_os_str(),
@@ -147,7 +265,8 @@ fn build_root(project: &Project, cached: bool) -> Result<PathBuf, ExitError> {
     .to_owned())
 }
 
-fn cached_root(project: &Project) -> Result<PathBuf, ExitError> {
+/// The environment of the latest successful build, without building.
+pub(crate) fn cached_root(project: &Project) -> Result<PathBuf, ExitError> {
     let root_paths = Roots::from_project(&project).paths();
     if !root_paths.all_exist() {
         Err(ExitError::temporary(
@@ -5,7 +5,7 @@ use crate::cli::StartUserShellOptions_;
 use crate::ops::error::OpResult;
 use crate::project::Project;
 use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::{env, fs};
 
@@ -22,7 +22,9 @@ pub fn main(project: Project, opts: StartUserShellOptions_) -> OpResult {
     panic!("failed to exec into '{}': {}", opts.shell_path.display(), e);
 }
 
-fn shell_cmd(shell_path: &Path, cas: &ContentAddressable, tempdir: &Path) -> Command {
+/// The command to start the user shell at `shell_path` with a `(lorri)` prompt prefix.
+/// Init scripts are written to `cas` or `tempdir`.
+pub fn shell_cmd(shell_path: &Path, cas: &ContentAddressable, tempdir: &Path) -> Command {
     let mut cmd = Command::new(shell_path);
 
     match shell_path
@@ -83,8 +85,145 @@ PS1="(lorri) ${PS1}"
             }
             cmd.env("ZDOTDIR", tempdir);
         }
+        "fish" => {
+            // `--init-command` runs after the user’s configuration has been read. Some
+            // configurations (like NixOS’ /etc/fish/config.fish) reset PATH, so the
+            // project’s PATH is put back in front afterwards.
+            let init_file = tempdir.join("lorri.fish");
+            fs::write(
+                &init_file,
+                r#"
+if set -q LORRI_SHELL_PATH
+    set -l lorri_path (string split : -- $LORRI_SHELL_PATH)
+    for p in $PATH
+        contains -- $p $lorri_path; or set -a lorri_path $p
+    end
+    set -gx PATH $lorri_path
+    set -e LORRI_SHELL_PATH
+end
+
+if functions -q fish_prompt
+    functions -c fish_prompt __lorri_fish_prompt
+else
+    function __lorri_fish_prompt
+        echo -n '> '
+    end
+end
+function fish_prompt
+    echo -n '(lorri) '
+    __lorri_fish_prompt
+end
+"#,
+            )
+            .expect("failed to write fish init script");
+            if let Ok(path) = env::var("PATH") {
+                cmd.env("LORRI_SHELL_PATH", path);
+            }
+            cmd.arg("--init-command").arg(format!(
+                "source '{}'",
+                init_file.to_str().expect("file path not UTF-8 clean")
+            ));
+        }
+        "nu" => {
+            // `--config` replaces the user’s configuration, so we source it explicitly.
+            // `source` needs a path known when the script is parsed, so it is
+            // written into the script.
+            let user_config = nushell_config_dir().map(|dir| dir.join("config.nu"));
+            let source_user_config = match user_config {
+                Some(ref config) if config.is_file() => format!(
+                    "source '{}'\n",
+                    config.to_str().expect("file path not UTF-8 clean")
+                ),
+                _ => String::new(),
+            };
+            let config_file = tempdir.join("lorri.nu");
+            fs::write(
+                &config_file,
+                source_user_config
+                    + r#"
+let lorri_prompt = ($env.PROMPT_COMMAND? | default "")
+$env.PROMPT_COMMAND = {||
+    let prompt = if ($lorri_prompt | describe) == "closure" { do $lorri_prompt } else { $lorri_prompt }
+    "(lorri) " + $prompt
+}
+"#,
+            )
+            .expect("failed to write nushell init script");
+            cmd.arg("--config").arg(&config_file);
+        }
+        "xonsh" => {
+            // `--rc` replaces the default run control files, so we source them explicitly.
+            let rcfile = tempdir.join("lorri.xsh");
+            fs::write(
+                &rcfile,
+                r#"
+import os as __lorri_os
+for __lorri_rc in ["/etc/xonshrc", "~/.config/xonsh/rc.xsh", "~/.xonshrc"]:
+    __lorri_rc = __lorri_os.path.expanduser(__lorri_rc)
+    if __lorri_os.path.isfile(__lorri_rc):
+        source @(__lorri_rc)
+
+__lorri_prompt = $PROMPT
+if isinstance(__lorri_prompt, str):
+    $PROMPT = "(lorri) " + __lorri_prompt
+else:
+    $PROMPT = lambda: "(lorri) " + __lorri_prompt()
+del __lorri_os, __lorri_rc
+"#,
+            )
+            .expect("failed to write xonsh init script");
+            cmd.arg("--rc").arg(&rcfile);
+        }
+        "tcsh" => {
+            // Like zsh, tcsh has no option to read another init script, but it reads
+            // ~/.tcshrc. So we point $HOME to a directory under lorri's control, reset
+            // it from there and follow the default sourcing procedure.
+            fs::write(
+                tempdir.join(".tcshrc"),
+                r#"
+if ( $?LORRI_HOME_BEFORE ) then
+    setenv HOME "$LORRI_HOME_BEFORE"
+    unsetenv LORRI_HOME_BEFORE
+else
+    unsetenv HOME
+endif
+
+if ( $?HOME ) then
+    if ( -r "$HOME/.tcshrc" ) then
+        source "$HOME/.tcshrc"
+    else if ( -r "$HOME/.cshrc" ) then
+        source "$HOME/.cshrc"
+    endif
+endif
+
+if ( $?prompt ) then
+    set prompt = "(lorri) ${prompt:q}"
+endif
+"#,
+            )
+            .expect("failed to write tcsh init script");
+            if let Ok(home) = env::var("HOME") {
+                cmd.env("LORRI_HOME_BEFORE", home);
+            }
+            cmd.env("HOME", tempdir);
+        }
         // Add handling for other supported shells here.
         _ => {}
     }
     cmd
 }
+
+/// The directory nushell reads `config.nu` from by default.
+fn nushell_config_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("nushell")),
+        _ => {
+            let home = PathBuf::from(env::var_os("HOME")?);
+            Some(if cfg!(target_os = "macos") {
+                home.join("Library/Application Support/nushell")
+            } else {
+                home.join(".config/nushell")
+            })
+        }
+    }
+}
@@ -1,13 +1,16 @@
 //! Run to output a stream of build events in a machine-parseable form.
 use crate::build_loop::Event;
+use crate::daemon::EventFilter;
 use crate::ops::{
     error::{ok, ExitError, OpResult},
     get_paths,
 };
 use crate::proto;
+use crate::NixFile;
 use crossbeam_channel::{select, unbounded};
 use slog_scope::debug;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread;
 use std::time::Duration;
@@ -36,6 +39,35 @@ impl FromStr for EventKind {
     }
 }
 
+/// The path the daemon knows `nix_file` by: like `locate_file::in_cwd`
+/// finds it when run in its directory, the directory is canonical,
+/// but the file itself may be a symlink.
+fn resolve_nix_file(nix_file: &Path) -> Result<NixFile, ExitError> {
+    let not_found = || ExitError::user_error(format!("`{}` does not exist", nix_file.display()));
+    let name = nix_file.file_name().ok_or_else(not_found)?;
+    let dir = match nix_file.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let path = dir.canonicalize().map_err(|_| not_found())?.join(name);
+    if path.is_file() {
+        Ok(NixFile::from(path))
+    } else {
+        Err(not_found())
+    }
+}
+
+/// The canonical path of the directory `project_root`.
+fn resolve_project_root(project_root: &Path) -> Result<PathBuf, ExitError> {
+    match project_root.canonicalize() {
+        Ok(path) if path.is_dir() => Ok(path),
+        _ => Err(ExitError::user_error(format!(
+            "`{}` is not a directory",
+            project_root.display()
+        ))),
+    }
+}
+
 #[derive(Debug)]
 enum Error {
     Varlink(proto::Error),
@@ -44,12 +76,31 @@ enum Error {
 
 /// See the documentation for lorri::cli::Command::StreamEvents_ for more
 /// details.
-pub fn main(kind: EventKind) -> OpResult {
+pub fn main(opts: crate::cli::StreamEvents_) -> OpResult {
+    let kind = opts.kind;
+    let mut filter = proto::MonitorFilter::try_from(&EventFilter {
+        nix_files: opts
+            .nix_files
+            .iter()
+            .map(|f| resolve_nix_file(f))
+            .collect::<Result<_, _>>()?,
+        project_roots: opts
+            .project_roots
+            .iter()
+            .map(|r| resolve_project_root(r))
+            .collect::<Result<_, _>>()?,
+        kinds: opts.build_events,
+    })
+    .map_err(ExitError::user_error)?;
+    if opts.no_logs {
+        filter.logs = Some(false);
+    }
     let address = get_paths()?.daemon_socket_address();
 
     use proto::VarlinkClientInterface;
     let mut client = proto::VarlinkClient::new(
-        varlink::Connection::with_address(&address).expect("failed to connect to daemon server"),
+        crate::daemon::client::connect(&address)
+            .ok_or_else(|| ExitError::temporary("no compatible daemon is listening"))?,
     );
 
     let mut snapshot_done = false;
@@ -57,7 +108,11 @@ pub fn main(kind: EventKind) -> OpResult {
     let recycle = tx.clone();
 
     let th = thread::spawn(move || {
-        for res in client.monitor().more().expect("couldn't connect to server") {
+        for res in client
+            .monitor(Some(filter))
+            .more()
+            .expect("couldn't connect to server")
+        {
             tx.send(res).expect("local channel couldn't send")
         }
     });
@@ -82,6 +137,17 @@ pub fn main(kind: EventKind) -> OpResult {
                     snapshot_done = true
                 }
             }
+            Event::Shutdown => {
+                debug!("Shutdown");
+                if let EventKind::Snapshot = kind {
+                    return ok();
+                }
+                println!(
+                    "{}",
+                    serde_json::to_string(&Event::Shutdown).expect("couldn't serialize event")
+                );
+                return ok();
+            }
             ev => match (snapshot_done, &kind) {
                 (_, EventKind::All) | (false, EventKind::Snapshot) | (true, EventKind::Live) => {
                     println!(
@@ -97,3 +163,33 @@ pub fn main(kind: EventKind) -> OpResult {
     drop(th);
     ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_paths_are_resolved() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().canonicalize()?;
+        std::fs::create_dir(dir.join("project"))?;
+        std::fs::write(dir.join("project/default.nix"), "")?;
+        std::os::unix::fs::symlink("default.nix", dir.join("project/shell.nix"))?;
+        std::os::unix::fs::symlink("project", dir.join("link"))?;
+
+        assert_eq!(
+            resolve_nix_file(&dir.join("link/../project/shell.nix")).unwrap(),
+            NixFile::from(dir.join("project/shell.nix"))
+        );
+        assert_eq!(
+            resolve_project_root(&dir.join("link")).unwrap(),
+            dir.join("project")
+        );
+        assert!(resolve_nix_file(&dir.join("project/missing.nix")).is_err());
+        assert!(resolve_nix_file(&dir.join("missing/shell.nix")).is_err());
+        assert!(resolve_nix_file(&dir.join("project")).is_err());
+        assert!(resolve_project_root(&dir.join("missing")).is_err());
+        assert!(resolve_project_root(&dir.join("project/default.nix")).is_err());
+        Ok(())
+    }
+}
@@ -1,67 +1,289 @@
 //! Given a list of paths, reduce them to a minimum set of paths
 //! which should be watched for changes.
+//!
+//! Every path is passed through a list of `Reducer`s; the first one
+//! which has an opinion about the path decides whether it is kept,
+//! rewritten to other paths or removed from the watch list.
+//! Afterwards, paths below directories which are watched anyway are dropped.
 
+use crate::config::{Config, ReducerConfig};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
-#[derive(PartialEq, Debug)]
-enum PathReduction {
-    Reduced(PathBuf),
+/// What a `Reducer` decided to do with a path.
+#[derive(PartialEq, Debug, Clone)]
+pub enum PathReduction {
+    /// Watch these paths instead
+    Reduced(Vec<PathBuf>),
+    /// Don’t watch the path at all
     Remove,
 }
 
+/// The answer of a `Reducer` for a single path.
 #[derive(Debug)]
-enum ReductionOp {
+pub enum ReductionOp {
+    /// The reducer decided what to do with the path
     Reduction(PathReduction),
+    /// The reducer does not care about the path, the next reducer is asked
     NoOpinion,
 }
 
-impl PathReduction {
-    fn unwrap(self, msg: &'static str) -> PathBuf {
-        match self {
-            PathReduction::Reduced(p) => p,
-            _ => panic!(msg),
+/// Decides how a path referenced by a nix evaluation is watched.
+pub trait Reducer {
+    /// Short human-readable description, shown by `lorri internal explain-watches`.
+    fn name(&self) -> String;
+
+    /// Reduce `path`, or return `ReductionOp::NoOpinion`.
+    fn reduce(&self, path: &Path) -> ReductionOp;
+}
+
+/// An ordered list of reducers.
+pub struct Reducers(Vec<Box<dyn Reducer>>);
+
+/// How a single referenced path ended up in the watch list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    /// The referenced path
+    pub path: PathBuf,
+    /// The reducer which decided, `None` if no reducer had an opinion
+    pub reducer: Option<String>,
+    /// What happened to the path
+    pub decision: Decision,
+    /// Resulting paths which are not watched separately,
+    /// because they are below a watched directory (the second element).
+    pub covered: Vec<(PathBuf, PathBuf)>,
+}
+
+/// What happened to a referenced path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    /// The path is watched as-is
+    Kept,
+    /// The path was replaced by these paths
+    Rewritten(Vec<PathBuf>),
+    /// The path is not watched
+    Removed,
+}
+
+impl Explanation {
+    /// The paths resulting from the decision, before dropping covered paths.
+    fn results(&self) -> Vec<PathBuf> {
+        match &self.decision {
+            Decision::Kept => vec![self.path.clone()],
+            Decision::Rewritten(paths) => paths.clone(),
+            Decision::Removed => vec![],
         }
     }
 }
 
-/// Reduce one list of paths to another list of paths.
-pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
-    let mut reduced = paths
-        .iter()
-        .map(|path| {
-            let reducers = &[reduce_channel_path, reduce_nix_store_path];
+impl Reducers {
+    /// The reducers lorri always uses.
+    pub fn builtin() -> Reducers {
+        Reducers(vec![Box::new(ChannelReducer), Box::new(NixStoreReducer)])
+    }
 
-            for reducer in reducers {
-                match reducer(path) {
-                    ReductionOp::Reduction(r) => {
-                        return r;
-                    }
-                    ReductionOp::NoOpinion => {
-                        // next
-                    }
+    /// The reducers registered in `config`, followed by the builtin reducers.
+    pub fn from_config(config: &Config) -> Reducers {
+        let mut reducers: Vec<Box<dyn Reducer>> = config
+            .path_reducers
+            .iter()
+            .map(|r| -> Box<dyn Reducer> {
+                match r {
+                    ReducerConfig::GitCheckout { path } => Box::new(GitCheckoutReducer::new(path)),
+                    ReducerConfig::Ignore { path } => Box::new(IgnoreReducer {
+                        prefix: path.clone(),
+                    }),
                 }
-            }
+            })
+            .collect();
+        reducers.extend(Reducers::builtin().0);
+        Reducers(reducers)
+    }
 
-            // Default: return a noop reduction
-            PathReduction::Reduced(path.clone())
-        })
-        .filter(|reduction| reduction != &PathReduction::Remove)
-        .map(|reduction| reduction.unwrap("previous filter got them"))
-        .collect::<Vec<PathBuf>>();
-
-    // Sort by length so we automatically select project roots when
-    // possible, in the next fold.
-    reduced.sort();
-    reduced.dedup();
-    reduced
-        .into_iter()
-        .fold::<HashSet<PathBuf>, _>(HashSet::new(), |mut set, new_path| {
-            if !set.iter().any(|path| new_path.starts_with(path)) {
-                set.insert(new_path);
+    /// Reduce one list of paths to another list of paths.
+    pub fn reduce(&self, paths: &[PathBuf]) -> HashSet<PathBuf> {
+        let explanations = self.decide(paths);
+        Self::collapse(&explanations)
+    }
+
+    /// Reduce directories which are watched without their subdirectories
+    /// (see `Watch::extend_shallow`). Returns the directories which are kept,
+    /// and the paths the others were rewritten to, which are watched
+    /// like the paths passed to `reduce`.
+    pub fn reduce_shallow(&self, directories: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut kept = vec![];
+        let mut rewritten = vec![];
+        for explanation in self.decide(directories) {
+            match explanation.decision {
+                Decision::Kept => kept.push(explanation.path),
+                Decision::Rewritten(paths) => rewritten.extend(paths),
+                Decision::Removed => {}
             }
-            set
-        })
+        }
+        (kept, rewritten)
+    }
+
+    /// Explain for every path in `paths` what happens to it.
+    pub fn explain(&self, paths: &[PathBuf]) -> Vec<Explanation> {
+        let mut explanations = self.decide(paths);
+        let watched = Self::collapse(&explanations);
+        for explanation in explanations.iter_mut() {
+            explanation.covered = explanation
+                .results()
+                .into_iter()
+                .filter(|p| !watched.contains(p))
+                .filter_map(|p| {
+                    let by = watched.iter().find(|w| p.starts_with(w))?.clone();
+                    Some((p, by))
+                })
+                .collect();
+        }
+        explanations
+    }
+
+    fn decide(&self, paths: &[PathBuf]) -> Vec<Explanation> {
+        paths
+            .iter()
+            .map(|path| {
+                for reducer in &self.0 {
+                    if let ReductionOp::Reduction(r) = reducer.reduce(path) {
+                        return Explanation {
+                            path: path.clone(),
+                            reducer: Some(reducer.name()),
+                            decision: match r {
+                                PathReduction::Reduced(ref p) if p.len() == 1 && &p[0] == path => {
+                                    Decision::Kept
+                                }
+                                PathReduction::Reduced(p) => Decision::Rewritten(p),
+                                PathReduction::Remove => Decision::Removed,
+                            },
+                            covered: vec![],
+                        };
+                    }
+                }
+
+                // Default: keep the path
+                Explanation {
+                    path: path.clone(),
+                    reducer: None,
+                    decision: Decision::Kept,
+                    covered: vec![],
+                }
+            })
+            .collect()
+    }
+
+    /// Drop all paths that are below another path we already watch.
+    /// The watcher walks directories recursively, so this only
+    /// applies to directories (which still exist).
+    fn collapse(explanations: &[Explanation]) -> HashSet<PathBuf> {
+        let mut reduced = explanations
+            .iter()
+            .flat_map(|e| e.results())
+            .collect::<Vec<PathBuf>>();
+
+        // Sort so that we automatically select project roots when
+        // possible, in the next fold (parents sort before their children).
+        reduced.sort();
+        reduced.dedup();
+        reduced
+            .into_iter()
+            .fold::<HashSet<PathBuf>, _>(HashSet::new(), |mut set, new_path| {
+                if !set
+                    .iter()
+                    .any(|path| new_path.starts_with(path) && path.is_dir())
+                {
+                    set.insert(new_path);
+                }
+                set
+            })
+    }
+}
+
+/// Reduce one list of paths to another list of paths,
+/// with the builtin reducers.
+pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
+    Reducers::builtin().reduce(paths)
+}
+
+/// See `reduce_channel_path`.
+struct ChannelReducer;
+
+impl Reducer for ChannelReducer {
+    fn name(&self) -> String {
+        "channel".to_string()
+    }
+
+    fn reduce(&self, path: &Path) -> ReductionOp {
+        reduce_channel_path(path)
+    }
+}
+
+/// See `reduce_nix_store_path`.
+struct NixStoreReducer;
+
+impl Reducer for NixStoreReducer {
+    fn name(&self) -> String {
+        "nix store".to_string()
+    }
+
+    fn reduce(&self, path: &Path) -> ReductionOp {
+        reduce_nix_store_path(path)
+    }
+}
+
+/// Reduce all paths in a git checkout to the refs of the checkout
+/// (see `git::ref_paths`), so that the checkout is rebuilt when a
+/// different commit is checked out, but not when working tree files change.
+struct GitCheckoutReducer {
+    root: PathBuf,
+    /// The refs of the checkout, looked up once for all the paths below it
+    /// (thousands for a nixpkgs checkout). `None` if it is not a git checkout.
+    refs: Option<Vec<PathBuf>>,
+}
+
+impl GitCheckoutReducer {
+    fn new(root: &Path) -> GitCheckoutReducer {
+        GitCheckoutReducer {
+            root: root.to_owned(),
+            refs: crate::git::ref_paths(root),
+        }
+    }
+}
+
+impl Reducer for GitCheckoutReducer {
+    fn name(&self) -> String {
+        format!("git checkout {}", self.root.display())
+    }
+
+    fn reduce(&self, path: &Path) -> ReductionOp {
+        if !path.starts_with(&self.root) {
+            return ReductionOp::NoOpinion;
+        }
+        match &self.refs {
+            Some(refs) => ReductionOp::Reduction(PathReduction::Reduced(refs.clone())),
+            None => ReductionOp::NoOpinion,
+        }
+    }
+}
+
+/// Remove all paths below a prefix.
+struct IgnoreReducer {
+    prefix: PathBuf,
+}
+
+impl Reducer for IgnoreReducer {
+    fn name(&self) -> String {
+        format!("ignore {}", self.prefix.display())
+    }
+
+    fn reduce(&self, path: &Path) -> ReductionOp {
+        if path.starts_with(&self.prefix) {
+            ReductionOp::Reduction(PathReduction::Remove)
+        } else {
+            ReductionOp::NoOpinion
+        }
+    }
 }
 
 /// Reduce a path coming from a user's channel to the location where
@@ -105,7 +327,7 @@ pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
 ///    (C) it never changes.
 ///
 /// (E) Sub-path to exactly what file was looked at.
-fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
+fn reduce_channel_path(path: &Path) -> ReductionOp {
     let nix_profile = Path::new("/nix/var/nix/profiles/per-user");
 
     // example path: /nix/var/nix/profiles/per-user/root/channels/nixos/....
@@ -127,15 +349,18 @@ fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
     // Check to see that the channel's root canonicalizes to the same
     // root the full path resolves to. If so, simplify to
     // the directory containing the swapped channel symlink.
-    let canonical_channel_location = channel_root_path.canonicalize().unwrap();
-    let canonical_path_location = path.canonicalize().unwrap();
-    if canonical_path_location.starts_with(&canonical_channel_location) {
+    let canonical_channel_location = channel_root_path.canonicalize();
+    let canonical_path_location = path.canonicalize();
+    if let (Ok(channel), Ok(path)) = (canonical_channel_location, canonical_path_location) {
+        if !path.starts_with(&channel) {
+            return ReductionOp::NoOpinion;
+        }
         let reduce_to = channel_root_path
             .parent()
             .expect("expected /nix/var/nix/profiles/per-user/root/channels")
             .parent()
             .expect("expected /nix/var/nix/profiles/per-user/root");
-        ReductionOp::Reduction(PathReduction::Reduced(reduce_to.to_path_buf()))
+        ReductionOp::Reduction(PathReduction::Reduced(vec![reduce_to.to_path_buf()]))
     } else {
         ReductionOp::NoOpinion
     }
@@ -147,7 +372,7 @@ fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
 ///
 /// Note that because store paths are immutable, these paths can
 /// be discarded.
-fn reduce_nix_store_path(path: &PathBuf) -> ReductionOp {
+fn reduce_nix_store_path(path: &Path) -> ReductionOp {
     let nix_store = Path::new("/nix/store");
 
     // This is only a valid reduction if the Nix store path
@@ -168,3 +393,103 @@ fn reduce_nix_store_path(path: &PathBuf) -> ReductionOp {
 
     ReductionOp::NoOpinion
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(path_reducers: Vec<ReducerConfig>) -> Config {
+        Config {
+            path_reducers,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn files_below_watched_directories_are_covered() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().join("src");
+        std::fs::create_dir(&dir)?;
+        let file = dir.join("main.rs");
+        std::fs::write(&file, "")?;
+
+        let explanations = Reducers::builtin().explain(&[dir.clone(), file.clone()]);
+        assert_eq!(explanations[0].covered, vec![]);
+        assert_eq!(explanations[1].covered, vec![(file.clone(), dir.clone())]);
+        assert_eq!(
+            Reducers::builtin().reduce(&[dir.clone(), file]),
+            vec![dir].into_iter().collect()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn files_below_missing_directories_are_kept() {
+        let dir = PathBuf::from("/this/directory/does/not/exist");
+        let file = dir.join("file");
+        assert_eq!(
+            Reducers::builtin().reduce(&[dir.clone(), file.clone()]),
+            vec![dir, file].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn ignore_reducer() {
+        let reducers = Reducers::from_config(&config(vec![ReducerConfig::Ignore {
+            path: PathBuf::from("/data"),
+        }]));
+        let explanations = reducers.explain(&[
+            PathBuf::from("/data/big.json"),
+            PathBuf::from("/project/shell.nix"),
+        ]);
+        assert_eq!(explanations[0].decision, Decision::Removed);
+        assert_eq!(explanations[0].reducer, Some("ignore /data".to_string()));
+        assert_eq!(explanations[1].decision, Decision::Kept);
+        assert_eq!(explanations[1].reducer, None);
+    }
+
+    #[test]
+    fn shallow_directories_are_not_collapsed() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path().join("repo");
+        std::fs::create_dir_all(repo.join("src"))?;
+        let reducers = Reducers::from_config(&config(vec![ReducerConfig::Ignore {
+            path: repo.join("vendor"),
+        }]));
+
+        // `repo/src` is not covered by `repo`, which is not watched recursively
+        assert_eq!(
+            reducers.reduce_shallow(&[repo.clone(), repo.join("src"), repo.join("vendor")]),
+            (vec![repo.clone(), repo.join("src")], vec![])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn git_checkout_reducer() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let checkout = tmp.path().join("nixpkgs");
+        std::fs::create_dir_all(checkout.join(".git/refs/heads"))?;
+        std::fs::write(checkout.join(".git/HEAD"), "ref: refs/heads/master\n")?;
+
+        let reducers = Reducers::from_config(&config(vec![ReducerConfig::GitCheckout {
+            path: checkout.clone(),
+        }]));
+        let refs = vec![checkout.join(".git/HEAD"), checkout.join(".git/refs/heads")];
+        let explanations = reducers.explain(&[
+            checkout.join("default.nix"),
+            checkout.join("pkgs/top-level/all-packages.nix"),
+        ]);
+        for explanation in explanations {
+            assert_eq!(explanation.decision, Decision::Rewritten(refs.clone()));
+        }
+
+        // the refs are looked up once, when the reducers are created
+        std::fs::remove_dir_all(checkout.join(".git"))?;
+        assert_eq!(
+            reducers.explain(&[checkout.join("default.nix")])[0].decision,
+            Decision::Rewritten(refs)
+        );
+        Ok(())
+    }
+}
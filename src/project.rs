@@ -1,8 +1,10 @@
 //! Wrap a nix file and manage corresponding state.
 
+pub mod diagnostics;
 pub mod roots;
 
 use crate::cas::ContentAddressable;
+use crate::config::Config;
 use crate::NixFile;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
@@ -23,6 +25,9 @@ pub struct Project {
 
     /// Content-addressable store to save static files in
     pub cas: ContentAddressable,
+
+    /// The user configuration
+    pub config: Config,
 }
 
 impl Project {
@@ -33,6 +38,7 @@ impl Project {
         nix_file: NixFile,
         gc_root_dir: &Path,
         cas: ContentAddressable,
+        config: Config,
     ) -> std::io::Result<Project> {
         let hash = format!(
             "{:x}",
@@ -47,6 +53,7 @@ impl Project {
             gc_root_path: project_gc_root,
             hash,
             cas,
+            config,
         })
     }
 
@@ -55,3 +62,29 @@ impl Project {
         &self.hash
     }
 }
+
+/// Atomically write `value` as JSON to one of the project’s state files.
+pub(crate) fn write_json<T: serde::Serialize + ?Sized>(
+    file: &Path,
+    value: &T,
+) -> std::io::Result<()> {
+    use atomicwrites::{AtomicFile, OverwriteBehavior};
+    use std::io::Write;
+    AtomicFile::new(file, OverwriteBehavior::AllowOverwrite)
+        .write(|f| {
+            serde_json::to_writer(&mut *f, value)?;
+            f.write_all(b"\n")
+        })
+        .map_err(std::io::Error::from)
+}
+
+/// Read one of the project’s JSON state files.
+/// `None` if the file does not exist.
+/// A file that cannot be parsed (e.g. from an older lorri version) is ignored as well.
+pub(crate) fn read_json<T: serde::de::DeserializeOwned>(file: &Path) -> std::io::Result<Option<T>> {
+    match std::fs::read(file) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+        Ok(contents) => Ok(serde_json::from_slice(&contents).ok()),
+    }
+}
@@ -0,0 +1,118 @@
+//! Records why a project was rebuilt, for `lorri internal explain-rebuild`.
+//!
+//! The build loop writes the list of watched paths after every evaluation
+//! and appends each rebuild trigger to a bounded history.
+//! Both live in the project’s state directory next to its GC roots.
+
+use crate::project::{read_json, write_json, Project};
+use crate::watch::Reason;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Only this many triggers are kept in the history.
+const MAX_TRIGGERS: usize = 50;
+
+/// Diagnostics files of a project.
+pub struct Diagnostics {
+    watched_file: PathBuf,
+    triggers_file: PathBuf,
+}
+
+/// A single reason the build loop started a build.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trigger {
+    /// Seconds since the UNIX epoch
+    pub time: u64,
+    /// Why the build was started
+    pub reason: Reason,
+    /// The kind of the filesystem event (as reported by the watcher),
+    /// if the build was started because of a filesystem event.
+    pub event_kind: Option<String>,
+}
+
+impl Trigger {
+    /// A trigger that happened just now.
+    pub fn now(reason: Reason, event_kind: Option<String>) -> Trigger {
+        Trigger {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            reason,
+            event_kind,
+        }
+    }
+}
+
+impl Diagnostics {
+    /// The diagnostics of `project`.
+    pub fn from_project(project: &Project) -> Diagnostics {
+        let dir = project
+            .gc_root_path
+            .parent()
+            .expect("project gc root path must be in a project directory");
+        Diagnostics {
+            watched_file: dir.join("watched.json"),
+            triggers_file: dir.join("triggers.json"),
+        }
+    }
+
+    /// Replace the list of watched paths.
+    pub fn record_watched(&self, watched: &HashSet<PathBuf>) -> std::io::Result<()> {
+        let mut watched = watched.iter().collect::<Vec<_>>();
+        watched.sort();
+        write_json(&self.watched_file, &watched)
+    }
+
+    /// The list of watched paths, as recorded by the last evaluation.
+    pub fn watched(&self) -> std::io::Result<Option<Vec<PathBuf>>> {
+        read_json(&self.watched_file)
+    }
+
+    /// Add a trigger to the history, dropping the oldest one if the history is full.
+    pub fn record_trigger(&self, trigger: Trigger) -> std::io::Result<()> {
+        let mut triggers = self.triggers()?;
+        triggers.push(trigger);
+        let drop = triggers.len().saturating_sub(MAX_TRIGGERS);
+        write_json(&self.triggers_file, &triggers[drop..])
+    }
+
+    /// The recorded triggers, oldest first.
+    pub fn triggers(&self) -> std::io::Result<Vec<Trigger>> {
+        Ok(read_json(&self.triggers_file)?.unwrap_or_else(Vec::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::ContentAddressable;
+    use crate::config::Config;
+    use crate::NixFile;
+
+    #[test]
+    fn trigger_history_is_bounded() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let project = Project::new(
+            NixFile::from(tmp.path().join("shell.nix")),
+            &tmp.path().join("gc_roots"),
+            ContentAddressable::new(tmp.path().join("cas"))?,
+            Config::default(),
+        )?;
+        let diagnostics = Diagnostics::from_project(&project);
+        assert!(diagnostics.triggers()?.is_empty());
+
+        for i in 0..MAX_TRIGGERS + 5 {
+            diagnostics.record_trigger(Trigger {
+                time: i as u64,
+                reason: Reason::PingReceived,
+                event_kind: None,
+            })?;
+        }
+        let triggers = diagnostics.triggers()?;
+        assert_eq!(triggers.len(), MAX_TRIGGERS);
+        assert_eq!(triggers[0].time, 5);
+        Ok(())
+    }
+}
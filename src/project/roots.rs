@@ -1,13 +1,17 @@
 //! Handling of nix GC roots
 //!
 //! TODO: inline this module into `::project`
-use crate::builder::{OutputPaths, RootedPath};
+use crate::builder::{OutputPaths, RootedDrv, RootedPath};
+use crate::error::BuildError;
 use crate::nix::StorePath;
-use crate::project::Project;
-use slog_scope::debug;
+use crate::project::{read_json, write_json, Project};
+use slog_scope::{debug, warn};
+use std::collections::HashSet;
 use std::env;
 use std::fmt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Roots manipulation
 #[derive(Clone)]
@@ -15,6 +19,12 @@ pub struct Roots {
     /// The GC root directory in the lorri user cache dir
     gc_root_path: PathBuf,
     id: String,
+    /// How many generations to keep
+    keep_generations: usize,
+    /// Whether the derivation and its inputs are rooted as well
+    keep_outputs: bool,
+    /// The configured directory for the reverse GC roots, if any
+    gc_roots_dir: Option<PathBuf>,
 }
 
 /// A path to a gc root.
@@ -45,6 +55,152 @@ impl OutputPaths<RootPath> {
     }
 }
 
+/// Which build the current roots come from, and whether
+/// any build after it failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RootStatus {
+    /// The build that produced the current roots
+    pub built: Option<BuildRecord>,
+    /// The newest build, if it failed after the roots were created
+    pub failed: Option<BuildRecord>,
+}
+
+/// A build of the project.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildRecord {
+    /// Seconds since the UNIX epoch
+    pub time: u64,
+    /// The store path of a successful build, the error summary of a failed one
+    pub summary: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl BuildRecord {
+    fn now(summary: String) -> BuildRecord {
+        BuildRecord {
+            time: now(),
+            summary,
+        }
+    }
+}
+
+/// The environments of past successful builds, like the generations
+/// of a nix profile. Each generation has its own GC root, so it
+/// stays available for `lorri rollback`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Generations {
+    /// The generation `shell_gc_root` points to
+    pub active: Option<u32>,
+    /// All kept generations, oldest first
+    pub generations: Vec<Generation>,
+}
+
+/// A single environment generation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Generation {
+    /// Increases with every successful build
+    pub number: u32,
+    /// When the generation was built, in seconds since the UNIX epoch
+    pub time: u64,
+    /// The environment’s store path
+    pub store_path: PathBuf,
+}
+
+impl Generations {
+    /// Add a new generation and make it the active one.
+    /// Returns the generations that have to be dropped to
+    /// keep at most `keep` of them, or `None` if `store_path`
+    /// is the active generation already, which is kept as is.
+    fn push(&mut self, store_path: PathBuf, time: u64, keep: usize) -> Option<Vec<Generation>> {
+        if self.active_generation().map(|g| &g.store_path) == Some(&store_path) {
+            return None;
+        }
+        let number = self.generations.last().map_or(1, |g| g.number + 1);
+        self.generations.push(Generation {
+            number,
+            time,
+            store_path,
+        });
+        self.active = Some(number);
+        // the new generation is always kept
+        let drop = self
+            .generations
+            .len()
+            .saturating_sub(std::cmp::max(keep, 1));
+        Some(self.generations.drain(..drop).collect())
+    }
+
+    /// The generation `shell_gc_root` points to.
+    fn active_generation(&self) -> Option<&Generation> {
+        let active = self.active?;
+        self.generations.iter().find(|g| g.number == active)
+    }
+
+    /// The generation to roll back to: generation `number`, or the
+    /// one before the active generation.
+    fn rollback_target(&self, number: Option<u32>) -> Result<&Generation, RollbackError> {
+        match number {
+            Some(n) => self
+                .generations
+                .iter()
+                .find(|g| g.number == n)
+                .ok_or(RollbackError::NoSuchGeneration(n)),
+            None => {
+                let active = self.active.ok_or(RollbackError::NoPreviousGeneration)?;
+                self.generations
+                    .iter()
+                    .rev()
+                    .find(|g| g.number < active)
+                    .ok_or(RollbackError::NoPreviousGeneration)
+            }
+        }
+    }
+}
+
+/// Rolling back to an older generation failed.
+#[derive(Debug)]
+pub enum RollbackError {
+    /// The requested generation does not exist (any more)
+    NoSuchGeneration(u32),
+    /// There is no generation older than the active one
+    NoPreviousGeneration,
+    /// The generation’s store path was garbage collected
+    Collected(PathBuf),
+    /// Reading the generations or re-rooting failed
+    Roots(AddRootError),
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RollbackError::NoSuchGeneration(n) => write!(f, "generation {} does not exist", n),
+            RollbackError::NoPreviousGeneration => {
+                write!(f, "there is no generation older than the active one")
+            }
+            RollbackError::Collected(p) => write!(
+                f,
+                "the environment {} has been garbage collected",
+                p.display()
+            ),
+            RollbackError::Roots(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl RootStatus {
+    /// The roots exist, but the environment they point to is
+    /// older than the last build, which failed.
+    pub fn is_stale(&self) -> bool {
+        self.built.is_some() && self.failed.is_some()
+    }
+}
+
 /// Proxy through the `Display` class for `PathBuf`.
 impl std::fmt::Display for RootPath {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -60,6 +216,12 @@ impl Roots {
         Roots {
             gc_root_path: project.gc_root_path.to_path_buf(),
             id: project.hash().to_string(),
+            keep_generations: project.config.generations.keep,
+            keep_outputs: project
+                .config
+                .gc_roots
+                .keeps_outputs(project.nix_file.as_path()),
+            gc_roots_dir: project.config.gc_roots.directory.clone(),
         }
     }
 
@@ -71,63 +233,429 @@ impl Roots {
     }
 
     /// Create roots to store paths.
+    /// If the project keeps its outputs, `drv` and the outputs
+    /// it was built from are rooted as well.
     pub fn create_roots(
         &self,
-        // Important: this intentionally only allows creating
-        // roots to `StorePath`, not to `DrvFile`, because we have
-        // no use case for creating GC roots for drv files.
         path: RootedPath,
+        drv: &RootedDrv,
     ) -> Result<OutputPaths<RootPath>, AddRootError>
 where {
-        Ok(OutputPaths {
+        let _lock = self
+            .lock()
+            .map_err(|e| AddRootError::lock(e, &self.lock_file()))?;
+        let mut generations = self.generations().map_err(|e| {
+            AddRootError::Io(
+                e,
+                format!("Failed to read {}", self.generations_file().display()),
+            )
+        })?;
+        let dropped =
+            match generations.push(path.path.as_path().to_owned(), now(), self.keep_generations) {
+                Some(dropped) => {
+                    let number = generations.active.expect("a generation was just added");
+                    self.add(&generation_root_name(number), &path.path)?;
+                    dropped
+                }
+                // the environment did not change, so neither do the generations
+                None => vec![],
+            };
+        let paths = OutputPaths {
             shell_gc_root: self.add("shell_gc_root", &path.path)?,
+        };
+        self.write_state(
+            &generations,
+            RootStatus {
+                built: Some(BuildRecord::now(path.path.as_path().display().to_string())),
+                failed: None,
+            },
+        )?;
+        for generation in dropped {
+            self.remove(&generation_root_name(generation.number))?;
+        }
+        if self.keep_outputs {
+            self.add(
+                DRV_ROOT_NAME,
+                &StorePath::from(drv.path.as_path().as_os_str()),
+            )?;
+            match realized_inputs(drv) {
+                Ok(inputs) => self.sync_inputs(&inputs)?,
+                // the previous inputs stay rooted until the next build
+                Err(e) => warn!("could not query the inputs of the environment"; "error" => %e),
+            }
+        } else {
+            self.remove(DRV_ROOT_NAME)?;
+            self.remove_inputs()?;
+        }
+        Ok(paths)
+    }
+
+    /// Root exactly the store paths `inputs` in the inputs directory.
+    fn sync_inputs(&self, inputs: &[StorePath]) -> Result<(), AddRootError> {
+        let dir = self.gc_root_path.join(INPUTS_ROOT_NAME);
+        let user_dir = self.user_root(INPUTS_ROOT_NAME)?;
+        for d in std::iter::once(&dir).chain(&user_dir) {
+            std::fs::create_dir_all(d).map_err(|e| AddRootError::create_dir_all(e, d))?;
+        }
+        // add the new roots before removing the old ones,
+        // so inputs shared by both builds are never unrooted
+        let mut wanted = HashSet::new();
+        for input in inputs {
+            let name = match input.as_path().file_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let link = dir.join(&name);
+            match &user_dir {
+                Some(user_dir) => {
+                    symlink_atomically(input.as_path(), &link)?;
+                    symlink_atomically(&link, &user_dir.join(&name))?;
+                }
+                // nix remembers the link for as long as it exists
+                None if link.exists() => symlink_atomically(input.as_path(), &link)?,
+                None => register_root(input, &link)?,
+            }
+            wanted.insert(name);
+        }
+        for d in std::iter::once(&dir).chain(&user_dir) {
+            let entries = std::fs::read_dir(d)
+                .map_err(|e| AddRootError::Io(e, format!("Failed to read {}", d.display())))?;
+            for entry in entries.filter_map(Result::ok) {
+                if !wanted.contains(&entry.file_name()) {
+                    let path = entry.path();
+                    std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the inputs directory and its reverse GC roots.
+    fn remove_inputs(&self) -> Result<(), AddRootError> {
+        let dir = self.gc_root_path.join(INPUTS_ROOT_NAME);
+        let user_dir = self.user_root(INPUTS_ROOT_NAME)?;
+        for dir in std::iter::once(&dir).chain(&user_dir) {
+            if dir.is_dir() {
+                debug!("removing roots"; "path" => dir.to_str());
+                std::fs::remove_dir_all(dir).or_else(|e| AddRootError::remove(e, dir))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the derivation and its inputs are rooted as well.
+    pub fn keeps_outputs(&self) -> bool {
+        self.keep_outputs
+    }
+
+    /// The store paths this project currently roots: the environments
+    /// of all generations and, if outputs are kept, the derivation and its inputs.
+    pub fn pinned(&self) -> Vec<PathBuf> {
+        let links = |dir: &Path| -> Vec<PathBuf> {
+            std::fs::read_dir(dir)
+                .into_iter()
+                .flat_map(|entries| entries.filter_map(Result::ok))
+                .filter_map(|entry| std::fs::read_link(entry.path()).ok())
+                .filter(|target| target.exists())
+                .collect()
+        };
+        let mut paths = links(&self.gc_root_path);
+        paths.extend(links(&self.gc_root_path.join(INPUTS_ROOT_NAME)));
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Point `shell_gc_root` at an older generation: generation `number`
+    /// or, if not given, the one before the active generation.
+    /// The next successful build creates a new generation and activates it.
+    pub fn rollback(&self, number: Option<u32>) -> Result<Generation, RollbackError> {
+        let _lock = self
+            .lock()
+            .map_err(|e| RollbackError::Roots(AddRootError::lock(e, &self.lock_file())))?;
+        let mut generations = self.generations().map_err(|e| {
+            RollbackError::Roots(AddRootError::Io(
+                e,
+                format!("Failed to read {}", self.generations_file().display()),
+            ))
+        })?;
+        let target = generations.rollback_target(number)?.clone();
+        if !target.store_path.exists() {
+            return Err(RollbackError::Collected(target.store_path));
+        }
+        self.add(
+            "shell_gc_root",
+            &StorePath::from(target.store_path.as_os_str()),
+        )
+        .map_err(RollbackError::Roots)?;
+        generations.active = Some(target.number);
+        self.write_state(
+            &generations,
+            RootStatus {
+                built: Some(BuildRecord {
+                    time: target.time,
+                    summary: target.store_path.display().to_string(),
+                }),
+                failed: None,
+            },
+        )
+        .map_err(RollbackError::Roots)?;
+        Ok(target)
+    }
+
+    /// The kept generations of this project.
+    pub fn generations(&self) -> std::io::Result<Generations> {
+        Ok(read_json(&self.generations_file())?.unwrap_or_default())
+    }
+
+    fn generations_file(&self) -> PathBuf {
+        self.gc_root_path.join("generations.json")
+    }
+
+    fn write_state(
+        &self,
+        generations: &Generations,
+        status: RootStatus,
+    ) -> Result<(), AddRootError> {
+        write_json(&self.generations_file(), generations).map_err(|e| {
+            AddRootError::Io(
+                e,
+                format!("Failed to write {}", self.generations_file().display()),
+            )
+        })?;
+        write_json(&self.status_file(), &status).map_err(|e| {
+            AddRootError::Io(
+                e,
+                format!("Failed to write {}", self.status_file().display()),
+            )
         })
     }
 
+    /// Remember that a build failed after the current roots were created.
+    pub fn record_failure(&self, failure: &BuildError) -> std::io::Result<()> {
+        let _lock = self.lock()?;
+        let mut status = self.status()?;
+        status.failed = Some(BuildRecord::now(failure.summary()));
+        write_json(&self.status_file(), &status)
+    }
+
+    /// Which build the current roots come from.
+    pub fn status(&self) -> std::io::Result<RootStatus> {
+        Ok(read_json(&self.status_file())?.unwrap_or_default())
+    }
+
+    /// The file recording which build the current roots come from,
+    /// see `status`. It changes whenever a build finishes, even a failed one.
+    pub fn status_file(&self) -> PathBuf {
+        self.gc_root_path.join("status.json")
+    }
+
+    /// Lock the roots of this project against changes by other processes
+    /// (like the daemon and `lorri shell` building the same project).
+    /// Blocks until the lock is free.
+    fn lock(&self) -> std::io::Result<RootsLock> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.lock_file())?;
+        nix::fcntl::flock(file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive)
+            // flock only fails with an errno
+            .map_err(|e| std::io::Error::from(e.as_errno().unwrap_or(nix::errno::Errno::EINVAL)))?;
+        Ok(RootsLock { _file: file })
+    }
+
+    fn lock_file(&self) -> PathBuf {
+        self.gc_root_path.join("lock")
+    }
+
     /// Store a new root under name
     fn add(&self, name: &str, store_path: &StorePath) -> Result<RootPath, AddRootError> {
         // final path in the `self.gc_root_path` directory
         let mut path = self.gc_root_path.clone();
         path.push(name);
 
-        debug!("adding root"; "from" => store_path.as_path().to_str(), "to" => path.to_str());
-        std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
+        match self.user_root(name)? {
+            Some(root) => {
+                debug!("adding root"; "from" => store_path.as_path().to_str(), "to" => path.to_str());
+                // the forward GC root that points from the store path to our cache gc_roots dir
+                symlink_atomically(store_path.as_path(), &path)?;
 
+                // the reverse GC root that points from nix to our cache gc_roots dir
+                debug!("connecting root"; "from" => path.to_str(), "to" => root.to_str());
+                symlink_atomically(&path, &root)?;
+            }
+            None => {
+                register_root(store_path, &path)?;
+            }
+        }
+
+        // TODO: don’t return the RootPath here
+        Ok(RootPath(path))
+    }
+
+    /// Remove the root `name` and its reverse GC root.
+    fn remove(&self, name: &str) -> Result<(), AddRootError> {
+        let path = self.gc_root_path.join(name);
+        debug!("removing root"; "path" => path.to_str());
         std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
+        match self.user_root(name)? {
+            Some(root) => std::fs::remove_file(&root).or_else(|e| AddRootError::remove(e, &root)),
+            // nix drops its reference to the missing link by itself
+            None => Ok(()),
+        }
+    }
 
-        // the forward GC root that points from the store path to our cache gc_roots dir
-        std::os::unix::fs::symlink(store_path.as_path(), &path)
-            .map_err(|e| AddRootError::symlink(e, store_path.as_path(), &path))?;
+    /// The path of the reverse GC root for the root `name`,
+    /// or `None` if the roots have to be registered with nix instead.
+    fn user_root(&self, name: &str) -> Result<Option<PathBuf>, AddRootError> {
+        Ok(match gc_roots_location(self.gc_roots_dir.as_ref())? {
+            GcRootsLocation::Directory(dir) => Some(dir.join(format!("{}-{}", self.id, name))),
+            GcRootsLocation::NixStore { .. } => None,
+        })
+    }
+}
+
+/// Where the reverse GC roots, which make nix follow the roots
+/// in the lorri cache directory, are created.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GcRootsLocation {
+    /// Symlinks in a directory nix searches for GC roots
+    Directory(PathBuf),
+    /// The per-user GC roots directory is not writable, so the roots are
+    /// registered with `nix-store --add-root --indirect` instead
+    NixStore {
+        /// The per-user GC roots directory that could not be used
+        per_user_dir: PathBuf,
+    },
+}
 
-        // the reverse GC root that points from nix to our cache gc_roots dir
-        let mut root = if let Ok(path) = env::var("NIX_STATE_DIR") {
-            PathBuf::from(path)
+/// Where the reverse GC roots are created: in `configured` if given,
+/// otherwise in the per-user GC roots directory of nix if it is writable.
+pub fn gc_roots_location(configured: Option<&PathBuf>) -> Result<GcRootsLocation, AddRootError> {
+    if let Some(dir) = configured {
+        std::fs::create_dir_all(dir).map_err(|e| AddRootError::create_dir_all(e, dir))?;
+        return if is_writable(dir) {
+            Ok(GcRootsLocation::Directory(dir.to_owned()))
         } else {
-            PathBuf::from("/nix/var/nix/")
+            Err(AddRootError::NotWritable(dir.to_owned()))
         };
-        root.push("gcroots");
-        root.push("per-user");
+    }
+    let per_user_dir = per_user_gc_roots_dir();
+    // The user directory sometimes doesn’t exist,
+    // but we can usually create it (`per-user` is root but `rwxrwxrwt`)
+    if std::fs::create_dir_all(&per_user_dir).is_ok() && is_writable(&per_user_dir) {
+        Ok(GcRootsLocation::Directory(per_user_dir))
+    } else {
+        Ok(GcRootsLocation::NixStore { per_user_dir })
+    }
+}
 
-        // TODO: check on start of lorri
-        root.push(env::var("USER").expect("env var 'USER' must be set"));
+/// The per-user GC roots directory of nix.
+pub fn per_user_gc_roots_dir() -> PathBuf {
+    let mut root = if let Ok(path) = env::var("NIX_STATE_DIR") {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from("/nix/var/nix/")
+    };
+    root.push("gcroots");
+    root.push("per-user");
+    // nix searches the whole `gcroots` directory, so the name
+    // only has to be unique, it doesn’t have to match the user name
+    root.push(
+        env::var("USER")
+            .or_else(|_| env::var("LOGNAME"))
+            .unwrap_or_else(|_| format!("uid-{}", nix::unistd::getuid())),
+    );
+    root
+}
 
-        // The user directory sometimes doesn’t exist,
-        // but we can create it (it’s root but `rwxrwxrwx`)
-        if !root.is_dir() {
-            std::fs::create_dir_all(&root).map_err(|e| AddRootError::create_dir_all(e, &root))?;
+fn is_writable(dir: &Path) -> bool {
+    nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK).is_ok()
+}
+
+/// Point the symlink `link` to `target` and register `link` as an
+/// indirect GC root with nix, which keeps it for as long as `link` exists.
+fn register_root(target: &StorePath, link: &Path) -> Result<(), AddRootError> {
+    debug!("registering root"; "from" => target.as_path().to_str(), "to" => link.to_str());
+    // `--realise` is a no-op for valid paths, but for derivations
+    // the link points to the output, so it is replaced afterwards
+    let mut cmd = std::process::Command::new("nix-store");
+    cmd.arg("--add-root")
+        .arg(link)
+        .arg("--indirect")
+        .arg("--realise")
+        .arg(target.as_path())
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null());
+    let registered = cmd.output().map_err(|e| e.to_string()).and_then(|out| {
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(String::from_utf8_lossy(&out.stderr).trim().to_string())
         }
+    });
+    match registered {
+        Ok(()) => symlink_atomically(target.as_path(), link),
+        Err(msg) => Err(AddRootError::Unrootable {
+            link: link.to_owned(),
+            msg,
+        }),
+    }
+}
 
-        root.push(format!("{}-{}", self.id, name));
+/// Held while the roots of a project are changed. Drop to release.
+struct RootsLock {
+    _file: std::fs::File,
+}
 
-        debug!("connecting root"; "from" => path.to_str(), "to" => root.to_str());
-        std::fs::remove_file(&root).or_else(|e| AddRootError::remove(e, &root))?;
+/// Point the symlink `link` to `target`.
+/// An existing `link` is replaced atomically, so it never goes missing in between.
+fn symlink_atomically(target: &Path, link: &Path) -> Result<(), AddRootError> {
+    let tmp = link.with_file_name(format!(
+        ".{}.{}.tmp",
+        link.file_name()
+            .expect("a root must end in a file name")
+            .to_string_lossy(),
+        std::process::id()
+    ));
+    // left over if a previous attempt was interrupted
+    std::fs::remove_file(&tmp).or_else(|e| AddRootError::remove(e, &tmp))?;
+    std::os::unix::fs::symlink(target, &tmp).map_err(|e| AddRootError::symlink(e, target, &tmp))?;
+    std::fs::rename(&tmp, link).map_err(|e| {
+        AddRootError::Io(
+            e,
+            format!("Failed to move {} to {}", tmp.display(), link.display()),
+        )
+    })
+}
 
-        std::os::unix::fs::symlink(&path, &root)
-            .map_err(|e| AddRootError::symlink(e, &path, &root))?;
+/// Name of the root of the derivation, if outputs are kept.
+const DRV_ROOT_NAME: &str = "shell_drv";
 
-        // TODO: don’t return the RootPath here
-        Ok(RootPath(path))
-    }
+/// Name of the directory rooting the inputs of the derivation, if outputs are kept.
+const INPUTS_ROOT_NAME: &str = "inputs";
+
+/// The outputs of the derivations `drv` depends on, as far as they exist.
+/// Together with `drv` itself (which keeps its sources alive),
+/// these are what is needed to build it again.
+fn realized_inputs(drv: &RootedDrv) -> Result<Vec<StorePath>, BuildError> {
+    let input_drvs = crate::nix::query_store(&["--references"], &[drv.path.as_path()])?
+        .into_iter()
+        .filter(|path| path.ends_with(".drv"))
+        .collect::<Vec<_>>();
+    Ok(crate::nix::query_store(&["--outputs"], &input_drvs)?
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|path| path.exists())
+        .map(|path| StorePath::from(path.into_os_string()))
+        .collect())
+}
+
+/// Name of the root of generation `number`, like the links of nix profiles.
+fn generation_root_name(number: u32) -> String {
+    format!("shell_gc_root-{}-link", number)
 }
 
 /// Error conditions encountered when adding roots
@@ -135,6 +663,16 @@ where {
 pub enum AddRootError {
     /// IO-related errors
     Io(std::io::Error, String),
+    /// The configured GC roots directory is not writable
+    NotWritable(PathBuf),
+    /// The per-user GC roots directory is not writable,
+    /// and registering the root with nix failed as well
+    Unrootable {
+        /// The root that could not be registered
+        link: PathBuf,
+        /// Why nix could not register it
+        msg: String,
+    },
 }
 
 impl AddRootError {
@@ -159,6 +697,11 @@ impl AddRootError {
         }
     }
 
+    /// Return an error explaining that the roots could not be locked
+    fn lock(err: std::io::Error, path: &Path) -> AddRootError {
+        AddRootError::Io(err, format!("Failed to lock {}", path.display()))
+    }
+
     /// Return an error explaining what symlink failed
     fn symlink(err: std::io::Error, src: &Path, dest: &Path) -> AddRootError {
         AddRootError::Io(
@@ -172,6 +715,144 @@ impl fmt::Display for AddRootError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             AddRootError::Io(e, msg) => write!(f, "{}: {}", msg, e),
+            AddRootError::NotWritable(dir) => write!(
+                f,
+                "the GC roots directory {} (`gc_roots.directory` in the lorri config) is not writable",
+                dir.display()
+            ),
+            AddRootError::Unrootable { link, msg } => write!(
+                f,
+                "cannot protect {} from garbage collection: the per-user GC roots directory \
+                 of nix is not writable and `nix-store --add-root` failed ({}). \
+                 Set `gc_roots.directory` in the lorri config to a writable directory \
+                 below the `gcroots` directory of nix",
+                link.display(),
+                msg
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(generations: &[Generation]) -> Vec<u32> {
+        generations.iter().map(|g| g.number).collect()
+    }
+
+    #[test]
+    fn keep_only_the_newest_generations() {
+        let mut generations = Generations::default();
+        for i in 1..=3 {
+            let dropped = generations
+                .push(PathBuf::from(format!("/nix/store/{}", i)), i, 2)
+                .expect("a new environment is a new generation");
+            assert_eq!(generations.active, Some(i as u32));
+            if i == 3 {
+                assert_eq!(numbers(&dropped), vec![1]);
+            } else {
+                assert!(dropped.is_empty());
+            }
         }
+        assert_eq!(numbers(&generations.generations), vec![2, 3]);
+
+        // the newest generation is kept even if no generations should be
+        assert_eq!(
+            numbers(
+                &generations
+                    .push(PathBuf::from("/nix/store/4"), 4, 0)
+                    .unwrap()
+            ),
+            vec![2, 3]
+        );
+        assert_eq!(numbers(&generations.generations), vec![4]);
+    }
+
+    #[test]
+    fn same_environment_is_no_new_generation() {
+        let mut generations = Generations::default();
+        for i in 1..=2 {
+            generations.push(PathBuf::from(format!("/nix/store/{}", i)), i, 10);
+        }
+        assert_eq!(generations.push(PathBuf::from("/nix/store/2"), 3, 10), None);
+        assert_eq!(generations.active, Some(2));
+        assert_eq!(numbers(&generations.generations), vec![1, 2]);
+
+        // after a rollback, the same environment is a new generation again
+        generations.active = Some(1);
+        assert_eq!(
+            generations.push(PathBuf::from("/nix/store/2"), 4, 10),
+            Some(vec![])
+        );
+        assert_eq!(generations.active, Some(3));
+        // but the environment of the active generation is not
+        assert_eq!(generations.push(PathBuf::from("/nix/store/2"), 5, 10), None);
+    }
+
+    #[test]
+    fn symlinks_are_replaced() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let link = dir.path().join("shell_gc_root");
+        symlink_atomically(Path::new("/nix/store/a"), &link).unwrap();
+        symlink_atomically(Path::new("/nix/store/b"), &link).unwrap();
+        assert_eq!(std::fs::read_link(&link)?, Path::new("/nix/store/b"));
+        // no temporary links are left behind
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn lock_is_exclusive() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let roots = Roots {
+            gc_root_path: dir.path().to_owned(),
+            id: "test".to_string(),
+            keep_generations: 1,
+            keep_outputs: false,
+            gc_roots_dir: None,
+        };
+        let lock = roots.lock()?;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let other = roots.clone();
+        std::thread::spawn(move || {
+            let _lock = other.lock().unwrap();
+            tx.send(()).unwrap();
+        });
+        let wait = std::time::Duration::from_millis(200);
+        assert!(rx.recv_timeout(wait).is_err(), "lock was taken twice");
+        drop(lock);
+        rx.recv_timeout(wait * 25).expect("lock was not released");
+        Ok(())
+    }
+
+    #[test]
+    fn configured_gc_roots_dir() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let configured = dir.path().join("lorri").join("me");
+        assert_eq!(
+            gc_roots_location(Some(&configured)).unwrap(),
+            GcRootsLocation::Directory(configured.clone())
+        );
+        assert!(configured.is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_target() {
+        let mut generations = Generations::default();
+        assert!(generations.rollback_target(None).is_err());
+        for i in 1..=3 {
+            generations.push(PathBuf::from(format!("/nix/store/{}", i)), i, 10);
+        }
+        assert_eq!(generations.rollback_target(None).unwrap().number, 2);
+        assert_eq!(generations.rollback_target(Some(1)).unwrap().number, 1);
+        assert!(generations.rollback_target(Some(7)).is_err());
+
+        // rolling back repeatedly goes further back
+        generations.active = Some(2);
+        assert_eq!(generations.rollback_target(None).unwrap().number, 1);
+        generations.active = Some(1);
+        assert!(generations.rollback_target(None).is_err());
     }
 }
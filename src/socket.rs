@@ -1,6 +1,9 @@
 //! `bind()`ing & `connect()`ing to sockets.
 
-use std::os::unix::io::AsRawFd;
+use slog_scope::warn;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
 
 /// Small wrapper that makes sure lorri sockets are handled correctly.
@@ -30,9 +33,35 @@ impl From<std::io::Error> for BindError {
     }
 }
 
+/// The process holding the lock of a socket.
+#[derive(Debug, PartialEq)]
+pub enum LockHolder {
+    /// No process is listening on the socket
+    Nobody,
+    /// A lorri process is listening on the socket
+    Lorri {
+        /// Its version, unless it was too old to record it
+        version: Option<String>,
+        /// Its process id, unless it was too old to record it
+        pid: Option<i32>,
+    },
+}
+
 /// Locks the socket the server is bound to. Drop to release.
 pub struct BindLock(std::fs::File);
 
+/// Where the listening socket of the daemon comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    /// Passed by the service manager (socket activation), which owns the socket file
+    Activated,
+    /// Bound by lorri, which removes the socket file when it stops
+    Bound,
+}
+
+/// The first file descriptor passed with socket activation, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
 impl SocketPath {
     /// Create from the path of the socket.
     /// Must be passed a valid socket file path (ending in a file name).
@@ -41,8 +70,9 @@ impl SocketPath {
     }
 
     /// Try to lock the lock file to find outswhether another process is listening.
+    /// The lock file records the lorri version and the pid of the process holding the lock.
     pub fn lock(&self) -> Result<BindLock, BindError> {
-        let h = std::fs::OpenOptions::new()
+        let mut h = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
@@ -55,9 +85,52 @@ impl SocketPath {
             }
             other => other.map_err(BindError::Unix),
         }?;
+        h.set_len(0)?;
+        writeln!(h, "{}\n{}", crate::LORRI_VERSION, std::process::id())?;
         Ok(BindLock(h))
     }
 
+    /// Which process holds the lock, without taking it.
+    pub fn lock_holder(&self) -> Result<LockHolder, BindError> {
+        match self.lock() {
+            Ok(_) => Ok(LockHolder::Nobody),
+            Err(BindError::OtherProcessListening(lockfile)) => {
+                let mut contents = String::new();
+                std::fs::File::open(lockfile)?.read_to_string(&mut contents)?;
+                let mut lines = contents.lines();
+                Ok(LockHolder::Lorri {
+                    version: lines
+                        .next()
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.to_string()),
+                    pid: lines.next().and_then(|pid| pid.parse().ok()),
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Listen on the socket the service manager passed to this process, if it was
+    /// socket-activated, otherwise bind the socket (replacing a stale socket file).
+    /// Only call this while holding the lock.
+    pub fn listen(&self) -> Result<(UnixListener, Binding), BindError> {
+        if let Some(listener) = activated_listener()? {
+            match listener.local_addr()?.as_pathname() {
+                Some(path) if path == self.path() => {}
+                other => warn!(
+                    "the socket passed by the service manager is not where lorri clients look for the daemon";
+                    "passed" => ?other, "expected" => ?self.path()
+                ),
+            }
+            return Ok((listener, Binding::Activated));
+        }
+        match std::fs::remove_file(self.path()) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            other => other?,
+        }
+        Ok((UnixListener::bind(self.path())?, Binding::Bound))
+    }
+
     /// The absolute path of the socket.
     pub fn path(&self) -> &Path {
         self.0.as_ref()
@@ -81,6 +154,39 @@ impl SocketPath {
     }
 }
 
+/// The listening socket passed with socket activation (`LISTEN_PID` and `LISTEN_FDS`
+/// are set for this process), see sd_listen_fds(3).
+/// The variables are removed, so processes started by lorri don’t see them.
+fn activated_listener() -> Result<Option<UnixListener>, BindError> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    if !for_us || fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("using only the first of the sockets passed by the service manager"; "count" => fds);
+    }
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        // keep the nix processes from inheriting the sockets
+        nix::fcntl::fcntl(
+            fd,
+            nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+        )
+        .map_err(BindError::Unix)?;
+    }
+    // the service manager passed this file descriptor to us, nothing else owns it
+    Ok(Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) }))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -93,8 +199,26 @@ mod test {
             .lock()
             .expect("first locking attempt should succeed");
         assert!(
-            SocketPath(p).lock().is_err(),
+            SocketPath(p.clone()).lock().is_err(),
             "second locking attempt should fail because we still hold the lock"
         );
+        assert_eq!(
+            SocketPath(p).lock_holder().unwrap(),
+            LockHolder::Lorri {
+                version: Some(crate::LORRI_VERSION.to_string()),
+                pid: Some(std::process::id() as i32)
+            }
+        );
+    }
+
+    #[test]
+    fn listen_replaces_stale_socket() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let socket = SocketPath(tempdir.path().join("socket"));
+        std::fs::write(socket.path(), "").unwrap();
+        let _lock = socket.lock().unwrap();
+        let (_listener, binding) = socket.listen().expect("stale socket file must be replaced");
+        assert_eq!(binding, Binding::Bound);
+        std::os::unix::net::UnixStream::connect(socket.path()).expect("must be listening");
     }
 }
@@ -1,11 +1,13 @@
 //! Recursively watch paths for changes, in an extensible and
 //! cross-platform way.
 
+mod poll;
+
 use crate::NixFile;
 use crossbeam_channel as chan;
 use notify::event::ModifyKind;
 use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use slog_scope::{debug, info};
+use slog_scope::{debug, info, warn};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
@@ -15,12 +17,37 @@ use std::time::Duration;
 pub struct Watch {
     /// Event receiver. Process using `Watch::process`.
     pub rx: chan::Receiver<notify::Result<notify::Event>>,
-    notify: RecommendedWatcher,
+    tx: chan::Sender<notify::Result<notify::Event>>,
+    source: Source,
+    poll_interval: Duration,
     watches: HashSet<PathBuf>,
 }
 
+/// How a `Watch` finds out about changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Use filesystem notifications (inotify, FSEvents).
+    /// If the system runs out of inotify watches, fall back to
+    /// polling with the given interval.
+    Notify {
+        /// Interval to poll with after falling back
+        poll_interval: Duration,
+    },
+    /// Poll the metadata of all watched paths with the given interval.
+    /// Works on filesystems without notification support, like NFS.
+    Poll {
+        /// Time between two polls
+        interval: Duration,
+    },
+}
+
+enum Source {
+    Notify(RecommendedWatcher),
+    Poll(poll::Poller),
+}
+
 /// A debug message string that can only be displayed via `Debug`.
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DebugMessage(pub String);
 
 #[derive(Debug, PartialEq, Eq)]
@@ -30,7 +57,7 @@ struct FilteredOut<'a> {
 }
 
 /// Description of the project change that triggered a build.
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Reason {
     /// When a project is presented to Lorri to track, it's built for this reason.
@@ -51,16 +78,31 @@ pub enum EventError {
     RxNoEventReceived,
     /// The changed file event had no file path
     EventHasNoFilePath(notify::Event),
+    /// The watcher reported an error; events might have been lost.
+    WatcherFailed(DebugMessage),
 }
 
 impl Watch {
     /// Instantiate a new Watch.
-    pub fn try_new() -> Result<Watch, notify::Error> {
+    pub fn try_new(backend: Backend) -> Result<Watch, notify::Error> {
         let (tx, rx) = chan::unbounded();
 
+        let (source, poll_interval) = match backend {
+            Backend::Notify { poll_interval } => (
+                Source::Notify(Watcher::new(tx.clone(), Duration::from_millis(100))?),
+                poll_interval,
+            ),
+            Backend::Poll { interval } => (
+                Source::Poll(poll::Poller::new(tx.clone(), interval)),
+                interval,
+            ),
+        };
+
         Ok(Watch {
-            notify: Watcher::new(tx, Duration::from_millis(100))?,
+            source,
+            poll_interval,
             watches: HashSet::new(),
+            tx,
             rx,
         })
     }
@@ -73,7 +115,10 @@ impl Watch {
         event: notify::Result<notify::Event>,
     ) -> Option<Result<Reason, EventError>> {
         match event {
-            Err(err) => panic!("notify error: {}", err),
+            Err(err) => Some(Err(EventError::WatcherFailed(DebugMessage(format!(
+                "{:?}",
+                err
+            ))))),
             Ok(event) => {
                 self.log_event(&event);
                 if event.paths.is_empty() {
@@ -94,21 +139,33 @@ impl Watch {
         }
     }
 
+    /// All paths that are currently watched.
+    pub fn watched(&self) -> &HashSet<PathBuf> {
+        &self.watches
+    }
+
     /// Extend the watch list with an additional list of paths.
     /// Note: Watch maintains a list of already watched paths, and
     /// will not add duplicates.
     pub fn extend(&mut self, paths: Vec<PathBuf>) -> Result<(), notify::Error> {
         for path in paths {
             let recursive_paths = walk_path_topo(path)?;
-            for p in recursive_paths {
-                let p = p.canonicalize()?;
-                match Self::extend_filter(p) {
-                    Err(FilteredOut { reason, path }) => {
-                        debug!("Skipping watching {}: {}", path.display(), reason)
-                    }
-                    Ok(p) => {
-                        self.add_path(p)?;
-                    }
+            self.extend_shallow(recursive_paths)?;
+        }
+        Ok(())
+    }
+
+    /// Like `extend`, but directories are not walked: changes of their
+    /// entries are noticed, changes below their subdirectories are not.
+    pub fn extend_shallow(&mut self, paths: Vec<PathBuf>) -> Result<(), notify::Error> {
+        for p in paths {
+            let p = p.canonicalize()?;
+            match Self::extend_filter(p) {
+                Err(FilteredOut { reason, path }) => {
+                    debug!("Skipping watching {}: {}", path.display(), reason)
+                }
+                Ok(p) => {
+                    self.add_path(p)?;
                 }
             }
         }
@@ -142,21 +199,56 @@ impl Watch {
         if !self.watches.contains(&path) {
             debug!("watching path"; "path" => path.to_str());
 
-            self.notify.watch(&path, RecursiveMode::NonRecursive)?;
+            self.watch_path(&path)?;
             self.watches.insert(path.clone());
         }
 
-        if let Some(parent) = path.parent() {
+        // Notifications about a file being replaced arrive on its parent
+        // directory. The poller notices replaced files by itself.
+        if let (Some(parent), Source::Notify(_)) = (path.parent(), &self.source) {
             if !self.watches.contains(parent) {
                 debug!("watching parent path"; "parent_path" => parent.to_str());
 
-                self.notify.watch(&parent, RecursiveMode::NonRecursive)?;
+                self.watch_path(parent)?;
             }
         }
 
         Ok(())
     }
 
+    fn watch_path(&mut self, path: &Path) -> Result<(), notify::Error> {
+        if let Source::Notify(notify) = &mut self.source {
+            match notify.watch(path, RecursiveMode::NonRecursive) {
+                Err(notify::Error {
+                    kind: notify::ErrorKind::Io(ref e),
+                    ..
+                }) if e.raw_os_error() == Some(nix::errno::Errno::ENOSPC as i32) => {
+                    warn!(
+                        "ran out of inotify watches, falling back to polling; \
+                         increase fs.inotify.max_user_watches to avoid this";
+                        "poll_interval" => ?self.poll_interval
+                    );
+                    self.fall_back_to_polling();
+                }
+                res => return res,
+            }
+        }
+        if let Source::Poll(poller) = &self.source {
+            poller.watch(path);
+        }
+        Ok(())
+    }
+
+    /// Replace the notification watcher by a poller,
+    /// which releases all inotify watches it holds.
+    fn fall_back_to_polling(&mut self) {
+        let poller = poll::Poller::new(self.tx.clone(), self.poll_interval);
+        for path in &self.watches {
+            poller.watch(path);
+        }
+        self.source = Source::Poll(poller);
+    }
+
     fn path_is_interesting(&self, path: &PathBuf, kind: &EventKind) -> bool {
         path_match(&self.watches, path)
             && match kind {
@@ -289,7 +381,7 @@ fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::{EventError, Reason, Watch};
+    use super::{Backend, EventError, Reason, Watch};
     use crate::bash::expect_bash;
     use std::path::PathBuf;
     use std::thread::sleep;
@@ -302,6 +394,18 @@ mod tests {
         Duration::from_millis(1000)
     }
 
+    fn notify_backend() -> Backend {
+        Backend::Notify {
+            poll_interval: Duration::from_millis(100),
+        }
+    }
+
+    fn poll_backend() -> Backend {
+        Backend::Poll {
+            interval: Duration::from_millis(100),
+        }
+    }
+
     /// Collect all notifications
     fn process_all(watch: &Watch) -> Vec<Option<Result<Reason, EventError>>> {
         watch.rx.try_iter().map(|e| watch.process(e)).collect()
@@ -371,7 +475,7 @@ mod tests {
 
     #[test]
     fn trivial_watch_whole_directory() {
-        let mut watcher = Watch::try_new().expect("failed creating Watch");
+        let mut watcher = Watch::try_new(notify_backend()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
         expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
@@ -388,7 +492,7 @@ mod tests {
 
     #[test]
     fn trivial_watch_specific_file() {
-        let mut watcher = Watch::try_new().expect("failed creating Watch");
+        let mut watcher = Watch::try_new(notify_backend()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
         expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
@@ -401,10 +505,64 @@ mod tests {
         assert_file_changed(&watcher, "foo");
     }
 
+    #[test]
+    fn poll_watch_specific_file() {
+        let mut watcher = Watch::try_new(poll_backend()).expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1""#, [temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/foo""#, [temp.path().as_os_str()]);
+        watcher.extend(vec![temp.path().join("foo")]).unwrap();
+        sleep(upper_watcher_timeout());
+        assert!(no_changes(&watcher));
+
+        // unwatched siblings are not reported
+        expect_bash(r#"echo 1 > "$1/bar""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert!(no_changes(&watcher));
+
+        expect_bash(r#"echo 1 > "$1/foo""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "foo");
+
+        // renaming over the watched file is noticed as well
+        expect_bash(r#"mv "$1/bar" "$1/foo""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "foo");
+    }
+
+    #[test]
+    fn shallow_watch_directory() {
+        for &backend in &[notify_backend(), poll_backend()] {
+            let mut watcher = Watch::try_new(backend).expect("failed creating Watch");
+            let temp = tempdir().unwrap();
+
+            expect_bash(
+                r#"mkdir -p "$1/sub" && for i in $(seq 100); do touch "$1/$i"; done"#,
+                [temp.path().as_os_str()],
+            );
+            watcher
+                .extend_shallow(vec![temp.path().to_path_buf()])
+                .unwrap();
+            // one watched path for the directory, not one per file
+            assert_eq!(watcher.watched().len(), 1);
+            macos_eat_late_notifications(&mut watcher);
+
+            expect_bash(r#"echo 1 > "$1/42""#, [temp.path().as_os_str()]);
+            sleep(upper_watcher_timeout());
+            assert_file_changed(&watcher, "42");
+
+            // subdirectories are not watched
+            expect_bash(r#"touch "$1/sub/foo""#, [temp.path().as_os_str()]);
+            sleep(upper_watcher_timeout());
+            assert!(no_changes(&watcher));
+        }
+    }
+
     #[test]
     fn rename_over_vim() {
         // Vim renames files in to place for atomic writes
-        let mut watcher = Watch::try_new().expect("failed creating Watch");
+        let mut watcher = Watch::try_new(notify_backend()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
         expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
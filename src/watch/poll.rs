@@ -0,0 +1,174 @@
+//! Detect changes by periodically comparing the metadata of the
+//! watched paths.
+//!
+//! This is the fallback for filesystems which do not deliver
+//! notifications (NFS, sshfs) and for when the kernel runs out
+//! of inotify watches.
+
+use crossbeam_channel as chan;
+use notify::event::{CreateKind, ModifyKind, RemoveKind};
+use notify::{Event, EventKind};
+use slog_scope::debug;
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+/// What we remember about a path between two polls.
+/// `None` in the map means the path did not exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    mtime: Option<SystemTime>,
+    len: u64,
+    ino: u64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Stamp> {
+        std::fs::metadata(path).ok().map(|m| Stamp {
+            mtime: m.modified().ok(),
+            len: m.len(),
+            ino: m.ino(),
+        })
+    }
+}
+
+type Stamps = HashMap<PathBuf, Option<Stamp>>;
+
+/// Polls a set of paths in a background thread and sends a
+/// `notify::Event` for every path whose metadata changed.
+///
+/// The thread stops once the `Poller` is dropped.
+pub struct Poller {
+    stamps: Arc<Mutex<Stamps>>,
+}
+
+impl Poller {
+    /// Start polling every `interval`; events are sent to `tx`.
+    pub fn new(tx: chan::Sender<notify::Result<Event>>, interval: Duration) -> Poller {
+        let stamps = Arc::new(Mutex::new(HashMap::new()));
+        let weak = Arc::downgrade(&stamps);
+        std::thread::Builder::new()
+            .name("lorri-poll-watcher".to_string())
+            .spawn(move || run(weak, tx, interval))
+            .expect("failed to spawn the polling watcher thread");
+        Poller { stamps }
+    }
+
+    /// Start polling `path`, if it isn’t polled yet. For a directory,
+    /// the files in it are polled as well, since its own metadata only
+    /// changes when entries are added or removed.
+    pub fn watch(&self, path: &Path) {
+        let mut stamps = self.stamps.lock().expect("poller lock poisoned");
+        let files = std::fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().map(|t| !t.is_dir()).unwrap_or(false))
+            .map(|entry| entry.path());
+        for p in std::iter::once(path.to_owned()).chain(files) {
+            stamps.entry(p.clone()).or_insert_with(|| Stamp::of(&p));
+        }
+    }
+}
+
+fn run(stamps: Weak<Mutex<Stamps>>, tx: chan::Sender<notify::Result<Event>>, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        let stamps = match stamps.upgrade() {
+            Some(stamps) => stamps,
+            // the `Poller` was dropped
+            None => break,
+        };
+        let events = poll(&mut stamps.lock().expect("poller lock poisoned"));
+        for event in events {
+            debug!("poll event"; "event" => ?event);
+            if tx.send(Ok(event)).is_err() {
+                // nobody is listening anymore
+                return;
+            }
+        }
+    }
+}
+
+/// Re-check every path and update its stamp.
+/// Returns an event for every path that changed.
+fn poll(stamps: &mut Stamps) -> Vec<Event> {
+    let mut events = vec![];
+    for (path, old) in stamps.iter_mut() {
+        let new = Stamp::of(path);
+        if new == *old {
+            continue;
+        }
+        let kind = match (*old, new) {
+            (None, _) => EventKind::Create(CreateKind::Any),
+            (_, None) => EventKind::Remove(RemoveKind::Any),
+            (Some(_), Some(_)) => EventKind::Modify(ModifyKind::Any),
+        };
+        events.push(Event::new(kind).add_path(path.clone()));
+        *old = new;
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(events: Vec<Event>) -> Vec<EventKind> {
+        events.into_iter().map(|e| e.kind).collect()
+    }
+
+    #[test]
+    fn poll_detects_changes() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("shell.nix");
+        std::fs::write(&file, "a")?;
+
+        let mut stamps = Stamps::new();
+        stamps.insert(file.clone(), Stamp::of(&file));
+        assert_eq!(kinds(poll(&mut stamps)), vec![]);
+
+        // a change in size is detected even within the mtime granularity
+        std::fs::write(&file, "ab")?;
+        assert_eq!(
+            kinds(poll(&mut stamps)),
+            vec![EventKind::Modify(ModifyKind::Any)]
+        );
+        assert_eq!(kinds(poll(&mut stamps)), vec![]);
+
+        std::fs::remove_file(&file)?;
+        assert_eq!(
+            kinds(poll(&mut stamps)),
+            vec![EventKind::Remove(RemoveKind::Any)]
+        );
+
+        std::fs::write(&file, "a")?;
+        assert_eq!(
+            kinds(poll(&mut stamps)),
+            vec![EventKind::Create(CreateKind::Any)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn poll_detects_replaced_file() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("shell.nix");
+        let other = tmp.path().join("shell.nix.swp");
+        std::fs::write(&file, "a")?;
+
+        let mut stamps = Stamps::new();
+        stamps.insert(file.clone(), Stamp::of(&file));
+
+        // same size, possibly same mtime, but a different inode
+        std::fs::write(&other, "b")?;
+        std::fs::rename(&other, &file)?;
+        assert_eq!(
+            kinds(poll(&mut stamps)),
+            vec![EventKind::Modify(ModifyKind::Any)]
+        );
+        Ok(())
+    }
+}
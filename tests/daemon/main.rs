@@ -1,9 +1,17 @@
+use crossbeam_channel as chan;
 use lorri::build_loop;
 use lorri::cas::ContentAddressable;
-use lorri::daemon::{Daemon, LoopHandlerEvent};
+use lorri::config::Config;
+use lorri::daemon::{tcp, Daemon, LoopHandlerEvent};
 use lorri::nix::options::NixOptions;
-use lorri::socket::SocketPath;
-use std::io::{Error, ErrorKind};
+use lorri::ops::shell;
+use lorri::project::Project;
+use lorri::socket::{LockHolder, SocketPath};
+use lorri::NixFile;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -22,20 +30,7 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     let shell_nix = tempdir.as_ref().join("shell.nix");
     std::fs::File::create(&shell_nix)?;
 
-    let socket_path = SocketPath::from(&tempdir.path().join("socket"));
-    let address = socket_path.address();
-    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
-    let gc_root_dir = tempdir.path().join("gc_root").to_path_buf();
-
-    // The daemon knows how to build stuff
-    let (mut daemon, build_rx) = Daemon::new(NixOptions::empty());
-    let accept_handle = thread::spawn(move || {
-        daemon
-            .serve(socket_path, gc_root_dir, cas)
-            .expect("failed to serve daemon endpoint");
-    });
-
-    connect(&address, Duration::from_millis(1000));
+    let (address, build_rx, accept_handle) = start_daemon(tempdir.path(), None);
 
     lorri::ops::ping::main(lorri::NixFile::from(shell_nix), Some(address)).unwrap();
 
@@ -53,6 +48,239 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     Ok(())
 }
 
+/// `lorri shell` waits for a build that starts after it asked for one,
+/// not for the builds the daemon reports from before.
+#[test]
+pub fn shell_waits_for_a_fresh_build() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    // fails to build, with or without nix
+    let shell_nix = tempdir.path().join("shell.nix");
+    std::fs::File::create(&shell_nix)?;
+    let project = Project::new(
+        NixFile::from(shell_nix),
+        &tempdir.path().join("client_gc_root"),
+        ContentAddressable::new(tempdir.path().join("client_cas"))?,
+        Config::default(),
+    )?;
+
+    let (address, build_rx, _accept_handle) = start_daemon(tempdir.path(), None);
+    let builds = || {
+        build_rx
+            .try_iter()
+            .filter_map(|event| match event {
+                LoopHandlerEvent::BuildEvent(build_loop::Event::Started { .. }) => Some("started"),
+                LoopHandlerEvent::BuildEvent(build_loop::Event::Failure { .. }) => Some("failed"),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // a project the daemon does not know yet is built for the first time
+    let err =
+        shell::build_with_daemon(&project, false, &address).expect_err("the build should fail");
+    assert!(err.message().contains("Build failed"), "{}", err.message());
+    assert_eq!(builds(), vec!["started", "failed"]);
+
+    // the daemon reports that failure again, but it is built once more
+    let err =
+        shell::build_with_daemon(&project, false, &address).expect_err("the build should fail");
+    assert!(err.message().contains("Build failed"), "{}", err.message());
+    assert_eq!(builds(), vec!["started", "failed"]);
+    Ok(())
+}
+
+/// Clients of the TCP listener have to authenticate before any other call.
+#[test]
+pub fn tcp_requires_authentication() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let shell_nix = tempdir.path().join("shell.nix");
+    std::fs::File::create(&shell_nix)?;
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let token = "a".repeat(64);
+
+    let (_address, build_rx, _accept_handle) = start_daemon(
+        tempdir.path(),
+        Some(tcp::Endpoint {
+            address: format!("127.0.0.1:{}", port),
+            token: token.clone(),
+        }),
+    );
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(e) if start.elapsed() > Duration::from_secs(1) => panic!("cannot connect: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    let watch_shell = serde_json::json!({ "shell_nix": { "path": shell_nix } });
+
+    let reply = call(
+        &mut stream,
+        "com.target.lorri.internal.WatchShell",
+        watch_shell.clone(),
+    )?;
+    assert_eq!(
+        reply["error"], "com.target.lorri.internal.AuthenticationRequired",
+        "{}",
+        reply
+    );
+    let reply = call(
+        &mut stream,
+        "com.target.lorri.Monitor",
+        serde_json::json!({}),
+    )?;
+    assert_eq!(
+        reply["error"], "com.target.lorri.AuthenticationRequired",
+        "{}",
+        reply
+    );
+    let reply = call(
+        &mut stream,
+        "com.target.lorri.internal.Authenticate",
+        serde_json::json!({ "token": "b".repeat(64) }),
+    )?;
+    assert_eq!(
+        reply["error"], "com.target.lorri.internal.InvalidToken",
+        "{}",
+        reply
+    );
+    // nothing was built for the unauthenticated client
+    assert!(build_rx.try_recv().is_err());
+
+    let reply = call(
+        &mut stream,
+        "com.target.lorri.internal.Authenticate",
+        serde_json::json!({ "token": token }),
+    )?;
+    assert!(reply.get("error").is_none(), "{}", reply);
+    let reply = call(
+        &mut stream,
+        "com.target.lorri.internal.WatchShell",
+        watch_shell,
+    )?;
+    assert!(reply.get("error").is_none(), "{}", reply);
+    match build_rx.recv_timeout(Duration::from_millis(1000)).unwrap() {
+        LoopHandlerEvent::BuildEvent(build_loop::Event::Started { .. }) => Ok(()),
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+}
+
+/// `Shutdown` tells monitoring clients and releases the socket before the daemon exits.
+#[test]
+pub fn shutdown() -> std::io::Result<()> {
+    use lorri::proto::VarlinkClientInterface as _;
+    use std::convert::TryFrom;
+
+    let tempdir = tempfile::tempdir()?;
+    let lorri = |args: &[&str]| {
+        let mut cmd = Command::new(cargo_bin("lorri"));
+        cmd.args(args)
+            .env("HOME", tempdir.path())
+            .env("XDG_CACHE_HOME", tempdir.path().join("cache"))
+            .env("XDG_CONFIG_HOME", tempdir.path().join("config"));
+        cmd
+    };
+    let mut daemon = lorri(&["daemon"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let socket_path = SocketPath::from(&tempdir.path().join("cache/lorri/daemon.socket"));
+    let address = socket_path.address();
+
+    let mut monitor = lorri::proto::VarlinkClient::new(connect(&address, Duration::from_secs(10)));
+    let mut call = monitor.monitor(None);
+    // the connection is closed after the last event
+    let mut events = call
+        .more()
+        .unwrap()
+        .take_while(Result::is_ok)
+        .map(|reply| build_loop::Event::try_from(reply.unwrap()).unwrap());
+    // the daemon knows no projects yet, so the snapshot is empty
+    match events.next() {
+        Some(build_loop::Event::SectionEnd) => {}
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+
+    let stop = lorri(&["daemon", "stop"]).output()?;
+    assert!(stop.status.success(), "{:?}", stop);
+    let events = events.collect::<Vec<_>>();
+    match events.as_slice() {
+        [build_loop::Event::Shutdown] => {}
+        _ => panic!("expected only a shutdown event, got {:?}", events),
+    }
+
+    assert!(daemon.wait()?.success());
+    assert!(!socket_path.path().exists(), "the socket is left behind");
+    assert_eq!(socket_path.lock_holder().unwrap(), LockHolder::Nobody);
+    Ok(())
+}
+
+/// Serve a daemon with its files in `dir`.
+/// Returns its address, the events of its builds and its thread.
+fn start_daemon(
+    dir: &Path,
+    tcp: Option<tcp::Endpoint>,
+) -> (
+    String,
+    chan::Receiver<LoopHandlerEvent>,
+    thread::JoinHandle<()>,
+) {
+    let socket_path = SocketPath::from(&dir.join("socket"));
+    let address = socket_path.address();
+    let cas = ContentAddressable::new(dir.join("cas")).unwrap();
+    let gc_root_dir = dir.join("gc_root");
+    let active_projects_file = dir.join("active_projects.json");
+
+    // The daemon knows how to build stuff
+    let (mut daemon, build_rx) = Daemon::new(NixOptions::empty());
+    if let Some(endpoint) = tcp {
+        daemon.listen_tcp(endpoint);
+    }
+    let accept_handle = thread::spawn(move || {
+        daemon
+            .serve(
+                socket_path,
+                gc_root_dir,
+                cas,
+                Config::default(),
+                None,
+                active_projects_file,
+            )
+            .expect("failed to serve daemon endpoint");
+    });
+
+    connect(&address, Duration::from_millis(1000));
+    (address, build_rx, accept_handle)
+}
+
+fn cargo_bin(name: &str) -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .map(|mut path| {
+            path.pop();
+            if path.ends_with("deps") {
+                path.pop();
+            }
+            path.join(name)
+        })
+        .unwrap()
+}
+
+/// Call `method` on a raw varlink connection and return the reply.
+fn call(
+    stream: &mut TcpStream,
+    method: &str,
+    parameters: serde_json::Value,
+) -> std::io::Result<serde_json::Value> {
+    let request = serde_json::json!({ "method": method, "parameters": parameters });
+    stream.write_all(format!("{}\0", request).as_bytes())?;
+    let mut reply = Vec::new();
+    BufReader::new(&*stream).read_until(b'\0', &mut reply)?;
+    reply.pop();
+    Ok(serde_json::from_slice(&reply)?)
+}
+
 /// The server side of the connection is started in a separate thread. This function waits until
 /// the socket address is available for connection.
 fn connect(
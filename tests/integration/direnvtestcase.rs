@@ -4,6 +4,7 @@
 use lorri::{
     build_loop::{BuildLoop, BuildResults},
     cas::ContentAddressable,
+    config::Config,
     error::BuildError,
     nix::options::NixOptions,
     ops::direnv,
@@ -41,6 +42,7 @@ impl DirenvTestCase {
             shell_file.clone(),
             &cachedir.path().join("gc_roots").to_owned(),
             cas,
+            Config::default(),
         )
         .unwrap();
 
@@ -1,16 +1,18 @@
 use lorri::{
     builder,
     cas::ContentAddressable,
+    config::Config,
     nix::options::NixOptions,
-    ops::shell,
+    ops::{shell, start_user_shell},
     project::{roots::Roots, Project},
     NixFile,
 };
 use std::env;
 use std::fs;
+use std::io::Write;
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
 
 fn cargo_bin(name: &str) -> PathBuf {
     env::current_exe()
@@ -56,6 +58,171 @@ fn loads_env() {
     );
 }
 
+#[test]
+fn exec_loads_env() {
+    let output = lorri_exec(&[], &["/bin/sh", "-c", "echo $MY_ENV_VAR"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).expect("stdout not UTF-8 clean"),
+        "my_env_value\n"
+    );
+}
+
+#[test]
+fn exec_exit_status() {
+    let output = lorri_exec(&[], &["/bin/sh", "-c", "exit 3"]);
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+}
+
+#[test]
+fn exec_missing_command() {
+    let output = lorri_exec(&[], &["lorri-test-no-such-command"]);
+    assert_eq!(output.status.code(), Some(127), "{:?}", output);
+    // errors are logged to stdout
+    let stdout = String::from_utf8(output.stdout).expect("stdout not UTF-8 clean");
+    assert!(
+        stdout.contains("lorri-test-no-such-command: command not found"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn exec_pure() {
+    let output = lorri_exec_with_env(
+        &["--pure", "--keep", "LORRI_TEST_KEPT"],
+        &["/usr/bin/env"],
+        &[("LORRI_TEST_KEPT", "kept"), ("LORRI_TEST_UNKEPT", "unkept")],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).expect("stdout not UTF-8 clean");
+    let vars = stdout
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect::<std::collections::BTreeMap<_, _>>();
+
+    assert_eq!(vars.get("LORRI_TEST_UNKEPT"), None, "{}", stdout);
+    assert_eq!(vars.get("LORRI_TEST_KEPT"), Some(&"kept"), "{}", stdout);
+    assert_eq!(vars.get("MY_ENV_VAR"), Some(&"my_env_value"), "{}", stdout);
+    // nothing of the current PATH is left
+    let tempdir = tempfile::tempdir().expect("tempfile::tempdir() failed us!");
+    let project_env = lorri::env::read_env(&build(&project("loads_env", tempdir.path()))).unwrap();
+    assert_eq!(
+        vars.get("PATH"),
+        project_env.get("PATH").map(String::as_str).as_ref(),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn fish() {
+    let output = user_shell("fish", &["-c", "fish_prompt; echo; echo $MY_ENV_VAR"], "");
+    assert!(output.starts_with("(lorri) "), "{}", output);
+    assert!(output.ends_with("\nmy_env_value\n"), "{}", output);
+}
+
+#[test]
+fn nushell() {
+    // `--config` is read before the commands run
+    let output = user_shell(
+        "nu",
+        &[
+            "-c",
+            "print (do $env.PROMPT_COMMAND); print $env.MY_ENV_VAR",
+        ],
+        "",
+    );
+    assert!(output.starts_with("(lorri) "), "{}", output);
+    assert!(output.ends_with("\nmy_env_value\n"), "{}", output);
+}
+
+#[test]
+fn xonsh() {
+    let output = user_shell(
+        "xonsh",
+        &[
+            "-i",
+            "-c",
+            "print($PROMPT if isinstance($PROMPT, str) else $PROMPT()); print($MY_ENV_VAR)",
+        ],
+        "",
+    );
+    assert!(output.starts_with("(lorri) "), "{}", output);
+    assert!(output.ends_with("\nmy_env_value\n"), "{}", output);
+}
+
+#[test]
+fn tcsh() {
+    let output = user_shell(
+        "tcsh",
+        &["-i"],
+        "echo \"$prompt\"\necho $MY_ENV_VAR\nexit\n",
+    );
+    assert!(output.contains("(lorri) "), "{}", output);
+    assert!(output.contains("my_env_value\n"), "{}", output);
+}
+
+/// Run `lorri exec` with `options` and `command` in the `loads_env` project.
+fn lorri_exec(options: &[&str], command: &[&str]) -> Output {
+    lorri_exec_with_env(options, command, &[])
+}
+
+/// Like `lorri_exec`, with `env` added to the environment of lorri.
+fn lorri_exec_with_env(options: &[&str], command: &[&str], env: &[(&str, &str)]) -> Output {
+    let tempdir = tempfile::tempdir().expect("tempfile::tempdir() failed us!");
+    let project = project("loads_env", tempdir.path());
+    Command::new(cargo_bin("lorri"))
+        .arg("exec")
+        .arg("--shell-file")
+        .arg(project.nix_file.as_path())
+        .args(options)
+        .arg("--")
+        .args(command)
+        .envs(env.iter().cloned())
+        .current_dir(&tempdir)
+        .output()
+        .expect("fail to run lorri exec")
+}
+
+/// Start the user shell `name` in the `loads_env` project environment,
+/// like `lorri internal start-user-shell` does, with `input` on stdin.
+/// The shell has to be installed; the `shell.nix` of lorri provides it.
+fn user_shell(name: &str, args: &[&str], input: &str) -> String {
+    let shell_path = env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| panic!("{} is not installed, see shell.nix", name));
+    let tempdir = tempfile::tempdir().expect("tempfile::tempdir() failed us!");
+    let project = project("loads_env", tempdir.path());
+    let current = env::vars().collect::<lorri::env::Env>();
+    let project_env = lorri::env::evaluate(&build(&project), &current).unwrap();
+
+    let shell_dir = tempdir.path().join("shell");
+    fs::create_dir(&shell_dir).unwrap();
+    let mut child = start_user_shell::shell_cmd(&shell_path, &project.cas, &shell_dir)
+        .args(args)
+        .envs(&project_env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run shell");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{} failed: {:?}", name, output);
+    String::from_utf8(output.stdout).expect("stdout not UTF-8 clean")
+}
+
 fn project(name: &str, cache_dir: &Path) -> Project {
     let test_root = PathBuf::from_iter(&[env!("CARGO_MANIFEST_DIR"), "tests", "shell", name]);
     let cas_dir = cache_dir.join("cas").to_owned();
@@ -64,18 +231,16 @@ fn project(name: &str, cache_dir: &Path) -> Project {
         NixFile::from(test_root.join("shell.nix")),
         &cache_dir.join("gc_roots").to_owned(),
         ContentAddressable::new(cas_dir).unwrap(),
+        Config::default(),
     )
     .unwrap()
 }
 
 fn build(project: &Project) -> PathBuf {
+    let run_result = builder::run(&project.nix_file, &project.cas, &NixOptions::empty()).unwrap();
     Path::new(
         Roots::from_project(&project)
-            .create_roots(
-                builder::run(&project.nix_file, &project.cas, &NixOptions::empty())
-                    .unwrap()
-                    .result,
-            )
+            .create_roots(run_result.result, &run_result.drv)
             .unwrap()
             .shell_gc_root
             .as_os_str(),